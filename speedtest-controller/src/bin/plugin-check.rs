@@ -0,0 +1,597 @@
+//! Checks that a plugin follows the plugin protocol, before it is deployed.
+//!
+//! The plugin is launched the way the controller launches it, then every method it declares is
+//! called with valid and invalid inputs. A plugin that needs arguments, environment, a sandbox
+//! or the Wasm type is checked by its entry in a controller config.
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::Parser;
+use config::Config;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use speedtest_controller::discovery;
+use speedtest_controller::netns::{self, IsolationConfig};
+use speedtest_controller::plugin::{
+    ConnectionDescriptor, DataTransformDescriptor, Plugin, PluginError, PluginErrorKind,
+    PluginMetaData, ProtocolDescriptor, TestDescriptor,
+};
+use speedtest_controller::speedtest::{load_plugin, PluginConfig};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use url::Url;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Plugin source, as in the controller config, e.g. `file://path/to/plugin`
+    #[arg(required_unless_present = "controller_config")]
+    source: Option<Url>,
+    /// The `config` of the plugin entry, as JSON
+    #[arg(long, default_value = "null", conflicts_with = "controller_config")]
+    config: String,
+    /// Controller config to take the plugin entry from, instead of `source`
+    #[arg(long, requires = "plugin", conflicts_with = "source")]
+    controller_config: Option<String>,
+    /// Name of the plugin in `plugins` or `plugin_dirs` of the controller config
+    #[arg(long)]
+    plugin: Option<String>,
+    /// Subscription handed to `parse_protocol`
+    #[arg(long, default_value = "")]
+    connection_string: String,
+    /// How long a call may take before it fails, in seconds
+    #[arg(long, default_value_t = 30)]
+    timeout: u64,
+}
+
+/// The part of the controller config that decides how plugins are launched.
+#[derive(Deserialize)]
+struct ControllerPlugins {
+    #[serde(default)]
+    plugins: HashMap<String, PluginConfig>,
+    #[serde(default)]
+    plugin_dirs: Vec<PathBuf>,
+    #[serde(default)]
+    isolation: IsolationConfig,
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    Skip(String),
+}
+
+#[derive(Default)]
+struct Report {
+    checks: Vec<(String, Outcome)>,
+}
+
+impl Report {
+    fn record(&mut self, check: impl Into<String>, outcome: Outcome) {
+        let check = check.into();
+        match &outcome {
+            Outcome::Pass => println!("PASS {check}"),
+            Outcome::Fail(reason) => println!("FAIL {check}: {reason}"),
+            Outcome::Skip(reason) => println!("SKIP {check}: {reason}"),
+        }
+        self.checks.push((check, outcome));
+    }
+
+    /// Records whether `result` is `Ok`, and returns the value if it is.
+    fn expect_ok<T, E: Display>(&mut self, check: &str, result: Result<T, E>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.record(check, Outcome::Pass);
+                Some(value)
+            }
+            Err(e) => {
+                self.record(check, Outcome::Fail(e.to_string()));
+                None
+            }
+        }
+    }
+
+    /// Invalid input has to be answered with an error object, not by dropping the connection.
+    fn expect_call_error<T>(
+        &mut self,
+        check: &str,
+        result: Result<Result<T, PluginError>, String>,
+    ) {
+        let outcome = match result {
+            Ok(Err(e)) if e.kind() == PluginErrorKind::Call => Outcome::Pass,
+            Ok(Err(e)) => Outcome::Fail(format!("expected an error object, got {e}")),
+            Ok(Ok(_)) => Outcome::Fail("the invalid input was accepted".to_owned()),
+            Err(e) => Outcome::Fail(e),
+        };
+        self.record(check, outcome);
+    }
+
+    fn failures(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+            .count()
+    }
+}
+
+struct Checker {
+    plugin: Arc<dyn Plugin>,
+    metadata: PluginMetaData,
+    timeout: Duration,
+    report: Report,
+}
+
+/// (pid, parent pid) of every process, read from `/proc`.
+fn parent_pids() -> Vec<(u32, u32)> {
+    std::fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let pid: u32 = entry.ok()?.file_name().to_str()?.parse().ok()?;
+            let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+            // The command name may contain spaces, the fields after it do not.
+            let (_, fields) = stat.rsplit_once(')')?;
+            let ppid = fields.split_whitespace().nth(1)?.parse().ok()?;
+            Some((pid, ppid))
+        })
+        .collect()
+}
+
+/// Every process descending from `pid`.
+fn descendants(pid: u32) -> HashSet<u32> {
+    let parents = parent_pids();
+    let mut found = HashSet::new();
+    let mut queue = vec![pid];
+    while let Some(parent) = queue.pop() {
+        for (pid, _) in parents.iter().filter(|(_, ppid)| *ppid == parent) {
+            if found.insert(*pid) {
+                queue.push(*pid);
+            }
+        }
+    }
+    found
+}
+
+/// Waits for killed processes to exit, returning those `running` still lists after two seconds.
+async fn leftover(running: impl Fn() -> HashSet<u32>) -> HashSet<u32> {
+    let mut leftover = HashSet::new();
+    for _ in 0..10 {
+        leftover = running();
+        if leftover.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    leftover
+}
+
+async fn is_reachable(endpoint: &str) -> Result<(), String> {
+    let url = Url::parse(endpoint).map_err(|e| format!("{endpoint} is not a url. {e}"))?;
+    let addrs = url
+        .socket_addrs(|| None)
+        .map_err(|e| format!("{endpoint} has no address. {e}"))?;
+    let addr = addrs
+        .first()
+        .ok_or_else(|| format!("{endpoint} has no address"))?;
+    match timeout(Duration::from_secs(5), TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("cannot connect to {endpoint}. {e}")),
+        Err(_) => Err(format!("connecting to {endpoint} timed out")),
+    }
+}
+
+impl Checker {
+    /// Calls the plugin, failing if it does not answer within the timeout.
+    async fn call_raw<T>(
+        &self,
+        call: impl Future<Output = Result<T, PluginError>>,
+    ) -> Result<Result<T, PluginError>, String> {
+        timeout(self.timeout, call)
+            .await
+            .map_err(|_| format!("no answer within {:?}", self.timeout))
+    }
+
+    async fn call<T>(
+        &self,
+        call: impl Future<Output = Result<T, PluginError>>,
+    ) -> Result<T, String> {
+        self.call_raw(call).await?.map_err(|e| e.to_string())
+    }
+
+    async fn check_responsive(&mut self, after: &str) {
+        let outcome = match self.call(self.plugin.metadata()).await {
+            Ok(_) => Outcome::Pass,
+            Err(e) => Outcome::Fail(e),
+        };
+        self.report
+            .record(format!("plugin still answers after {after}"), outcome);
+    }
+
+    async fn check_parser(&mut self, connection_string: &str) -> Vec<ProtocolDescriptor> {
+        let result = self
+            .call(self.plugin.parse_protocol(connection_string))
+            .await;
+        let proxies = self
+            .report
+            .expect_ok("parse_protocol", result)
+            .unwrap_or_default();
+        if proxies.is_empty() {
+            self.report.record(
+                "parse_protocol returns proxies",
+                Outcome::Skip("no proxy was parsed, pass --connection-string".to_owned()),
+            );
+        }
+        let result = self
+            .call_raw(
+                self.plugin
+                    .parse_protocol("\u{0}plugin-check: not a subscription"),
+            )
+            .await;
+        let outcome = match result {
+            // Returning no proxy is as good as an error for an invalid subscription.
+            Ok(Ok(proxies)) if proxies.is_empty() => Outcome::Pass,
+            Ok(Ok(_)) => Outcome::Fail("proxies were parsed from garbage".to_owned()),
+            Ok(Err(e)) if e.kind() == PluginErrorKind::Call => Outcome::Pass,
+            Ok(Err(e)) => Outcome::Fail(format!("expected an error object, got {e}")),
+            Err(e) => Outcome::Fail(e),
+        };
+        self.report
+            .record("parse_protocol rejects an invalid subscription", outcome);
+        self.check_responsive("parse_protocol").await;
+        proxies
+    }
+
+    async fn check_backend(
+        &mut self,
+        plugin_pid: Option<u32>,
+        proxies: &[ProtocolDescriptor],
+    ) -> Option<ConnectionDescriptor> {
+        let result = self.call(self.plugin.proxy_schemes()).await;
+        self.report.expect_ok("proxy_schemes", result);
+        let result = self.call(self.plugin.proxy_protocols()).await;
+        self.report.expect_ok("proxy_protocols", result);
+
+        let result = self
+            .call_raw(
+                self.plugin
+                    .setup_proxy(json!({ "plugin-check": "not a proxy" })),
+            )
+            .await;
+        self.report
+            .expect_call_error("setup_proxy rejects an invalid proxy", result);
+        self.check_responsive("an invalid setup_proxy").await;
+
+        let Some(proxy) = proxies.first() else {
+            self.report.record(
+                "setup_proxy",
+                Outcome::Skip("there is no parsed proxy to set up".to_owned()),
+            );
+            return None;
+        };
+        let before = plugin_pid.map(descendants);
+        let result = self
+            .call(self.plugin.setup_proxy(proxy.content.clone()))
+            .await;
+        let connection = self.report.expect_ok("setup_proxy", result)?;
+
+        let endpoints: Vec<_> = [&connection.socks5, &connection.http]
+            .into_iter()
+            .flatten()
+            .collect();
+        if endpoints.is_empty() && !connection.uses_tun() {
+            self.report.record(
+                "setup_proxy returns an endpoint",
+                Outcome::Fail("neither socks5, http nor tun is set".to_owned()),
+            );
+        }
+        for endpoint in endpoints {
+            let outcome = match is_reachable(endpoint).await {
+                Ok(()) => Outcome::Pass,
+                Err(e) => Outcome::Fail(e),
+            };
+            self.report
+                .record(format!("{endpoint} is reachable"), outcome);
+        }
+        if let Some(netns) = &connection.netns {
+            let outcome = if netns::netns_path(netns).exists() {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(format!("{netns} does not exist"))
+            };
+            self.report
+                .record(format!("network namespace {netns} exists"), outcome);
+        } else if let Some(interface) = &connection.interface {
+            // An interface inside a namespace is not visible from here.
+            let outcome = if Path::new("/sys/class/net").join(interface).exists() {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(format!("{interface} does not exist"))
+            };
+            self.report
+                .record(format!("interface {interface} exists"), outcome);
+        }
+
+        self.check_tests(Some(&connection)).await;
+
+        let result = self.call(self.plugin.teardown_proxy(&connection)).await;
+        self.report.expect_ok("teardown_proxy", result);
+        let outcome = match (plugin_pid, before) {
+            (Some(plugin_pid), Some(before)) => {
+                let leftover = leftover(|| &descendants(plugin_pid) - &before).await;
+                if leftover.is_empty() {
+                    Outcome::Pass
+                } else {
+                    Outcome::Fail(format!("processes {leftover:?} are still running"))
+                }
+            }
+            _ => not_launched(),
+        };
+        self.report
+            .record("teardown_proxy stops the processes of the proxy", outcome);
+        Some(connection)
+    }
+
+    /// Runs every test through `connection`, which only exists if the plugin is a backend as well.
+    async fn check_tests(&mut self, connection: Option<&ConnectionDescriptor>) {
+        if !self.metadata.capabilities.test_provider {
+            return;
+        }
+        let result = self.call(self.plugin.tests()).await;
+        let tests = self.report.expect_ok("tests", result).unwrap_or_default();
+
+        let unknown = TestDescriptor {
+            name: "plugin-check-unknown-test".to_owned(),
+        };
+        let invalid = ConnectionDescriptor {
+            http: None,
+            socks5: None,
+            tun: false,
+            ..Default::default()
+        };
+        let result = self
+            .call_raw(self.plugin.run_test(&unknown, &invalid))
+            .await;
+        self.report
+            .expect_call_error("run_test rejects an unknown test", result);
+        self.check_responsive("an invalid run_test").await;
+
+        for test in &tests {
+            let check = format!("run_test {}", test.name);
+            match connection {
+                Some(connection) => {
+                    let result = self.call(self.plugin.run_test(test, connection)).await;
+                    self.report.expect_ok(&check, result);
+                }
+                None => self.report.record(
+                    check,
+                    Outcome::Skip("the plugin does not set up proxies to test".to_owned()),
+                ),
+            }
+        }
+    }
+
+    async fn check_transformer(&mut self, proxies: &[ProtocolDescriptor]) {
+        let result = self.call(self.plugin.data_transforms()).await;
+        let transforms = self
+            .report
+            .expect_ok("data_transforms", result)
+            .unwrap_or_default();
+
+        let unknown = DataTransformDescriptor {
+            name: "plugin-check-unknown-transform".to_owned(),
+            accpeted_scheme: String::new(),
+            produced_scheme: String::new(),
+        };
+        let result = self
+            .call_raw(self.plugin.transform(&unknown, Value::Null))
+            .await;
+        self.report
+            .expect_call_error("transform rejects an unknown transform", result);
+        self.check_responsive("an invalid transform").await;
+
+        for transform in &transforms {
+            let check = format!("transform {}", transform.name);
+            let accepted = proxies
+                .iter()
+                .find(|p| p.scheme.as_deref() == Some(transform.accpeted_scheme.as_str()));
+            match accepted {
+                Some(proxy) => {
+                    let result = self
+                        .call(self.plugin.transform(transform, proxy.content.clone()))
+                        .await;
+                    self.report.expect_ok(&check, result);
+                }
+                None => self.report.record(
+                    check,
+                    Outcome::Skip(format!(
+                        "no parsed proxy has scheme {}",
+                        transform.accpeted_scheme
+                    )),
+                ),
+            }
+        }
+    }
+}
+
+/// Skips the process checks of plugins plugin-check did not launch, e.g. those connected to by
+/// `ws://`.
+fn not_launched() -> Outcome {
+    Outcome::Skip("plugin-check did not launch the plugin process".to_owned())
+}
+
+/// The plugin entry of `args`, completed the way the controller completes it.
+fn plugin_config(args: &Args) -> anyhow::Result<PluginConfig> {
+    let (Some(path), Some(name)) = (&args.controller_config, &args.plugin) else {
+        let source = args.source.clone().context("No plugin to check")?;
+        return Ok(PluginConfig::new(
+            source,
+            serde_json::from_str(&args.config)?,
+        ));
+    };
+    let config: ControllerPlugins = Config::builder()
+        .add_source(config::File::with_name(path))
+        .build()?
+        .try_deserialize()?;
+    let mut plugins = discovery::discover(&config.plugin_dirs);
+    plugins.extend(config.plugins);
+    let mut plugin = plugins
+        .remove(name)
+        .with_context(|| format!("{path} has no plugin {name}"))?;
+    if config.isolation.enabled {
+        plugin.allow_isolation(name);
+    }
+    Ok(plugin)
+}
+
+/// Runs every check that applies to the plugin of `args`.
+async fn check(args: Args) -> anyhow::Result<Report> {
+    let plugin_config = plugin_config(&args)?;
+    let mut report = Report::default();
+
+    // The plugin is the only process this tool starts.
+    let (plugin, metadata) = match load_plugin(plugin_config).await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            report.record("load", Outcome::Fail(e.to_string()));
+            return Ok(report);
+        }
+    };
+    report.record("load", Outcome::Pass);
+    let plugin_pid = plugin.process_id().await;
+    println!(
+        "Checking {} {} (protocol {}), {:?}",
+        metadata.name,
+        metadata.version.as_deref().unwrap_or("(unknown version)"),
+        metadata.protocol_version,
+        metadata.capabilities
+    );
+
+    let mut checker = Checker {
+        plugin,
+        metadata: metadata.clone(),
+        timeout: Duration::from_secs(args.timeout),
+        report,
+    };
+    let result = checker.call(checker.plugin.init()).await;
+    checker.report.expect_ok("init", result);
+
+    let capabilities = &metadata.capabilities;
+    let proxies = if capabilities.parser {
+        checker.check_parser(&args.connection_string).await
+    } else {
+        vec![]
+    };
+    let connection = if capabilities.proxy_backend {
+        checker.check_backend(plugin_pid, &proxies).await
+    } else {
+        None
+    };
+    if connection.is_none() {
+        checker.check_tests(None).await;
+    }
+    if capabilities.transformer {
+        checker.check_transformer(&proxies).await;
+    }
+
+    let running = plugin_pid.map(|pid| {
+        let mut running = descendants(pid);
+        running.insert(pid);
+        running
+    });
+    let result = checker.call(checker.plugin.shutdown()).await;
+    checker.report.expect_ok("shutdown", result);
+    let outcome = match running {
+        Some(running) => {
+            let running = leftover(|| {
+                let mut running = running.clone();
+                running.retain(|pid| Path::new(&format!("/proc/{pid}")).exists());
+                running
+            })
+            .await;
+            if running.is_empty() {
+                Outcome::Pass
+            } else {
+                Outcome::Fail(format!("processes {running:?} are still running"))
+            }
+        }
+        None => not_launched(),
+    };
+    checker
+        .report
+        .record("shutdown stops the plugin and its processes", outcome);
+    Ok(checker.report)
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let report = check(Args::parse()).await?;
+    let failures = report.failures();
+    println!("{} checks, {} failed", report.checks.len(), failures);
+    if failures > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use speedtest_controller::mock::{self, MockScript};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn the_mock_plugin_passes() {
+        let script: MockScript = serde_json::from_value(json!({
+            "tests": [{"name": "latency", "response": {"result": 100}}],
+        }))
+        .unwrap();
+        let server = mock::serve(script).await.unwrap();
+        let args = Args::parse_from(["plugin-check", &format!("ws://{}", server.addr)]);
+        let report = check(args).await.unwrap();
+
+        assert_eq!(report.failures(), 0);
+        let skipped = report
+            .checks
+            .iter()
+            .find(|(check, _)| check == "shutdown stops the plugin and its processes");
+        assert!(matches!(skipped, Some((_, Outcome::Skip(_)))));
+    }
+
+    #[tokio::test]
+    async fn checks_a_plugin_entry_of_a_controller_config() {
+        let server = mock::serve(MockScript::default()).await.unwrap();
+        let path = std::env::temp_dir().join(format!("plugin-check-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "connection_string = \"sub\"\n[plugins.mock]\nsource = \"ws://{}\"\n",
+                server.addr
+            ),
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let args = |plugin: &str| {
+            Args::parse_from([
+                "plugin-check",
+                "--controller-config",
+                path,
+                "--plugin",
+                plugin,
+            ])
+        };
+        let report = check(args("mock")).await.unwrap();
+        let other = check(args("other")).await;
+        std::fs::remove_file(path).unwrap();
+
+        assert!(report.checks.iter().any(|(check, _)| check == "load"));
+        assert_eq!(report.failures(), 0);
+        assert!(other.is_err());
+        assert!(Args::try_parse_from(["plugin-check", "--controller-config", path]).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::speedtest::{ProxyResults, RunResults, TestProviderMap};
+
+/// What a run has completed, used to resume it later.
+///
+/// Failures count as completed, so that a resumed run does not retry them either.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Checkpoint {
+    pub test_results: RunResults,
+    /// The errors of the tests that failed, by the same keys as `test_results`
+    #[serde(default)]
+    pub failed_tests: RunResults,
+    /// Provider -> proxies that could not be set up
+    #[serde(default)]
+    pub failed_setups: HashMap<String, HashSet<String>>,
+    /// Vantage point -> what the run there completed, instead of the fields above
+    #[serde(default)]
+    pub vantage_points: HashMap<String, Checkpoint>,
+}
+
+fn test_entry<'a>(
+    results: &'a mut RunResults,
+    provider: &str,
+    proxy: &str,
+    test_provider: &str,
+) -> &'a mut HashMap<String, Value> {
+    results
+        .entry(provider.to_owned())
+        .or_default()
+        .entry(proxy.to_owned())
+        .or_default()
+        .entry(test_provider.to_owned())
+        .or_default()
+}
+
+fn remove_test(
+    results: &mut RunResults,
+    provider: &str,
+    proxy: &str,
+    test_provider: &str,
+    test: &str,
+) {
+    let tests = results
+        .get_mut(provider)
+        .and_then(|proxies| proxies.get_mut(proxy))
+        .and_then(|test_providers| test_providers.get_mut(test_provider));
+    if let Some(tests) = tests {
+        tests.remove(test);
+    }
+}
+
+impl Checkpoint {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Saves the checkpoint, replacing the previous one only once it is fully written.
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let partial = path.with_extension("partial");
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        std::fs::rename(partial, path)
+    }
+
+    /// Records the tests of a proxy that passed, replacing earlier failures.
+    pub fn record_results(&mut self, provider: &str, proxy: &str, results: &ProxyResults) {
+        if let Some(failed) = self.failed_setups.get_mut(provider) {
+            failed.remove(proxy);
+        }
+        let proxies = self.test_results.entry(provider.to_owned()).or_default();
+        proxies.entry(proxy.to_owned()).or_default();
+        for (test_provider, tests) in results {
+            for (test, result) in tests {
+                remove_test(&mut self.failed_tests, provider, proxy, test_provider, test);
+                test_entry(&mut self.test_results, provider, proxy, test_provider)
+                    .insert(test.clone(), result.clone());
+            }
+        }
+    }
+
+    /// Records a test that failed with `error`, replacing an earlier result.
+    pub fn record_failed_test(
+        &mut self,
+        provider: &str,
+        proxy: &str,
+        test_provider: &str,
+        test: &str,
+        error: &str,
+    ) {
+        remove_test(&mut self.test_results, provider, proxy, test_provider, test);
+        test_entry(&mut self.failed_tests, provider, proxy, test_provider)
+            .insert(test.to_owned(), Value::from(error));
+    }
+
+    /// Records a proxy that could not be set up.
+    pub fn record_failed_setup(&mut self, provider: &str, proxy: &str) {
+        self.failed_setups
+            .entry(provider.to_owned())
+            .or_default()
+            .insert(proxy.to_owned());
+    }
+
+    pub fn is_completed(
+        &self,
+        provider: &str,
+        proxy: &str,
+        test_provider: &str,
+        test: &str,
+    ) -> bool {
+        [&self.test_results, &self.failed_tests]
+            .iter()
+            .any(|results| {
+                results
+                    .get(provider)
+                    .and_then(|proxies| proxies.get(proxy))
+                    .and_then(|test_providers| test_providers.get(test_provider))
+                    .is_some_and(|tests| tests.contains_key(test))
+            })
+    }
+
+    /// Whether the proxy failed to set up, or every test of `test_providers` already completed
+    /// for it.
+    pub fn is_proxy_completed(
+        &self,
+        provider: &str,
+        proxy: &str,
+        test_providers: &TestProviderMap,
+    ) -> bool {
+        if self
+            .failed_setups
+            .get(provider)
+            .is_some_and(|proxies| proxies.contains(proxy))
+        {
+            return true;
+        }
+        let checkpointed = self
+            .test_results
+            .get(provider)
+            .is_some_and(|proxies| proxies.contains_key(proxy));
+        checkpointed
+            && test_providers.iter().all(|(test_provider, (_, tests))| {
+                tests
+                    .iter()
+                    .all(|test| self.is_completed(provider, proxy, test_provider, &test.name))
+            })
+    }
+}
+
+/// Keeps the checkpoint file up to date while a run is in progress, so that a crash only loses
+/// the proxies being tested.
+pub struct CheckpointWriter {
+    path: PathBuf,
+    checkpoint: Mutex<Checkpoint>,
+}
+
+impl CheckpointWriter {
+    /// Starts from `resumed`, which is kept in the checkpoint.
+    pub fn new(path: PathBuf, resumed: Checkpoint) -> Self {
+        CheckpointWriter {
+            path,
+            checkpoint: Mutex::new(resumed),
+        }
+    }
+
+    /// Records an outcome of the run at `vantage_point`, or of the run here, and saves the
+    /// checkpoint if `save`.
+    pub fn record(
+        &self,
+        vantage_point: Option<&str>,
+        save: bool,
+        record: impl FnOnce(&mut Checkpoint),
+    ) -> std::io::Result<()> {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        match vantage_point {
+            Some(name) => record(
+                checkpoint
+                    .vantage_points
+                    .entry(name.to_owned())
+                    .or_default(),
+            ),
+            None => record(&mut checkpoint),
+        }
+        if save {
+            checkpoint.save(&self.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        self.checkpoint.lock().unwrap().save(&self.path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::plugin::{builtin, TestDescriptor};
+
+    fn checkpoint() -> Checkpoint {
+        let mut checkpoint = Checkpoint::default();
+        let results: ProxyResults =
+            serde_json::from_value(json!({"tests": {"latency": 100}})).unwrap();
+        checkpoint.record_results("sub", "a", &results);
+        checkpoint.record_failed_test("sub", "a", "tests", "download", "timed out");
+        checkpoint.record_failed_setup("sub", "b");
+        checkpoint
+    }
+
+    fn test_providers(tests: &[&str]) -> TestProviderMap {
+        let tests = tests
+            .iter()
+            .map(|name| TestDescriptor {
+                name: name.to_string(),
+            })
+            .collect();
+        let plugin = builtin::load("latency", Value::Null).unwrap();
+        HashMap::from([("tests".to_owned(), (plugin, tests))])
+    }
+
+    #[test]
+    fn counts_failures_as_completed() {
+        let checkpoint = checkpoint();
+        assert!(checkpoint.is_completed("sub", "a", "tests", "latency"));
+        assert!(checkpoint.is_completed("sub", "a", "tests", "download"));
+        assert!(!checkpoint.is_completed("sub", "a", "tests", "upload"));
+        assert!(!checkpoint.is_completed("sub", "c", "tests", "latency"));
+
+        assert!(checkpoint.is_proxy_completed(
+            "sub",
+            "a",
+            &test_providers(&["latency", "download"])
+        ));
+        assert!(!checkpoint.is_proxy_completed("sub", "a", &test_providers(&["upload"])));
+        assert!(checkpoint.is_proxy_completed("sub", "b", &test_providers(&["latency"])));
+        assert!(!checkpoint.is_proxy_completed("sub", "c", &HashMap::new()));
+    }
+
+    #[test]
+    fn a_rerun_replaces_the_earlier_outcome() {
+        let mut checkpoint = checkpoint();
+        let results: ProxyResults =
+            serde_json::from_value(json!({"tests": {"download": 5}})).unwrap();
+        checkpoint.record_results("sub", "a", &results);
+        checkpoint.record_failed_test("sub", "a", "tests", "latency", "refused");
+
+        assert_eq!(
+            checkpoint.test_results["sub"]["a"]["tests"],
+            HashMap::from([("download".to_owned(), json!(5))])
+        );
+        assert_eq!(
+            checkpoint.failed_tests["sub"]["a"]["tests"],
+            HashMap::from([("latency".to_owned(), json!("refused"))])
+        );
+    }
+
+    #[test]
+    fn saves_and_loads_the_checkpoint() {
+        let path = std::env::temp_dir().join(format!("checkpoint-{}.json", std::process::id()));
+        let writer = CheckpointWriter::new(path.clone(), checkpoint());
+        writer
+            .record(Some("tokyo"), true, |checkpoint| {
+                checkpoint.record_failed_setup("sub", "a")
+            })
+            .unwrap();
+        let loaded = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.test_results, checkpoint().test_results);
+        assert_eq!(loaded.failed_tests, checkpoint().failed_tests);
+        assert_eq!(loaded.failed_setups, checkpoint().failed_setups);
+        assert!(loaded.vantage_points["tokyo"].is_proxy_completed("sub", "a", &HashMap::new()));
+        assert!(!path.with_extension("partial").exists());
+    }
+}
@@ -0,0 +1,173 @@
+//! Finds plugins installed into plugin directories, so they need not be spelled out in the config.
+//!
+//! Every subdirectory of a plugin directory holding a `plugin.toml` (or `.yaml`, `.json`, any
+//! format the `config` crate reads) is a plugin:
+//!
+//! ```toml
+//! name = "hello"
+//! executable = "plugin-hello"
+//! args = ["--verbose"]
+//! env = { GOST = "${HOME}/bin/gost" }
+//! transport = "JSONRPC"
+//! capabilities = { parser = true }
+//! sandbox = { memory_mb = 512, seccomp = true }
+//!
+//! [config]
+//! greeting = "hi"
+//! ```
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+use crate::plugin::{Capabilities, PluginType};
+use crate::process::Sandbox;
+use crate::speedtest::PluginConfig;
+
+/// The file name, without extension, of a plugin manifest.
+pub const MANIFEST_NAME: &str = "plugin";
+
+/// Describes how to launch a plugin installed into a plugin directory.
+#[derive(Debug, Deserialize)]
+pub struct PluginManifest {
+    /// The name the plugin is known by, unless an explicit config entry uses it
+    pub name: String,
+    /// Relative to the directory of the manifest
+    executable: PathBuf,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(default)]
+    env_allowlist: Option<Vec<String>>,
+    /// Relative to the directory of the manifest, which it defaults to
+    #[serde(default)]
+    cwd: PathBuf,
+    #[serde(default)]
+    sandbox: Sandbox,
+    #[serde(default)]
+    transport: PluginType,
+    /// Used unless an explicit config entry replaces the plugin
+    #[serde(default)]
+    config: Value,
+    #[serde(default)]
+    capabilities: Option<Capabilities>,
+}
+
+impl PluginManifest {
+    /// Reads the manifest in `dir`, if there is one.
+    pub fn load(dir: &Path) -> anyhow::Result<Option<Self>> {
+        let Some(path) = find_manifest(dir)? else {
+            return Ok(None);
+        };
+        let manifest = config::Config::builder()
+            .add_source(config::File::from(path))
+            .build()?
+            .try_deserialize()?;
+        Ok(Some(manifest))
+    }
+
+    fn into_config(self, dir: &Path) -> anyhow::Result<PluginConfig> {
+        let dir = dir.canonicalize()?;
+        let executable = dir.join(self.executable);
+        if !executable.is_file() {
+            anyhow::bail!("Executable {} does not exist", executable.display());
+        }
+        let source = Url::from_file_path(&executable)
+            .map_err(|()| anyhow::anyhow!("Invalid executable {}", executable.display()))?;
+        let mut config = PluginConfig::new(source, self.config);
+        config.plugin_type = self.transport;
+        config.args = self.args;
+        config.env = self.env;
+        config.env_allowlist = self.env_allowlist;
+        config.cwd = Some(dir.join(self.cwd));
+        config.sandbox = self.sandbox;
+        config.capabilities = self.capabilities;
+        Ok(config)
+    }
+}
+
+fn find_manifest(dir: &Path) -> std::io::Result<Option<PathBuf>> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.file_stem().is_some_and(|stem| stem == MANIFEST_NAME) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Scans `dirs` for installed plugins, skipping the ones whose manifest cannot be read or whose
+/// executable is missing.
+///
+/// When several directories have a plugin of the same name, the later directory wins.
+pub fn discover(dirs: &[PathBuf]) -> HashMap<String, PluginConfig> {
+    let mut plugins = HashMap::new();
+    for dir in dirs {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Unable to scan plugin directory {}, {}", dir.display(), e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let dir = entry.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            let manifest = PluginManifest::load(&dir).and_then(|manifest| {
+                manifest
+                    .map(|manifest| Ok((manifest.name.clone(), manifest.into_config(&dir)?)))
+                    .transpose()
+            });
+            match manifest {
+                Ok(Some((name, config))) => {
+                    log::info!("Discovered plugin {} in {}", name, dir.display());
+                    plugins.insert(name, config);
+                }
+                Ok(None) => {}
+                Err(e) => log::warn!("Invalid plugin manifest in {}, {:#}", dir.display(), e),
+            }
+        }
+    }
+    plugins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discovers_plugins_with_a_manifest() {
+        let root = std::env::temp_dir().join(format!("plugins-{}", std::process::id()));
+        for dir in ["hello/bin", "broken", "empty", "uninstalled"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join("hello/bin/hello"), "").unwrap();
+        std::fs::write(
+            root.join("hello/plugin.toml"),
+            "name = \"hi\"\nexecutable = \"bin/hello\"\nargs = [\"-v\"]\ncapabilities = { parser = true }\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("broken/plugin.json"), "{\"name\": 1}").unwrap();
+        std::fs::write(
+            root.join("uninstalled/plugin.toml"),
+            "name = \"gone\"\nexecutable = \"bin/gone\"\n",
+        )
+        .unwrap();
+
+        let plugins = discover(&[root.clone(), root.join("missing")]);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(plugins.len(), 1, "{plugins:?}");
+        let hello = &plugins["hi"];
+        assert!(hello.source.path().ends_with("/hello/bin/hello"));
+        assert_eq!(hello.args, ["-v"]);
+        assert_eq!(hello.plugin_type, PluginType::JSONRPC);
+        let capabilities = hello.capabilities.as_ref().unwrap();
+        assert!(capabilities.parser && !capabilities.test_provider);
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{HashMap, HashSet};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::plugin::ProtocolDescriptor;
+use crate::score::RankedProxy;
+
+/// Subscription formats the results can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// A Clash config with a `proxies` list, each `content` being a Clash proxy
+    Clash,
+    /// Base64 encoded share links, each `content` being a link such as `ss://...`
+    ShareLinks,
+    /// A sing-box config with an `outbounds` list, each `content` being an outbound
+    SingBox,
+}
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("Unable to serialize the subscription as JSON. {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Unable to serialize the subscription as YAML. {0}")]
+    Yaml(#[from] serde_yaml::Error),
+}
+
+/// (provider, proxy) -> the descriptor the proxy was parsed into
+pub type ProxyDescriptorMap = HashMap<(String, String), ProtocolDescriptor>;
+
+/// Characters that must be escaped in the fragment of a share link.
+const FRAGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`');
+
+/// Renders the name of the node annotated with its metrics, e.g. `hk-01 | latency 120 | throughput 42.5`.
+fn annotated_name(name: &str, ranked: &RankedProxy) -> String {
+    let mut metrics: Vec<_> = ranked.metrics.iter().collect();
+    metrics.sort_by_key(|(metric, _)| *metric);
+    std::iter::once(name.to_owned())
+        .chain(
+            metrics
+                .into_iter()
+                .map(|(metric, value)| format!("{metric} {}", (value * 100.0).round() / 100.0)),
+        )
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+fn rename_share_link(link: &str, name: &str) -> Option<String> {
+    if let Some(encoded) = link.strip_prefix("vmess://") {
+        let mut config: Value =
+            serde_json::from_slice(&STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        config.as_object_mut()?.insert("ps".to_owned(), name.into());
+        return Some(format!("vmess://{}", STANDARD.encode(config.to_string())));
+    }
+    let (link, _) = link.split_once('#').unwrap_or((link, ""));
+    Some(format!("{link}#{}", utf8_percent_encode(name, FRAGMENT)))
+}
+
+fn rename_object(content: &Value, key: &str, name: &str) -> Option<Value> {
+    let mut content = content.clone();
+    content.as_object_mut()?.insert(key.to_owned(), name.into());
+    Some(content)
+}
+
+/// Writes the proxies that passed into a subscription, best first and renamed with their metrics.
+///
+/// Proxies whose `content` does not fit `format` are left out. A node tested on several backends
+/// is only exported once, for its best ranked backend.
+pub fn export(
+    format: ExportFormat,
+    ranking: &[RankedProxy],
+    descriptors: &ProxyDescriptorMap,
+) -> Result<String, ExportError> {
+    let mut names = HashSet::new();
+    let mut nodes = HashSet::new();
+    let proxies = ranking.iter().filter(|r| r.passed).filter_map(|ranked| {
+        let descriptor = descriptors.get(&(ranked.provider.clone(), ranked.proxy.clone()))?;
+        if !nodes.insert((&ranked.provider, &descriptor.name)) {
+            return None;
+        }
+        // Clients identify proxies by name, so they have to stay unique. The planned name of a
+        // proxy compared on several backends names its backend, which is not part of the node.
+        let annotated = annotated_name(&descriptor.name, ranked);
+        let mut name = annotated.clone();
+        let mut suffix = 1;
+        while !names.insert(name.clone()) {
+            suffix += 1;
+            name = format!("{annotated} ({suffix})");
+        }
+        let exported = match format {
+            ExportFormat::Clash => rename_object(&descriptor.content, "name", &name),
+            ExportFormat::SingBox => rename_object(&descriptor.content, "tag", &name),
+            ExportFormat::ShareLinks => descriptor
+                .content
+                .as_str()
+                .and_then(|link| rename_share_link(link, &name))
+                .map(Value::String),
+        };
+        if exported.is_none() {
+            log::warn!(
+                "Proxy {} of {} cannot be exported as {format:?}",
+                ranked.proxy,
+                ranked.provider
+            );
+        }
+        exported
+    });
+
+    Ok(match format {
+        ExportFormat::Clash => {
+            serde_yaml::to_string(&json!({ "proxies": proxies.collect::<Vec<_>>() }))?
+        }
+        ExportFormat::SingBox => {
+            serde_json::to_string_pretty(&json!({ "outbounds": proxies.collect::<Vec<_>>() }))?
+        }
+        ExportFormat::ShareLinks => {
+            let links: Vec<_> = proxies
+                .filter_map(|link| link.as_str().map(ToOwned::to_owned))
+                .collect();
+            STANDARD.encode(links.join("\n"))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranked(proxy: &str, latency: f64, passed: bool) -> RankedProxy {
+        RankedProxy {
+            provider: "sub".to_owned(),
+            proxy: proxy.to_owned(),
+            score: 0.0,
+            passed,
+            metrics: HashMap::from([("latency".to_owned(), latency)]),
+            failures: 0,
+            violations: vec![],
+        }
+    }
+
+    fn descriptors(contents: &[(&str, Value)]) -> ProxyDescriptorMap {
+        contents
+            .iter()
+            .map(|(name, content)| {
+                let descriptor = ProtocolDescriptor {
+                    name: name.to_string(),
+                    scheme: None,
+                    protocol: None,
+                    content: content.clone(),
+                };
+                (("sub".to_owned(), name.to_string()), descriptor)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exports_share_links_in_ranking_order() {
+        let vmess = STANDARD.encode(json!({"ps": "b", "add": "example.com"}).to_string());
+        let descriptors = descriptors(&[
+            ("a", json!("ss://YWVzLTI1Ni1nY206cGFzcw@1.2.3.4:8388#a")),
+            ("b", json!(format!("vmess://{vmess}"))),
+            ("c", json!("trojan://pass@5.6.7.8:443#c")),
+        ]);
+        let ranking = [
+            ranked("b", 80.0, true),
+            ranked("a", 120.5, true),
+            ranked("c", 900.0, false),
+        ];
+        let exported = export(ExportFormat::ShareLinks, &ranking, &descriptors).unwrap();
+        let exported = String::from_utf8(STANDARD.decode(exported).unwrap()).unwrap();
+        let links: Vec<_> = exported.lines().collect();
+
+        assert_eq!(links.len(), 2);
+        let vmess = links[0].strip_prefix("vmess://").unwrap();
+        let vmess: Value = serde_json::from_slice(&STANDARD.decode(vmess).unwrap()).unwrap();
+        assert_eq!(vmess["ps"], "b | latency 80");
+        assert_eq!(vmess["add"], "example.com");
+        assert_eq!(
+            links[1],
+            "ss://YWVzLTI1Ni1nY206cGFzcw@1.2.3.4:8388#a%20|%20latency%20120.5"
+        );
+    }
+
+    #[test]
+    fn exports_clash_proxies_with_unique_names() {
+        let content = json!({"name": "a", "type": "ss", "server": "1.2.3.4", "port": 8388});
+        let mut descriptors = descriptors(&[("a", content.clone())]);
+        descriptors.insert(
+            ("other".to_owned(), "a".to_owned()),
+            ProtocolDescriptor {
+                name: "a".to_owned(),
+                scheme: None,
+                protocol: None,
+                content,
+            },
+        );
+        let mut other = ranked("a", 100.0, true);
+        other.provider = "other".to_owned();
+        let ranking = [ranked("a", 100.0, true), other];
+        let exported = export(ExportFormat::Clash, &ranking, &descriptors).unwrap();
+        let exported: Value = serde_yaml::from_str(&exported).unwrap();
+
+        assert_eq!(exported["proxies"][0]["name"], "a | latency 100");
+        assert_eq!(exported["proxies"][1]["name"], "a | latency 100 (2)");
+        assert_eq!(exported["proxies"][1]["server"], "1.2.3.4");
+    }
+
+    #[test]
+    fn exports_a_proxy_compared_on_two_backends_once_without_its_backend() {
+        let content = json!({"name": "a", "type": "ss", "server": "1.2.3.4", "port": 8388});
+        let descriptor = ProtocolDescriptor {
+            name: "a".to_owned(),
+            scheme: None,
+            protocol: None,
+            content,
+        };
+        let descriptors: ProxyDescriptorMap = ["a@clash", "a@sing-box"]
+            .iter()
+            .map(|planned| (("sub".to_owned(), planned.to_string()), descriptor.clone()))
+            .collect();
+        let ranking = [
+            ranked("a@sing-box", 80.0, true),
+            ranked("a@clash", 100.0, true),
+        ];
+        let exported = export(ExportFormat::Clash, &ranking, &descriptors).unwrap();
+        let exported: Value = serde_yaml::from_str(&exported).unwrap();
+
+        let proxies = exported["proxies"].as_array().unwrap();
+        assert_eq!(proxies.len(), 1);
+        assert_eq!(proxies[0]["name"], "a | latency 80");
+    }
+}
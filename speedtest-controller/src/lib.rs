@@ -1,4 +1,18 @@
+pub mod checkpoint;
+pub mod discovery;
+pub mod export;
+pub mod mock;
+pub mod netns;
 pub mod plugin;
 mod plugin_loader;
 pub mod process;
+pub mod progress;
+pub mod retry;
+pub mod run;
+pub mod score;
+pub mod server;
+pub mod spec;
 pub mod speedtest;
+pub mod transform;
+pub mod tui;
+pub mod vantage;
@@ -1,177 +1,436 @@
-#![feature(impl_trait_in_fn_trait_return)]
-
 use std::collections::HashMap;
-use std::pin::Pin;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use config::Config;
-use futures::stream;
-use futures::Future;
+use futures::future::join_all;
+use futures::stream::FuturesUnordered;
 use futures::FutureExt;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use speedtest_controller::plugin::ConnectionDescriptor;
-use speedtest_controller::plugin::Plugin;
-use speedtest_controller::plugin::ProtocolDescriptor;
-use speedtest_controller::plugin::TestDescriptor;
-use speedtest_controller::speedtest::ProxyProviderMap;
-use speedtest_controller::speedtest::TestProviderMap;
-use speedtest_controller::speedtest::{PluginConfig, SpeedTest};
+use speedtest_controller::checkpoint::{Checkpoint, CheckpointWriter};
+use speedtest_controller::discovery;
+use speedtest_controller::export::{self, ExportFormat, ProxyDescriptorMap};
+use speedtest_controller::netns::IsolationConfig;
+use speedtest_controller::progress::{self, Event, Reporter};
+use speedtest_controller::retry::{AttemptMap, RetryConfig};
+use speedtest_controller::run::{self, merge_results, run_with_reporter, RunContext};
+use speedtest_controller::score::{RankedProxy, ScoringConfig};
+use speedtest_controller::spec;
+use speedtest_controller::speedtest::{PluginConfig, RunResults, SpeedTest};
+use speedtest_controller::speedtest::{ProxyProviderMap, TestProviderMap};
+use speedtest_controller::transform::{SetupConfig, SetupPlan};
+use speedtest_controller::tui::{self, Command, LogWriter, TuiReporter};
+use speedtest_controller::vantage::{Location, LocationSummary, VantagePoint};
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio_util::sync::CancellationToken;
 // use url::Url;
 
 #[derive(Debug, Deserialize)]
 pub struct ControllerConfig {
+    /// Override the plugins of the same name found in `plugin_dirs`
+    #[serde(default)]
     plugins: HashMap<String, PluginConfig>,
+    /// Directories scanned for installed plugins, see `discovery`
+    #[serde(default)]
+    plugin_dirs: Vec<PathBuf>,
     connection_string: String, //Url
+    #[serde(default)]
+    retry: RetryConfig,
+    #[serde(default)]
+    scoring: ScoringConfig,
+    #[serde(default)]
+    setup: SetupConfig,
+    /// Tests every proxy from each of these locations instead of from here, see `vantage`
+    #[serde(default)]
+    vantage_points: HashMap<String, VantagePoint>,
+    /// Sets up every proxy in a network namespace of its own, see `netns`
+    #[serde(default)]
+    isolation: IsolationConfig,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Print the OpenRPC specification of the plugin protocol
+    PluginSpec,
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
     #[arg(short, long, default_value = "config")]
     config: String,
+    /// Browse the results in an interactive terminal UI while the run is in progress
+    #[arg(long)]
+    tui: bool,
+    /// Where the completed results are saved while the run is in progress, removed once it
+    /// completes
+    #[arg(long, default_value = "speedtest.checkpoint.json")]
+    checkpoint: PathBuf,
+    /// Skip everything already completed in the checkpoint
+    #[arg(long)]
+    resume: bool,
+    /// Only keep the N best proxies in the ranking
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+    /// Write the proxies that passed to a new subscription, ranked and annotated with their metrics
+    #[arg(long, value_name = "PATH", requires = "export_format")]
+    export: Option<PathBuf>,
+    #[arg(long, value_enum)]
+    export_format: Option<ExportFormat>,
+    /// Record the traffic of every plugin to `DIR/<plugin>.ndjson`, to be replayed with a
+    /// `replay://` plugin source
+    #[arg(long, value_name = "DIR")]
+    record: Option<PathBuf>,
 }
 
 #[derive(Debug, Serialize, Default)]
 struct Output {
-    test_results: HashMap<String, HashMap<String, HashMap<String, HashMap<String, Value>>>>,
+    test_results: RunResults,
+    attempts: AttemptMap,
+    ranking: Vec<RankedProxy>,
+    /// Vantage point -> what was run from there, instead of the fields above
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    vantage_points: HashMap<String, VantageOutput>,
 }
 
-async fn collect_test_results(
-    plugin: Arc<dyn Plugin>,
-    proxy_connection: ConnectionDescriptor,
-    tests: Vec<TestDescriptor>,
-) -> HashMap<String, Value> {
-    let run_test_future = try_run_test(plugin, proxy_connection);
-    stream::iter(tests)
-        .filter_map(run_test_future)
-        .collect::<HashMap<_, _>>()
-        .await
+/// What was run from one vantage point.
+#[derive(Debug, Serialize)]
+struct VantageOutput {
+    test_results: RunResults,
+    attempts: AttemptMap,
+    ranking: Vec<RankedProxy>,
+    summary: LocationSummary,
 }
 
-async fn collect_test_results_from_test_providers(
-    test_providers: TestProviderMap,
-    proxy_connection: ConnectionDescriptor,
-) -> HashMap<String, HashMap<String, Value>> {
-    stream::iter(test_providers)
-        .then(|(test_provider, (plugin, tests))| {
-            let proxy_connection: ConnectionDescriptor = proxy_connection.clone();
-            async move {
-                (
-                    test_provider,
-                    collect_test_results(plugin, proxy_connection, tests).await,
-                )
-            }
+/// Narrows the plan and the test providers down to the proxy, and optionally the test, named by `command`.
+fn select_for_rerun(
+    command: &Command,
+    plan: &SetupPlan,
+    test_providers: &TestProviderMap,
+) -> (SetupPlan, TestProviderMap) {
+    let (provider, proxy) = match command {
+        Command::RerunProxy { provider, proxy }
+        | Command::RerunTest {
+            provider, proxy, ..
+        } => (provider, proxy),
+    };
+    let plan = plan
+        .iter()
+        .filter(|(name, _)| *name == provider)
+        .map(|(name, proxies)| {
+            let proxies = proxies.iter().filter(|p| &p.name == proxy).cloned();
+            (name.clone(), proxies.collect())
         })
-        .collect()
-        .await
+        .collect();
+    let test_providers = match command {
+        Command::RerunProxy { .. } => test_providers.clone(),
+        Command::RerunTest {
+            test_provider,
+            test,
+            ..
+        } => test_providers
+            .iter()
+            .filter(|(name, _)| *name == test_provider)
+            .map(|(name, (plugin, tests))| {
+                let tests = tests.iter().filter(|t| &t.name == test).cloned();
+                (name.clone(), (plugin.clone(), tests.collect()))
+            })
+            .collect(),
+    };
+    (plan, test_providers)
 }
 
-async fn perform_speedtest_for_proxies(
-    plugin: Arc<dyn Plugin>,
-    proxies: Vec<ProtocolDescriptor>,
+/// Runs the speedtest while the TUI is open, serving its rerun commands until it is closed.
+async fn run_with_tui(
+    plan: SetupPlan,
     test_providers: TestProviderMap,
-) -> HashMap<String, HashMap<String, HashMap<String, Value>>> {
-    let setup_proxy_future = try_set_up_proxy(plugin);
-    stream::iter(proxies)
-        .filter_map(|proxy| {
-            let proxy_name = proxy.name.clone();
-            let proxy_connection = setup_proxy_future(proxy);
-            let test_providers = test_providers.clone();
-            async move {
-                let proxy_connection = proxy_connection.await;
-                match proxy_connection {
-                    None => None,
-                    Some(proxy_connection) => Some((
-                        proxy_name,
-                        collect_test_results_from_test_providers(test_providers, proxy_connection)
-                            .await,
-                    )),
-                }
-            }
-        })
-        .collect()
-        .await
-}
+    run: Arc<RunContext>,
+    event_receiver: UnboundedReceiver<Event>,
+    log_receiver: UnboundedReceiver<String>,
+) -> anyhow::Result<RunResults> {
+    let (command_sender, mut command_receiver) = mpsc::unbounded_channel();
+    let cancel = run.cancel.clone();
+    let mut ui = tokio::task::spawn_blocking(move || {
+        tui::run(event_receiver, log_receiver, command_sender, cancel)
+    });
 
-async fn perform_speedtest_for_proxy_providers(
-    proxy_providers: ProxyProviderMap,
-    test_providers: TestProviderMap,
-) -> HashMap<String, HashMap<String, HashMap<String, HashMap<String, Value>>>> {
-    stream::iter(proxy_providers)
-        .then(|(provider, (plugin, proxies))| {
-            let test_providers = test_providers.clone();
-            async move {
-                (
-                    provider,
-                    perform_speedtest_for_proxies(plugin, proxies, test_providers).await,
-                )
+    let mut runs = FuturesUnordered::new();
+    runs.push(run_with_reporter(plan.clone(), test_providers.clone(), run.clone()).boxed_local());
+    let mut results = HashMap::new();
+    loop {
+        select! {
+            ui_result = &mut ui => {
+                ui_result??;
+                break;
             }
-        })
-        .collect()
-        .await
-}
-
-fn try_run_test(
-    plugin: Arc<dyn Plugin>,
-    proxy_connection: ConnectionDescriptor,
-) -> impl Fn(TestDescriptor) -> Pin<Box<dyn Future<Output = Option<(String, Value)>>>> {
-    move |test| {
-        let plugin = plugin.clone();
-        let proxy_connection = proxy_connection.clone();
-        async move {
-            let test_result: Result<Value, speedtest_controller::plugin::PluginError> =
-                plugin.run_test(&test, &proxy_connection).await;
-            match test_result {
-                Ok(p) => Some((test.name.clone(), p)),
-                Err(e) => {
-                    log::error!("Failed to run test {test:?} given {proxy_connection:?}. {e}");
-                    None
-                }
+            Some(command) = command_receiver.recv() => {
+                let (plan, test_providers) =
+                    select_for_rerun(&command, &plan, &test_providers);
+                runs.push(run::rerun(plan, test_providers, run.clone()).boxed_local());
             }
+            Some(run_results) = runs.next() => merge_results(&mut results, run_results),
+        }
+    }
+    // Closing the UI interrupts whatever is still running.
+    if !runs.is_empty() {
+        run.cancel.cancel();
+        while let Some(run_results) = runs.next().await {
+            merge_results(&mut results, run_results);
         }
-        .boxed()
+    }
+    Ok(results)
+}
+
+/// Keeps the checkpoint of an interrupted run, or removes the one of a completed run.
+fn finish_checkpoint(
+    checkpoint: &CheckpointWriter,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    if cancel.is_cancelled() {
+        checkpoint.save()?;
+        log::warn!(
+            "Run interrupted, continue it with --resume --checkpoint {}",
+            checkpoint.path().display()
+        );
+        return Ok(());
+    }
+    match std::fs::remove_file(checkpoint.path()) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
     }
 }
 
-fn try_set_up_proxy(
-    plugin: Arc<dyn Plugin>,
-) -> impl Fn(ProtocolDescriptor) -> Pin<Box<dyn Future<Output = Option<ConnectionDescriptor>>>> {
-    move |proxy| {
-        let plugin = plugin.clone();
+/// Runs the proxies from every vantage point at once, ranking them per vantage point.
+async fn run_at_vantage_points(
+    speedtest: &SpeedTest,
+    config: &ControllerConfig,
+    proxy_providers: &ProxyProviderMap,
+    args: &Args,
+    resumed: Checkpoint,
+    cancel: CancellationToken,
+) -> anyhow::Result<Output> {
+    let checkpoint = Arc::new(CheckpointWriter::new(
+        args.checkpoint.clone(),
+        resumed.clone(),
+    ));
+    // One reporter for every run, which tells them apart by the vantage point of each event.
+    let reporter = progress::stderr_reporter();
+    let runs = join_all(config.vantage_points.iter().map(|(name, point)| {
+        let reporter = reporter.clone();
+        let resumed = resumed
+            .vantage_points
+            .get(name)
+            .cloned()
+            .unwrap_or_default();
+        let cancel = cancel.clone();
+        let checkpoint = checkpoint.clone();
         async move {
-            let proxy_connection = plugin.setup_proxy(proxy.content).await;
-            match proxy_connection {
-                Err(e) => {
-                    log::error!("Cannot setup proxy. {e}");
-                    None
-                }
-                Ok(proxy_connection) => Some(proxy_connection),
+            let location =
+                Location::new(&speedtest.at(point), proxy_providers, &config.setup).await;
+            let (proxies, tests) = (location.proxies(), location.tests());
+            let run = Arc::new(RunContext {
+                reporter,
+                cancel,
+                resumed: resumed.clone(),
+                retry: config.retry.clone(),
+                attempts: Default::default(),
+                router: location.router,
+                tun_test_providers: location.tun_test_providers,
+                isolation: config.isolation.clone(),
+                netns_backends: location.netns_backends,
+                proxy_locks: Default::default(),
+                checkpoint: Some(checkpoint),
+                vantage_point: Some(name.clone()),
+            });
+            let mut test_results =
+                run_with_reporter(location.plan, location.test_providers, run.clone()).await;
+            merge_results(&mut test_results, resumed.test_results);
+            let attempts = std::mem::take(&mut *run.attempts.lock().unwrap());
+            (name.clone(), test_results, attempts, proxies, tests)
+        }
+    }))
+    .await;
+    finish_checkpoint(&checkpoint, &cancel)?;
+    let vantage_points = runs
+        .into_iter()
+        .map(|(name, test_results, attempts, proxies, tests)| {
+            let mut ranking = config
+                .scoring
+                .rank(&test_results, &proxies, &tests, &attempts);
+            let summary = LocationSummary::new(&ranking);
+            if let Some(top) = args.top {
+                ranking.truncate(top);
             }
+            let output = VantageOutput {
+                test_results,
+                attempts,
+                ranking,
+                summary,
+            };
+            (name, output)
+        })
+        .collect();
+    Ok(Output {
+        vantage_points,
+        ..Default::default()
+    })
+}
+
+/// Cancels the run on SIGINT or SIGTERM, and exits right away on the next one, e.g. when a
+/// teardown hangs.
+async fn cancel_on_signal(cancel: CancellationToken) -> std::io::Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    loop {
+        select! {
+            _ = interrupt.recv() => {},
+            _ = terminate.recv() => {},
+        }
+        if cancel.is_cancelled() {
+            log::error!("Interrupted again, exiting without tearing down the proxies");
+            std::process::exit(130);
         }
-        .boxed()
+        log::warn!("Interrupted, tearing down the active proxies, interrupt again to exit");
+        cancel.cancel();
     }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
-    println!("{:?}", std::env::current_dir()?);
     let args = Args::parse();
+    // The TUI owns the terminal, so it shows the log itself.
+    let log_receiver = if args.tui {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        env_logger::Builder::from_default_env()
+            .target(env_logger::Target::Pipe(Box::new(LogWriter::new(sender))))
+            .init();
+        Some(receiver)
+    } else {
+        env_logger::init();
+        None
+    };
+    if let Some(Commands::PluginSpec) = args.command {
+        println!("{}", serde_json::to_string_pretty(&spec::openrpc())?);
+        return Ok(());
+    }
+    println!("{:?}", std::env::current_dir()?);
     let settings = Config::builder()
         .add_source(config::File::with_name(&args.config))
         .build()?;
-    let config: ControllerConfig = settings.try_deserialize()?;
-    let speedtest = SpeedTest::new(config.plugins).await;
+    let mut config: ControllerConfig = settings.try_deserialize()?;
+    let mut plugins = discovery::discover(&config.plugin_dirs);
+    plugins.extend(std::mem::take(&mut config.plugins));
+    if config.isolation.enabled {
+        for (name, plugin) in plugins.iter_mut() {
+            plugin.allow_isolation(name);
+        }
+    }
+    if let Some(dir) = &args.record {
+        std::fs::create_dir_all(dir)?;
+        for (name, plugin) in plugins.iter_mut() {
+            plugin.record_to(dir.join(format!("{name}.ndjson")));
+        }
+    }
+    let resumed = if args.resume {
+        Checkpoint::load(&args.checkpoint)?
+    } else {
+        Checkpoint::default()
+    };
+    let cancel = CancellationToken::new();
+    tokio::spawn(cancel_on_signal(cancel.clone()));
+    let speedtest = SpeedTest::new(plugins).await;
     let proxy_providers = speedtest
         .get_proxy_provider(&config.connection_string)
         .await;
-    let test_providers = speedtest.get_test_provider().await;
+    if !config.vantage_points.is_empty() {
+        anyhow::ensure!(
+            !args.tui && args.export.is_none(),
+            "--tui and --export are not supported with vantage points"
+        );
+        let output = run_at_vantage_points(
+            &speedtest,
+            &config,
+            &proxy_providers,
+            &args,
+            resumed,
+            cancel,
+        )
+        .await?;
+        println!("{:?}", output);
+        speedtest.shutdown().await;
+        return Ok(());
+    }
+    let location = Location::new(&speedtest, &proxy_providers, &config.setup).await;
+    let descriptors: ProxyDescriptorMap = location
+        .plan
+        .iter()
+        .flat_map(|(provider, proxies)| {
+            proxies
+                .iter()
+                .map(|p| ((provider.clone(), p.name.clone()), p.descriptor.clone()))
+        })
+        .collect();
+    let proxies = location.proxies();
+    let tests = location.tests();
+    let (reporter, tui_events): (Arc<dyn Reporter>, _) = if let Some(logs) = log_receiver {
+        let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        (
+            Arc::new(TuiReporter::new(event_sender)),
+            Some((event_receiver, logs)),
+        )
+    } else {
+        (progress::stderr_reporter(), None)
+    };
+    let checkpoint = Arc::new(CheckpointWriter::new(
+        args.checkpoint.clone(),
+        resumed.clone(),
+    ));
+    let run = Arc::new(RunContext {
+        reporter,
+        cancel: cancel.clone(),
+        resumed: resumed.clone(),
+        retry: config.retry,
+        attempts: Default::default(),
+        router: location.router,
+        tun_test_providers: location.tun_test_providers,
+        isolation: config.isolation,
+        netns_backends: location.netns_backends,
+        proxy_locks: Default::default(),
+        checkpoint: Some(checkpoint.clone()),
+        vantage_point: None,
+    });
+    let (plan, test_providers) = (location.plan, location.test_providers);
+    let mut test_results = match tui_events {
+        Some((events, logs)) => {
+            run_with_tui(plan, test_providers, run.clone(), events, logs).await?
+        }
+        None => run_with_reporter(plan, test_providers, run.clone()).await,
+    };
+    merge_results(&mut test_results, resumed.test_results);
+    finish_checkpoint(&checkpoint, &cancel)?;
+    let attempts = std::mem::take(&mut *run.attempts.lock().unwrap());
+    let mut ranking = config
+        .scoring
+        .rank(&test_results, &proxies, &tests, &attempts);
+    if let Some(top) = args.top {
+        ranking.truncate(top);
+    }
+    if let (Some(path), Some(format)) = (&args.export, args.export_format) {
+        std::fs::write(path, export::export(format, &ranking, &descriptors)?)?;
+    }
     let output: Output = Output {
-        test_results: perform_speedtest_for_proxy_providers(proxy_providers, test_providers).await,
+        test_results,
+        attempts,
+        ranking,
+        ..Default::default()
     };
     println!("{:?}", output);
+    speedtest.shutdown().await;
     Ok(())
 }
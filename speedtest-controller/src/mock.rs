@@ -0,0 +1,327 @@
+//! A scriptable JSON-RPC plugin, which serves canned proxies and test results so that the
+//! controller can be exercised without any real proxy.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use jsonrpsee::server::{RpcModule, Server, ServerHandle};
+use jsonrpsee::types::{ErrorObject, ErrorObjectOwned};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tower::ServiceBuilder;
+
+use crate::plugin::{
+    Capabilities, ConnectionDescriptor, DataTransformDescriptor, PluginMetaData, ProtocolDescriptor,
+};
+use crate::server::AuthLayer;
+
+/// How the mock answers a call.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct MockResponse {
+    /// Returned when the call succeeds
+    pub result: Value,
+    /// Fails the call with this message instead
+    pub error: Option<String>,
+    /// Fails this many calls before answering as configured, to exercise retries
+    pub fail_times: u32,
+    /// Waits this long before answering, e.g. to simulate latency
+    pub delay_ms: u64,
+    /// Never answers
+    pub hang: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MockProxy {
+    #[serde(flatten)]
+    pub descriptor: ProtocolDescriptor,
+    /// Answer to `setup_proxy`. A connection naming the proxy is returned when `result` is null.
+    #[serde(default)]
+    pub setup: MockResponse,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MockTest {
+    pub name: String,
+    /// Answer to `run_test` for every proxy without an entry in `proxies`
+    #[serde(default)]
+    pub response: MockResponse,
+    /// Proxy name -> answer to `run_test`
+    #[serde(default)]
+    pub proxies: HashMap<String, MockResponse>,
+}
+
+fn default_metadata() -> PluginMetaData {
+    PluginMetaData {
+        name: "mock".to_owned(),
+        protocol_version: crate::plugin::PROTOCOL_VERSION,
+        version: None,
+        capabilities: Capabilities {
+            parser: true,
+            proxy_backend: true,
+            test_provider: true,
+            ..Default::default()
+        },
+    }
+}
+
+/// Everything the mock answers.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MockScript {
+    #[serde(default = "default_metadata")]
+    pub metadata: PluginMetaData,
+    #[serde(default)]
+    pub proxies: Vec<MockProxy>,
+    #[serde(default)]
+    pub tests: Vec<MockTest>,
+    /// Answer to `proxy_schemes`
+    #[serde(default)]
+    pub proxy_schemes: Vec<String>,
+    /// Answer to `proxy_protocols`
+    #[serde(default)]
+    pub proxy_protocols: Vec<String>,
+    /// Answer to `data_transforms`. `transform` wraps the content as
+    /// `{"transformed_by": <name>, "content": <content>}`
+    #[serde(default)]
+    pub data_transforms: Vec<DataTransformDescriptor>,
+}
+
+impl Default for MockScript {
+    fn default() -> Self {
+        MockScript {
+            metadata: default_metadata(),
+            proxies: vec![],
+            tests: vec![],
+            proxy_schemes: vec![],
+            proxy_protocols: vec![],
+            data_transforms: vec![],
+        }
+    }
+}
+
+/// The connection the mock returns for a proxy, which tells `run_test` which proxy is tested.
+fn connection_of(proxy: &str) -> ConnectionDescriptor {
+    ConnectionDescriptor {
+        http: None,
+        socks5: Some(format!("socks5://mock/{proxy}")),
+        tun: false,
+        ..Default::default()
+    }
+}
+
+fn proxy_of(connection: &ConnectionDescriptor) -> Option<&str> {
+    connection.socks5.as_deref()?.strip_prefix("socks5://mock/")
+}
+
+/// The content a proxy was parsed into, before any transform wrapped it.
+fn untransformed(content: &Value) -> &Value {
+    match content.get("transformed_by") {
+        Some(_) => untransformed(&content["content"]),
+        None => content,
+    }
+}
+
+struct MockState {
+    script: MockScript,
+    /// Every call received, as `method` or `method:proxy`
+    calls: Mutex<Vec<String>>,
+    /// Calls failed so far, by the same key as `calls`
+    failures: Mutex<HashMap<String, u32>>,
+}
+
+impl MockState {
+    fn find_proxy(&self, content: &Value) -> Result<&MockProxy, ErrorObjectOwned> {
+        let content = untransformed(content);
+        self.script
+            .proxies
+            .iter()
+            .find(|p| p.descriptor.content == *content)
+            .ok_or_else(|| ErrorObject::owned(2, "unknown proxy", None::<()>))
+    }
+
+    /// The proxy to set up for `content`, which must have been transformed when the mock does
+    /// not accept the scheme it was parsed as.
+    fn proxy_to_set_up(&self, content: &Value) -> Result<&MockProxy, ErrorObjectOwned> {
+        let proxy = self.find_proxy(content)?;
+        let schemes = &self.script.proxy_schemes;
+        let accepted = match &proxy.descriptor.scheme {
+            Some(scheme) if !schemes.is_empty() => schemes.contains(scheme),
+            _ => true,
+        };
+        if !accepted && content.get("transformed_by").is_none() {
+            return Err(ErrorObject::owned(2, "untransformed proxy", None::<()>));
+        }
+        Ok(proxy)
+    }
+
+    fn record(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    async fn answer(
+        &self,
+        key: String,
+        response: &MockResponse,
+    ) -> Result<Value, ErrorObjectOwned> {
+        self.record(key.clone());
+        if response.hang {
+            std::future::pending::<()>().await;
+        }
+        tokio::time::sleep(Duration::from_millis(response.delay_ms)).await;
+        {
+            let mut failures = self.failures.lock().unwrap();
+            let failed = failures.entry(key).or_default();
+            if *failed < response.fail_times {
+                *failed += 1;
+                return Err(ErrorObject::owned(
+                    1,
+                    "scripted transient failure",
+                    None::<()>,
+                ));
+            }
+        }
+        match &response.error {
+            Some(message) => Err(ErrorObject::owned(1, message.clone(), None::<()>)),
+            None => Ok(response.result.clone()),
+        }
+    }
+}
+
+/// A running mock plugin, stopped when dropped.
+pub struct MockServer {
+    pub addr: SocketAddr,
+    state: Arc<MockState>,
+    handle: ServerHandle,
+}
+
+impl MockServer {
+    /// Every call received so far, as `method` or `method:proxy`.
+    pub fn calls(&self) -> Vec<String> {
+        self.state.calls.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        let _ = self.handle.stop();
+    }
+}
+
+/// Serves `script` on a local port.
+pub async fn serve(script: MockScript) -> anyhow::Result<MockServer> {
+    let server = Server::builder()
+        .set_http_middleware(ServiceBuilder::new().layer(AuthLayer::from_env()))
+        .build("127.0.0.1:0")
+        .await?;
+    let state = Arc::new(MockState {
+        script,
+        calls: Default::default(),
+        failures: Default::default(),
+    });
+    let mut module = RpcModule::new(state.clone());
+    module.register_method("metadata", |_, state| {
+        state.record("metadata".to_owned());
+        state.script.metadata.clone()
+    })?;
+    module.register_method("init", |_, state| {
+        state.record("init".to_owned());
+        Value::Null
+    })?;
+    module.register_method("parse_protocol", |_, state| {
+        state.record("parse_protocol".to_owned());
+        let proxies: Vec<_> = state
+            .script
+            .proxies
+            .iter()
+            .map(|p| p.descriptor.clone())
+            .collect();
+        json!(proxies)
+    })?;
+    module.register_method("tests", |_, state| {
+        state.record("tests".to_owned());
+        let tests: Vec<_> = state
+            .script
+            .tests
+            .iter()
+            .map(|t| json!({ "name": t.name }))
+            .collect();
+        Value::Array(tests)
+    })?;
+    module.register_method("proxy_schemes", |_, state| {
+        state.record("proxy_schemes".to_owned());
+        json!(state.script.proxy_schemes)
+    })?;
+    module.register_method("proxy_protocols", |_, state| {
+        state.record("proxy_protocols".to_owned());
+        json!(state.script.proxy_protocols)
+    })?;
+    module.register_method("data_transforms", |_, state| {
+        state.record("data_transforms".to_owned());
+        json!(state.script.data_transforms)
+    })?;
+    module.register_method("transform", |params, state| {
+        let (name, content): (String, Value) = params.parse()?;
+        if !state.script.data_transforms.iter().any(|t| t.name == name) {
+            return Err(ErrorObject::owned(2, "unknown transform", None::<()>));
+        }
+        let proxy = state.find_proxy(&content)?;
+        state.record(format!("transform:{}", proxy.descriptor.name));
+        Ok::<_, ErrorObjectOwned>(json!({ "transformed_by": name, "content": content }))
+    })?;
+    module.register_async_method("setup_proxy", |params, state| async move {
+        let (content,): (Value,) = params.parse()?;
+        let proxy = state.proxy_to_set_up(&content)?;
+        let name = &proxy.descriptor.name;
+        let result = state
+            .answer(format!("setup_proxy:{name}"), &proxy.setup)
+            .await?;
+        Ok::<_, ErrorObjectOwned>(match result {
+            Value::Null => json!(connection_of(name)),
+            result => result,
+        })
+    })?;
+    module.register_async_method("setup_proxy_in_netns", |params, state| async move {
+        let (content, netns): (Value, String) = params.parse()?;
+        let proxy = state.proxy_to_set_up(&content)?;
+        let name = &proxy.descriptor.name;
+        let result = state
+            .answer(format!("setup_proxy_in_netns:{name}"), &proxy.setup)
+            .await?;
+        Ok::<_, ErrorObjectOwned>(match result {
+            Value::Null => json!(ConnectionDescriptor {
+                netns: Some(netns),
+                ..connection_of(name)
+            }),
+            result => result,
+        })
+    })?;
+    module.register_method("teardown_proxy", |params, state| {
+        let (connection,): (ConnectionDescriptor,) = params.parse()?;
+        let proxy = proxy_of(&connection).unwrap_or_default();
+        state.record(format!("teardown_proxy:{proxy}"));
+        Ok::<_, ErrorObjectOwned>(Value::Null)
+    })?;
+    module.register_async_method("run_test", |params, state| async move {
+        let (test, connection): (String, ConnectionDescriptor) = params.parse()?;
+        let script = state
+            .script
+            .tests
+            .iter()
+            .find(|t| t.name == test)
+            .ok_or_else(|| ErrorObject::owned(2, "unknown test", None::<()>))?;
+        let proxy = proxy_of(&connection).unwrap_or_default();
+        let response = script.proxies.get(proxy).unwrap_or(&script.response);
+        state
+            .answer(format!("run_test:{test}:{proxy}"), response)
+            .await
+    })?;
+
+    let addr = server.local_addr()?;
+    let handle = server.start(module);
+    Ok(MockServer {
+        addr,
+        state,
+        handle,
+    })
+}
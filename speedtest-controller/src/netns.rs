@@ -0,0 +1,226 @@
+//! Network namespaces of proxies, either reported by a TUN proxy or created by the controller
+//! to isolate the proxies under test from each other.
+//!
+//! A network namespace only applies to the thread entering it, so everything done inside one
+//! runs on a thread of its own, which is discarded afterwards. Namespaces only exist on Linux,
+//! elsewhere entering or creating one fails.
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde::Deserialize;
+
+/// Where `ip netns add` creates named namespaces.
+pub const NETNS_DIR: &str = "/run/netns";
+
+/// The path of `netns`, given either by name or as a path.
+pub fn netns_path(netns: &str) -> PathBuf {
+    if netns.contains('/') {
+        PathBuf::from(netns)
+    } else {
+        Path::new(NETNS_DIR).join(netns)
+    }
+}
+
+/// Moves the calling thread into the namespace at `path`.
+pub fn enter(path: &Path) -> io::Result<()> {
+    enter_fd(&File::open(path)?)
+}
+
+/// Runs `task` on a thread of its own, free to change its namespace.
+async fn on_thread<T: Send + 'static>(
+    task: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(task());
+    });
+    receiver
+        .await
+        .map_err(|_| io::Error::other("The thread inside the network namespace panicked"))?
+}
+
+/// Runs the future made by `task` inside `netns`, with a runtime of its own.
+pub async fn run_in<F, Fut>(netns: &str, task: F) -> io::Result<Fut::Output>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future,
+    Fut::Output: Send + 'static,
+{
+    let path = netns_path(netns);
+    on_thread(move || {
+        enter(&path)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(runtime.block_on(task()))
+    })
+    .await
+}
+
+/// Isolates the proxies under test from each other.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct IsolationConfig {
+    /// Sets up and tests every proxy in a network namespace of its own, on the backends that
+    /// support it, so that their listeners and routes cannot collide
+    pub enabled: bool,
+    /// Connects every namespace to the host through a veth pair in a /30 of `10.231.0.0/16`,
+    /// so that the proxies reach their servers. The host still has to forward and masquerade
+    /// that subnet. Without it, a namespace only has a loopback interface
+    pub veth: bool,
+}
+
+/// The subnet veth pairs take their /30 from.
+const VETH_SUBNET: Ipv4Addr = Ipv4Addr::new(10, 231, 0, 0);
+
+/// The index of the /30 of the next veth pair.
+static NEXT_VETH: AtomicU32 = AtomicU32::new(0);
+
+/// Runs `ip` with `args` in the namespace of the calling thread.
+fn ip(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("ip").args(args).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "`ip {}` failed with {status}",
+            args.join(" ")
+        )));
+    }
+    Ok(())
+}
+
+/// A network namespace created for one proxy. It is gone once dropped and left by every process.
+pub struct Namespace {
+    handle: File,
+    path: String,
+}
+
+impl Namespace {
+    /// Creates a namespace with its loopback up, and a veth pair if `config` asks for one.
+    pub async fn create(config: &IsolationConfig) -> io::Result<Self> {
+        let veth = config
+            .veth
+            .then(|| NEXT_VETH.fetch_add(1, Ordering::Relaxed) % (1 << 14));
+        let handle = on_thread(move || {
+            let host = File::open("/proc/thread-self/ns/net")?;
+            unshare()?;
+            ip(&["link", "set", "lo", "up"])?;
+            let namespace = File::open("/proc/thread-self/ns/net")?;
+            if let Some(index) = veth {
+                connect_veth(index, &host, &namespace)?;
+            }
+            Ok(namespace)
+        })
+        .await?;
+        Ok(Namespace {
+            path: format!("/proc/{}/fd/{}", std::process::id(), handle.as_raw_fd()),
+            handle,
+        })
+    }
+
+    /// The path other processes enter the namespace by, while it is not dropped.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl std::fmt::Debug for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Namespace")
+            .field("path", &self.path)
+            .field("fd", &self.handle.as_raw_fd())
+            .finish()
+    }
+}
+
+/// Connects `namespace` to `host` through the `index`th veth pair, while in `namespace`.
+fn connect_veth(index: u32, host: &File, namespace: &File) -> io::Result<()> {
+    let base = u32::from(VETH_SUBNET) + index * 4;
+    let (host_address, address) = (Ipv4Addr::from(base + 1), Ipv4Addr::from(base + 2));
+    let name = format!("stveth{index}");
+    let netns = format!("/proc/{}/fd/{}", std::process::id(), namespace.as_raw_fd());
+    enter_fd(host)?;
+    ip(&[
+        "link", "add", &name, "type", "veth", "peer", "name", "eth0", "netns", &netns,
+    ])?;
+    ip(&["addr", "add", &format!("{host_address}/30"), "dev", &name])?;
+    ip(&["link", "set", &name, "up"])?;
+    enter_fd(namespace)?;
+    ip(&["addr", "add", &format!("{address}/30"), "dev", "eth0"])?;
+    ip(&["link", "set", "eth0", "up"])?;
+    ip(&["route", "add", "default", "via", &host_address.to_string()])
+}
+
+#[cfg(target_os = "linux")]
+fn enter_fd(namespace: &File) -> io::Result<()> {
+    // SAFETY: `namespace` is an open file descriptor.
+    if unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Moves the calling thread into a new namespace.
+#[cfg(target_os = "linux")]
+fn unshare() -> io::Result<()> {
+    // SAFETY: Only changes the namespace of the calling thread.
+    if unsafe { libc::unshare(libc::CLONE_NEWNET) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enter_fd(_namespace: &File) -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unshare() -> io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "network namespaces are only supported on Linux",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fails_to_enter_a_missing_namespace() {
+        assert_eq!(netns_path("proxy"), Path::new("/run/netns/proxy"));
+        let result = run_in("speedtest-missing-netns", || async {}).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs CAP_SYS_ADMIN to create network namespaces"]
+    async fn isolates_listeners() {
+        let host = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = host.local_addr().unwrap().port();
+        let namespace = Namespace::create(&IsolationConfig::default())
+            .await
+            .unwrap();
+        // The port taken on the host is free inside, where the host listener is unreachable.
+        let inside = run_in(namespace.path(), move || async move {
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await?;
+            let connected = tokio::net::TcpStream::connect(("127.0.0.1", port)).await;
+            drop(listener);
+            connected.map(|_| ())
+        })
+        .await
+        .unwrap();
+        assert!(inside.is_ok(), "{inside:?}");
+    }
+}
@@ -2,12 +2,18 @@
 /// The `Plugin` trait defines the interface for a plugin that can be used in the speedtest controller.
 /// It provides methods for configuring the plugin, retrieving metadata, running tests, and performing data transformations.
 /// The module also includes various supporting types and macros used by the `Plugin` trait and its implementations.
+pub mod builtin;
 pub mod json_rpc;
+pub mod record;
+pub mod replay;
+pub mod wasm;
 
 use async_trait::async_trait;
 use jsonrpsee::client_transport::ws::WsHandshakeError;
+use jsonrpsee::core::ClientError;
 use jsonrpsee::types::{error::ErrorCode, ResponsePayload};
 use jsonrpsee::IntoResponse;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thiserror::Error;
@@ -15,14 +21,71 @@ use thiserror::Error;
 /// An error type representing various plugin-related errors.
 #[derive(Error, Debug)]
 pub enum PluginError {
-    #[error("json-rpc client error")]
+    #[error("json-rpc client error. {0}")]
     ClientError(#[from] jsonrpsee::core::ClientError),
-    #[error("json-rpc returns an invalid response")]
+    #[error("json-rpc returns an invalid response. {0}")]
     APIBadResponse(#[from] serde_json::Error),
-    #[error("Unable to parse the url")]
+    #[error("Unable to parse the url. {0}")]
     ParseError(#[from] url::ParseError),
-    #[error("Unable to perform the ws handshake")]
+    #[error("Unable to perform the ws handshake. {0}")]
     WsHandshakeError(#[from] WsHandshakeError),
+    /// A plugin running inside the controller failed the call
+    #[error("Plugin call failed. {0}")]
+    CallFailed(String),
+    #[error("The plugin does not implement this method")]
+    Unsupported,
+}
+
+/// A coarse classification of `PluginError`, used to decide whether a failed call is retried.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginErrorKind {
+    /// The plugin handled the call and returned an error object.
+    Call,
+    /// The connection to the plugin failed while the call was in flight.
+    Transport,
+    /// The plugin did not answer in time.
+    Timeout,
+    /// The connection to the plugin is gone for good.
+    Disconnected,
+    /// The plugin answered with something the controller cannot understand.
+    BadResponse,
+    /// Everything else, e.g. a malformed endpoint.
+    Other,
+}
+
+impl PluginError {
+    pub fn kind(&self) -> PluginErrorKind {
+        match self {
+            PluginError::ClientError(e) => match e {
+                ClientError::Call(_) => PluginErrorKind::Call,
+                ClientError::Transport(_) => PluginErrorKind::Transport,
+                ClientError::RequestTimeout => PluginErrorKind::Timeout,
+                ClientError::RestartNeeded(_) => PluginErrorKind::Disconnected,
+                ClientError::ParseError(_) => PluginErrorKind::BadResponse,
+                _ => PluginErrorKind::Other,
+            },
+            PluginError::CallFailed(_) | PluginError::Unsupported => PluginErrorKind::Call,
+            PluginError::APIBadResponse(_) => PluginErrorKind::BadResponse,
+            PluginError::ParseError(_) | PluginError::WsHandshakeError(_) => PluginErrorKind::Other,
+        }
+    }
+
+    /// Whether the plugin does not implement the method that was called.
+    pub fn is_method_not_found(&self) -> bool {
+        match self {
+            PluginError::Unsupported => true,
+            PluginError::ClientError(ClientError::Call(e)) => {
+                e.code() == ErrorCode::MethodNotFound.code()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Fails a call to a plugin running inside the controller.
+pub(crate) fn call_error(message: impl Into<String>) -> PluginError {
+    PluginError::CallFailed(message.into())
 }
 
 /// An enum representing the type of a plugin.
@@ -30,43 +93,135 @@ pub enum PluginError {
 pub enum PluginType {
     #[default]
     JSONRPC,
+    /// A WASI module run inside the controller, see `wasm::WasmPlugin`
+    Wasm,
 }
 
 /// A type alias for the result of plugin operations.
 type Result<T> = std::result::Result<T, PluginError>;
 
+/// The version of the plugin protocol spoken by this controller.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest plugin protocol version this controller still speaks.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+fn default_protocol_version() -> u32 {
+    MIN_PROTOCOL_VERSION
+}
+
+/// What a plugin is able to do, which decides the methods the controller calls on it.
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone, Default, JsonSchema)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Implements `parse_protocol`
+    pub parser: bool,
+    /// Implements `setup_proxy` and `teardown_proxy`, and may declare `proxy_schemes`
+    pub proxy_backend: bool,
+    /// Implements `tests` and `run_test`
+    pub test_provider: bool,
+    /// Implements `data_transforms` and `transform`
+    pub transformer: bool,
+    /// Runs its tests through a TUN device, or inside the network namespace of the proxy
+    pub supports_tun: bool,
+    /// Implements `setup_proxy_in_netns`
+    pub supports_netns: bool,
+}
+
+impl Capabilities {
+    /// Assumed for plugins that predate capabilities, which were called for everything.
+    pub fn legacy() -> Self {
+        Capabilities {
+            parser: true,
+            proxy_backend: true,
+            test_provider: true,
+            transformer: true,
+            supports_tun: false,
+            supports_netns: false,
+        }
+    }
+
+    /// What both `self` and `other` are capable of.
+    pub fn intersection(&self, other: &Capabilities) -> Self {
+        Capabilities {
+            parser: self.parser && other.parser,
+            proxy_backend: self.proxy_backend && other.proxy_backend,
+            test_provider: self.test_provider && other.test_provider,
+            transformer: self.transformer && other.transformer,
+            supports_tun: self.supports_tun && other.supports_tun,
+            supports_netns: self.supports_netns && other.supports_netns,
+        }
+    }
+}
+
 /// Metadata associated with a plugin.
-#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct PluginMetaData {
     pub name: String,
+    /// The plugin protocol version the plugin speaks
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u32,
+    /// The version of the plugin itself
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default = "Capabilities::legacy")]
+    pub capabilities: Capabilities,
+}
+
+impl PluginMetaData {
+    pub fn is_compatible(&self) -> bool {
+        (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.protocol_version)
+    }
 }
 
 /// Descriptor for a test.
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct TestDescriptor {
     pub name: String,
 }
 
-/// Descriptor for a data transformation.
-#[derive(Debug, Deserialize)]
+/// Descriptor for a data transformation, which converts proxy content of `accpeted_scheme`
+/// into content of `produced_scheme`.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct DataTransformDescriptor {
     pub name: String,
     pub accpeted_scheme: String,
+    pub produced_scheme: String,
 }
 
 /// Descriptor for a protocol.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ProtocolDescriptor {
     pub name: String,
+    /// The format of `content`, e.g. `clash` or `sing-box`
+    #[serde(default)]
+    pub scheme: Option<String>,
+    /// The proxy protocol, e.g. `vmess` or `trojan`
+    #[serde(default)]
+    pub protocol: Option<String>,
     pub content: Value,
 }
 
 /// Descriptor for a connection.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct ConnectionDescriptor {
     pub http: Option<String>,
     pub socks5: Option<String>,
+    /// The proxy captures traffic through a TUN device, besides or instead of the endpoints
     pub tun: bool,
+    /// The TUN device, which tests bind their sockets to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// The network namespace routed through the TUN device, which tests run their traffic in.
+    /// Either a name under `/run/netns` or a path such as `/proc/<pid>/ns/net`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub netns: Option<String>,
+}
+
+impl ConnectionDescriptor {
+    /// Whether the proxy is reached through a TUN device.
+    pub fn uses_tun(&self) -> bool {
+        self.tun || self.interface.is_some() || self.netns.is_some()
+    }
 }
 
 /// A macro for implementing the `IntoResponse` trait for a given type.
@@ -96,6 +251,18 @@ pub trait Plugin: Send + Sync {
     /// Configures the plugin with the given proxy configuration.
     async fn setup_proxy(&self, proxy: serde_json::Value) -> Result<ConnectionDescriptor>;
 
+    /// Tears down a proxy previously returned by `setup_proxy`.
+    async fn teardown_proxy(&self, proxy: &ConnectionDescriptor) -> Result<()>;
+
+    /// Like `setup_proxy`, but runs the proxy inside the network namespace at the path `netns`.
+    async fn setup_proxy_in_netns(
+        &self,
+        _proxy: serde_json::Value,
+        _netns: &str,
+    ) -> Result<ConnectionDescriptor> {
+        Err(PluginError::Unsupported)
+    }
+
     /// Initialize the plugin
     async fn init(&self) -> Result<()>;
 
@@ -115,8 +282,31 @@ pub trait Plugin: Send + Sync {
     /// Retrieves the list of data transformations supported by the plugin.
     async fn data_transforms(&self) -> Result<Vec<DataTransformDescriptor>>;
 
+    /// Runs one of the data transformations returned by `data_transforms` on proxy content.
+    async fn transform(
+        &self,
+        transform: &DataTransformDescriptor,
+        content: serde_json::Value,
+    ) -> Result<serde_json::Value>;
+
+    /// Retrieves the schemes of proxy content that `setup_proxy` accepts.
+    async fn proxy_schemes(&self) -> Result<Vec<String>>;
+
+    /// Retrieves the proxy protocols that `setup_proxy` supports, any protocol if empty.
+    async fn proxy_protocols(&self) -> Result<Vec<String>>;
+
     /// Parses the given connection string and returns a list of supported protocols.
     async fn parse_protocol(&self, connection_string: &str) -> Result<Vec<ProtocolDescriptor>>;
+
+    /// Lets the plugin clean up, e.g. stop the proxies it still runs, before it is stopped.
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The process serving the plugin, if the controller launched one.
+    async fn process_id(&self) -> Option<u32> {
+        None
+    }
 }
 
 #[async_trait]
@@ -129,6 +319,18 @@ where
         self.deref().setup_proxy(proxy).await
     }
 
+    async fn teardown_proxy(&self, proxy: &ConnectionDescriptor) -> Result<()> {
+        self.deref().teardown_proxy(proxy).await
+    }
+
+    async fn setup_proxy_in_netns(
+        &self,
+        proxy: serde_json::Value,
+        netns: &str,
+    ) -> Result<ConnectionDescriptor> {
+        self.deref().setup_proxy_in_netns(proxy, netns).await
+    }
+
     async fn init(&self) -> Result<()> {
         self.deref().init().await
     }
@@ -153,7 +355,59 @@ where
         self.deref().data_transforms().await
     }
 
+    async fn transform(
+        &self,
+        transform: &DataTransformDescriptor,
+        content: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        self.deref().transform(transform, content).await
+    }
+
+    async fn proxy_schemes(&self) -> Result<Vec<String>> {
+        self.deref().proxy_schemes().await
+    }
+
+    async fn proxy_protocols(&self) -> Result<Vec<String>> {
+        self.deref().proxy_protocols().await
+    }
+
     async fn parse_protocol(&self, connection_string: &str) -> Result<Vec<ProtocolDescriptor>> {
         self.deref().parse_protocol(connection_string).await
     }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.deref().shutdown().await
+    }
+
+    async fn process_id(&self) -> Option<u32> {
+        self.deref().process_id().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_metadata_is_assumed_capable_of_everything() {
+        let metadata: PluginMetaData = serde_json::from_str(r#"{"name": "old"}"#).unwrap();
+        assert!(metadata.is_compatible());
+        assert_eq!(metadata.capabilities, Capabilities::legacy());
+
+        let metadata: PluginMetaData =
+            serde_json::from_str(r#"{"name": "new", "protocol_version": 99, "capabilities": {}}"#)
+                .unwrap();
+        assert!(!metadata.is_compatible());
+        assert_eq!(metadata.capabilities, Capabilities::default());
+    }
+
+    #[tokio::test]
+    async fn builtin_plugins_fail_unsupported_methods_as_calls() {
+        let plugin = builtin::load("latency", Value::Null).unwrap();
+        let error = plugin.data_transforms().await.unwrap_err();
+        assert!(matches!(error, PluginError::Unsupported));
+        assert!(error.is_method_not_found());
+        assert_eq!(error.kind(), PluginErrorKind::Call);
+        assert_eq!(call_error("refused").kind(), PluginErrorKind::Call);
+    }
 }
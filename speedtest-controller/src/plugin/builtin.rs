@@ -0,0 +1,119 @@
+//! Plugins compiled into the controller, loaded from `builtin://<name>` sources.
+mod clash_parser;
+mod latency;
+mod udp_relay;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde_json::Value;
+
+use super::{
+    call_error, Capabilities, ConnectionDescriptor, DataTransformDescriptor, Plugin, PluginError,
+    PluginMetaData, ProtocolDescriptor, Result, TestDescriptor, PROTOCOL_VERSION,
+};
+use crate::plugin_loader::PluginLoaderError;
+use clash_parser::ClashParser;
+use latency::Latency;
+use udp_relay::UdpRelay;
+
+/// The part of `Plugin` a built-in plugin implements, the other methods are unsupported.
+#[async_trait]
+pub trait Builtin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn capabilities(&self) -> Capabilities;
+
+    async fn parse_protocol(&self, _connection_string: &str) -> Result<Vec<ProtocolDescriptor>> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn tests(&self) -> Result<Vec<TestDescriptor>> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn run_test(
+        &self,
+        _test: &TestDescriptor,
+        _proxy: &ConnectionDescriptor,
+    ) -> Result<Value> {
+        Err(PluginError::Unsupported)
+    }
+}
+
+struct BuiltinPlugin<B>(B);
+
+#[async_trait]
+impl<B: Builtin> Plugin for BuiltinPlugin<B> {
+    async fn setup_proxy(&self, _proxy: Value) -> Result<ConnectionDescriptor> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn teardown_proxy(&self, _proxy: &ConnectionDescriptor) -> Result<()> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn init(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn metadata(&self) -> Result<PluginMetaData> {
+        Ok(PluginMetaData {
+            name: self.0.name().to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            capabilities: self.0.capabilities(),
+        })
+    }
+
+    async fn tests(&self) -> Result<Vec<TestDescriptor>> {
+        self.0.tests().await
+    }
+
+    async fn run_test(&self, test: &TestDescriptor, proxy: &ConnectionDescriptor) -> Result<Value> {
+        self.0.run_test(test, proxy).await
+    }
+
+    async fn data_transforms(&self) -> Result<Vec<DataTransformDescriptor>> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn transform(
+        &self,
+        _transform: &DataTransformDescriptor,
+        _content: Value,
+    ) -> Result<Value> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn proxy_schemes(&self) -> Result<Vec<String>> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn proxy_protocols(&self) -> Result<Vec<String>> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn parse_protocol(&self, connection_string: &str) -> Result<Vec<ProtocolDescriptor>> {
+        self.0.parse_protocol(connection_string).await
+    }
+}
+
+/// Creates the built-in plugin `name` with the `config` of its plugin entry.
+pub fn load(name: &str, config: Value) -> std::result::Result<Arc<dyn Plugin>, PluginLoaderError> {
+    // A missing config means the defaults.
+    let config = match config {
+        Value::Null => Value::Object(Default::default()),
+        config => config,
+    };
+    Ok(match name {
+        "clash-parser" => Arc::new(BuiltinPlugin(ClashParser::new(serde_json::from_value(
+            config,
+        )?))),
+        "latency" => Arc::new(BuiltinPlugin(Latency::new(serde_json::from_value(config)?))),
+        "udp-relay" => Arc::new(BuiltinPlugin(UdpRelay::new(serde_json::from_value(
+            config,
+        )?))),
+        _ => return Err(PluginLoaderError::UnknownBuiltin(name.to_owned())),
+    })
+}
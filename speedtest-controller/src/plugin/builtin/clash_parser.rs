@@ -0,0 +1,121 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+use super::{call_error, Builtin};
+use crate::plugin::{Capabilities, ProtocolDescriptor, Result};
+
+fn default_user_agent() -> String {
+    "clash".to_owned()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClashParserConfig {
+    /// Subscription services pick the format by user agent
+    #[serde(default = "default_user_agent")]
+    user_agent: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClashSubscription {
+    #[serde(default)]
+    proxies: Vec<Value>,
+}
+
+/// Parses Clash subscriptions, fetched over http(s) or read from a file.
+pub struct ClashParser {
+    config: ClashParserConfig,
+}
+
+impl ClashParser {
+    pub fn new(config: ClashParserConfig) -> Self {
+        ClashParser { config }
+    }
+
+    async fn fetch(&self, connection_string: &str) -> Result<String> {
+        let url = Url::parse(connection_string).ok();
+        match url.as_ref().map(Url::scheme) {
+            Some("http" | "https") => {
+                let response = reqwest::Client::new()
+                    .get(connection_string)
+                    .header(reqwest::header::USER_AGENT, &self.config.user_agent)
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status)
+                    .map_err(|e| call_error(format!("Unable to fetch the subscription. {e}")))?;
+                response
+                    .text()
+                    .await
+                    .map_err(|e| call_error(format!("Unable to fetch the subscription. {e}")))
+            }
+            Some("file") | None => {
+                let path = match &url {
+                    Some(url) => url.path(),
+                    None => connection_string,
+                };
+                tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| call_error(format!("Unable to read {path}. {e}")))
+            }
+            Some(scheme) => Err(call_error(format!("Unsupported scheme {scheme}"))),
+        }
+    }
+}
+
+#[async_trait]
+impl Builtin for ClashParser {
+    fn name(&self) -> &'static str {
+        "clash-parser"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            parser: true,
+            ..Default::default()
+        }
+    }
+
+    async fn parse_protocol(&self, connection_string: &str) -> Result<Vec<ProtocolDescriptor>> {
+        let subscription: ClashSubscription =
+            serde_yaml::from_str(&self.fetch(connection_string).await?)
+                .map_err(|e| call_error(format!("Not a Clash subscription. {e}")))?;
+        Ok(subscription
+            .proxies
+            .into_iter()
+            .filter_map(|proxy| {
+                let name = proxy.get("name")?.as_str()?.to_owned();
+                let protocol = proxy.get("type").and_then(Value::as_str).map(str::to_owned);
+                Some(ProtocolDescriptor {
+                    name,
+                    scheme: Some("clash".to_owned()),
+                    protocol,
+                    content: proxy,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parses_a_subscription_file() {
+        let path = std::env::temp_dir().join(format!("clash-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "proxies:\n  - {name: hk, type: ss, server: 1.2.3.4, port: 8388}\n  - {type: vmess}\n",
+        )
+        .unwrap();
+        let parser = ClashParser::new(serde_json::from_str("{}").unwrap());
+        let proxies = parser.parse_protocol(path.to_str().unwrap()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(proxies.len(), 1, "proxies without a name are skipped");
+        assert_eq!(proxies[0].name, "hk");
+        assert_eq!(proxies[0].protocol.as_deref(), Some("ss"));
+        assert_eq!(proxies[0].content["port"], 8388);
+    }
+}
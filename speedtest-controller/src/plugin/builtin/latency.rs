@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{call_error, Builtin};
+use crate::netns;
+use crate::plugin::{Capabilities, ConnectionDescriptor, Result, TestDescriptor};
+
+fn default_targets() -> HashMap<String, String> {
+    HashMap::from([(
+        "latency".to_owned(),
+        "http://www.gstatic.com/generate_204".to_owned(),
+    )])
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_samples() -> u32 {
+    3
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LatencyConfig {
+    /// Test name -> url requested through the proxy
+    #[serde(default = "default_targets")]
+    targets: HashMap<String, String>,
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    /// Requests made per test, the result has their mean and minimum
+    #[serde(default = "default_samples")]
+    samples: u32,
+}
+
+/// Measures how long a request through the proxy takes until the response headers arrive.
+pub struct Latency {
+    config: LatencyConfig,
+}
+
+impl Latency {
+    pub fn new(config: LatencyConfig) -> Self {
+        Latency { config }
+    }
+}
+
+fn client(timeout: Duration, proxy: &ConnectionDescriptor) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().timeout(timeout);
+    // A TUN proxy is reached without any proxy setting.
+    if let Some(url) = proxy.socks5.as_ref().or(proxy.http.as_ref()) {
+        let proxy = reqwest::Proxy::all(url)
+            .map_err(|e| call_error(format!("Invalid proxy {url}. {e}")))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(interface) = &proxy.interface {
+        #[cfg(target_os = "linux")]
+        {
+            builder = builder.interface(interface);
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(call_error(format!(
+            "Binding to interface {interface} is only supported on Linux"
+        )));
+    }
+    builder
+        .build()
+        .map_err(|e| call_error(format!("Unable to create the client. {e}")))
+}
+
+/// Requests `target` `samples` times, returning the mean and minimum time to the headers.
+async fn measure(client: reqwest::Client, target: String, samples: u32) -> Result<Value> {
+    let mut times = vec![];
+    let mut status = 0;
+    for _ in 0..samples.max(1) {
+        let started = Instant::now();
+        let response = client
+            .get(&target)
+            .send()
+            .await
+            .map_err(|e| call_error(format!("Request to {target} failed. {e}")))?;
+        times.push(started.elapsed().as_secs_f64() * 1000.0);
+        status = response.status().as_u16();
+    }
+    let mean = times.iter().sum::<f64>() / times.len() as f64;
+    let min = times.iter().copied().fold(f64::INFINITY, f64::min);
+    Ok(json!({ "ms": mean, "min_ms": min, "status": status }))
+}
+
+#[async_trait]
+impl Builtin for Latency {
+    fn name(&self) -> &'static str {
+        "latency"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            test_provider: true,
+            supports_tun: true,
+            ..Default::default()
+        }
+    }
+
+    async fn tests(&self) -> Result<Vec<TestDescriptor>> {
+        Ok(self
+            .config
+            .targets
+            .keys()
+            .map(|name| TestDescriptor { name: name.clone() })
+            .collect())
+    }
+
+    async fn run_test(&self, test: &TestDescriptor, proxy: &ConnectionDescriptor) -> Result<Value> {
+        let target = self
+            .config
+            .targets
+            .get(&test.name)
+            .ok_or_else(|| call_error(format!("Unknown test {}", test.name)))?;
+        let client = client(Duration::from_millis(self.config.timeout_ms), proxy)?;
+        let (target, samples) = (target.clone(), self.config.samples);
+        match &proxy.netns {
+            // The sockets of the client are only created once requests are made, in the namespace.
+            Some(netns) => netns::run_in(netns, move || measure(client, target, samples))
+                .await
+                .map_err(|e| {
+                    call_error(format!("Unable to enter network namespace {netns}. {e}"))
+                })?,
+            None => measure(client, target, samples).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// An HTTP proxy answering every request with 204, which counts the requests it received.
+    async fn http_proxy() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requests, received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    let mut buffer = [0; 4096];
+                    while let Ok(len) = stream.read(&mut buffer).await {
+                        if len == 0 {
+                            break;
+                        }
+                        let request = String::from_utf8_lossy(&buffer[..len]);
+                        let _ = requests.send(request.lines().next().unwrap_or("").to_owned());
+                        let response = "HTTP/1.1 204 No Content\r\n\r\n";
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (format!("http://{addr}"), received)
+    }
+
+    fn latency() -> Latency {
+        Latency::new(
+            serde_json::from_value(json!({
+                "targets": {"latency": "http://plugin.test/generate_204"},
+                "timeout_ms": 2000,
+                "samples": 2,
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn measures_requests_through_the_proxy() {
+        let (proxy, mut requests) = http_proxy().await;
+        let connection = ConnectionDescriptor {
+            http: Some(proxy),
+            ..Default::default()
+        };
+        let test = TestDescriptor {
+            name: "latency".to_owned(),
+        };
+        let result = latency().run_test(&test, &connection).await.unwrap();
+
+        assert_eq!(result["status"], 204);
+        assert!(result["min_ms"].as_f64().unwrap() <= result["ms"].as_f64().unwrap());
+        for _ in 0..2 {
+            let request = requests.recv().await.unwrap();
+            assert_eq!(request, "GET http://plugin.test/generate_204 HTTP/1.1");
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_unknown_tests_and_unreachable_proxies() {
+        let connection = ConnectionDescriptor {
+            http: Some("http://127.0.0.1:1".to_owned()),
+            ..Default::default()
+        };
+        let unknown = TestDescriptor {
+            name: "download".to_owned(),
+        };
+        assert!(latency().run_test(&unknown, &connection).await.is_err());
+        let test = TestDescriptor {
+            name: "latency".to_owned(),
+        };
+        assert!(latency().run_test(&test, &connection).await.is_err());
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::{timeout, timeout_at, Instant};
+use url::Url;
+
+use super::{call_error, Builtin};
+use crate::netns;
+use crate::plugin::{Capabilities, ConnectionDescriptor, Result, TestDescriptor};
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_packets() -> u32 {
+    20
+}
+
+fn default_interval_ms() -> u64 {
+    50
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct UdpRelayConfig {
+    /// Test name -> `host:port` of a UDP echo server the datagrams are sent to
+    targets: HashMap<String, String>,
+    /// How long replies are awaited after the last datagram, and the SOCKS5 handshake at most
+    #[serde(default = "default_timeout_ms")]
+    timeout_ms: u64,
+    /// Datagrams sent per test
+    #[serde(default = "default_packets")]
+    packets: u32,
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+}
+
+/// Sends timestamped datagrams to an echo server through SOCKS5 UDP ASSOCIATE, or straight
+/// through a TUN device, reporting whether UDP is relayed with its RTT, jitter, loss and
+/// reordering.
+pub struct UdpRelay {
+    config: UdpRelayConfig,
+}
+
+impl UdpRelay {
+    pub fn new(config: UdpRelayConfig) -> Self {
+        UdpRelay { config }
+    }
+}
+
+/// Sequence number and microseconds since the start of the test.
+const PROBE_LEN: usize = 12;
+
+/// Encodes `host:port` as a SOCKS5 address.
+fn socks_address(host: &str, port: u16) -> Result<Vec<u8>> {
+    let mut address = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => [&[1][..], &ip.octets()].concat(),
+        Ok(IpAddr::V6(ip)) => [&[4][..], &ip.octets()].concat(),
+        Err(_) => {
+            let length = u8::try_from(host.len())
+                .map_err(|_| call_error(format!("Host name {host} is too long")))?;
+            [&[3, length][..], host.as_bytes()].concat()
+        }
+    };
+    address.extend_from_slice(&port.to_be_bytes());
+    Ok(address)
+}
+
+/// Reads the SOCKS5 address following a reply.
+async fn read_socks_address(stream: &mut TcpStream) -> std::io::Result<Option<SocketAddr>> {
+    let ip = match stream.read_u8().await? {
+        1 => IpAddr::from(stream.read_u32().await?.to_be_bytes()),
+        4 => IpAddr::from(stream.read_u128().await?.to_be_bytes()),
+        3 => {
+            let mut name = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            stream.read_u16().await?;
+            return Ok(None);
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(SocketAddr::new(ip, stream.read_u16().await?)))
+}
+
+/// The length of the SOCKS5 UDP header starting `datagram`.
+fn header_len(datagram: &[u8]) -> Option<usize> {
+    let len = match datagram.get(3)? {
+        1 => 10,
+        4 => 22,
+        3 => 7 + *datagram.get(4)? as usize,
+        _ => return None,
+    };
+    (datagram.len() >= len).then_some(len)
+}
+
+/// Where the datagrams of a test go.
+struct Path {
+    socket: UdpSocket,
+    destination: SocketAddr,
+    /// SOCKS5 UDP header prepended to every datagram, when relayed
+    header: Option<Vec<u8>>,
+    /// The UDP association lasts as long as this connection
+    _control: Option<TcpStream>,
+}
+
+impl Path {
+    /// Asks the SOCKS5 proxy at `url` to relay datagrams to `host:port`. `Ok(None)` means the
+    /// proxy refused to.
+    async fn associate(url: &str, host: &str, port: u16) -> Result<Option<Path>> {
+        let error =
+            |e: std::io::Error| call_error(format!("SOCKS5 handshake with {url} failed. {e}"));
+        let url = Url::parse(url).map_err(|e| call_error(format!("Invalid proxy {url}. {e}")))?;
+        let proxy = url
+            .socket_addrs(|| Some(1080))
+            .map_err(|e| call_error(format!("Unable to resolve proxy {url}. {e}")))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| call_error(format!("Unable to resolve proxy {url}")))?;
+        let mut control = TcpStream::connect(proxy).await.map_err(error)?;
+
+        let credentials = !url.username().is_empty();
+        let methods: &[u8] = if credentials {
+            &[5, 2, 0, 2]
+        } else {
+            &[5, 1, 0]
+        };
+        control.write_all(methods).await.map_err(error)?;
+        let mut reply = [0; 2];
+        control.read_exact(&mut reply).await.map_err(error)?;
+        match reply {
+            [5, 0] => {}
+            [5, 2] if credentials => {
+                let decode = |s| percent_decode_str(s).collect::<Vec<_>>();
+                let (username, password) = (
+                    decode(url.username()),
+                    decode(url.password().unwrap_or_default()),
+                );
+                let length = |field: &str, value: &[u8]| {
+                    u8::try_from(value.len())
+                        .map_err(|_| call_error(format!("The SOCKS5 {field} is too long")))
+                };
+                let mut request = vec![1, length("username", &username)?];
+                request.extend(&username);
+                request.push(length("password", &password)?);
+                request.extend(&password);
+                control.write_all(&request).await.map_err(error)?;
+                control.read_exact(&mut reply).await.map_err(error)?;
+                if reply[1] != 0 {
+                    return Err(call_error(format!("{url} rejected the credentials")));
+                }
+            }
+            _ => {
+                return Err(call_error(format!(
+                    "{url} accepts none of the auth methods"
+                )))
+            }
+        }
+
+        // The client address is unknown before binding, which RFC 1928 allows as all zeros.
+        control
+            .write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .map_err(error)?;
+        control.read_exact(&mut reply).await.map_err(error)?;
+        if reply[1] != 0 {
+            log::debug!("{url} refused UDP ASSOCIATE with reply {}", reply[1]);
+            return Ok(None);
+        }
+        control.read_u8().await.map_err(error)?;
+        let relay = match read_socks_address(&mut control).await.map_err(error)? {
+            // A relay on an unspecified address listens wherever the proxy does.
+            Some(relay) if relay.ip().is_unspecified() => SocketAddr::new(proxy.ip(), relay.port()),
+            Some(relay) => relay,
+            None => return Err(call_error(format!("{url} replied with an unusable relay"))),
+        };
+        let socket = bind_for(relay).await?;
+        Ok(Some(Path {
+            socket,
+            destination: relay,
+            header: Some([&[0, 0, 0][..], &socks_address(host, port)?].concat()),
+            _control: Some(control),
+        }))
+    }
+
+    /// Sends datagrams to `host:port` directly, bound to `interface` when given.
+    async fn direct(host: &str, port: u16, interface: Option<&str>) -> Result<Path> {
+        let destination = lookup_host((host, port))
+            .await
+            .ok()
+            .and_then(|mut addresses| addresses.next())
+            .ok_or_else(|| call_error(format!("Unable to resolve {host}")))?;
+        let socket = bind_for(destination).await?;
+        if let Some(interface) = interface {
+            #[cfg(target_os = "linux")]
+            socket
+                .bind_device(Some(interface.as_bytes()))
+                .map_err(|e| call_error(format!("Unable to bind to {interface}. {e}")))?;
+            #[cfg(not(target_os = "linux"))]
+            return Err(call_error(format!(
+                "Binding to interface {interface} is only supported on Linux"
+            )));
+        }
+        Ok(Path {
+            socket,
+            destination,
+            header: None,
+            _control: None,
+        })
+    }
+
+    async fn send(&self, probe: &[u8]) -> std::io::Result<usize> {
+        match &self.header {
+            Some(header) => {
+                let datagram = [header.as_slice(), probe].concat();
+                self.socket.send_to(&datagram, self.destination).await
+            }
+            None => self.socket.send_to(probe, self.destination).await,
+        }
+    }
+
+    /// Receives the next probe echoed back.
+    async fn receive(&self) -> std::io::Result<Option<(u32, u64)>> {
+        let mut buffer = [0; 1500];
+        let (len, from) = self.socket.recv_from(&mut buffer).await?;
+        if from != self.destination {
+            return Ok(None);
+        }
+        let datagram = &buffer[..len];
+        let probe = match self.header {
+            Some(_) => header_len(datagram).map(|header| &datagram[header..]),
+            None => Some(datagram),
+        };
+        Ok(probe.filter(|p| p.len() >= PROBE_LEN).map(|p| {
+            let seq = u32::from_be_bytes(p[..4].try_into().unwrap());
+            let sent = u64::from_be_bytes(p[4..PROBE_LEN].try_into().unwrap());
+            (seq, sent)
+        }))
+    }
+}
+
+async fn bind_for(destination: SocketAddr) -> Result<UdpSocket> {
+    let local: SocketAddr = match destination {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    UdpSocket::bind(local)
+        .await
+        .map_err(|e| call_error(format!("Unable to bind a UDP socket. {e}")))
+}
+
+/// Sends the probes of a test through `proxy` to `target` and summarizes the echoes.
+async fn measure(
+    config: UdpRelayConfig,
+    target: String,
+    proxy: ConnectionDescriptor,
+) -> Result<Value> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| call_error(format!("Invalid target {target}, expected host:port")))?;
+    let deadline = Duration::from_millis(config.timeout_ms);
+    let path = match &proxy.socks5 {
+        Some(url) => timeout(deadline, Path::associate(url, host, port))
+            .await
+            .map_err(|_| call_error(format!("SOCKS5 handshake with {url} timed out")))??,
+        // A TUN proxy is reached without any proxy setting.
+        None if proxy.uses_tun() => {
+            Some(Path::direct(host, port, proxy.interface.as_deref()).await?)
+        }
+        None => {
+            return Err(call_error(
+                "The proxy has no SOCKS5 endpoint to relay UDP through",
+            ))
+        }
+    };
+    let Some(path) = path else {
+        return Ok(json!({ "udp": false, "sent": 0, "received": 0, "loss": 1.0 }));
+    };
+
+    let started = Instant::now();
+    let interval = Duration::from_millis(config.interval_ms);
+    let last_sent = started + interval * config.packets.saturating_sub(1);
+    let send = async {
+        for seq in 0..config.packets {
+            tokio::time::sleep_until(started + interval * seq).await;
+            let sent = started.elapsed().as_micros() as u64;
+            let probe = [seq.to_be_bytes().as_slice(), &sent.to_be_bytes()].concat();
+            path.send(&probe).await?;
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let receive = async {
+        let mut seen = HashSet::new();
+        // RTTs in the order the echoes arrived, and how many arrived after a later probe
+        let (mut rtts, mut reordered, mut highest) = (vec![], 0, None);
+        while seen.len() < config.packets as usize {
+            let Ok(echo) = timeout_at(last_sent + deadline, path.receive()).await else {
+                break;
+            };
+            let Some((seq, sent)) = echo? else {
+                continue;
+            };
+            // A mangled echo may claim to be sent in the future.
+            let Some(rtt) = (started.elapsed().as_micros() as u64).checked_sub(sent) else {
+                continue;
+            };
+            if seq >= config.packets || !seen.insert(seq) {
+                continue;
+            }
+            rtts.push(rtt as f64 / 1000.0);
+            if highest.is_some_and(|highest| seq < highest) {
+                reordered += 1;
+            }
+            highest = highest.max(Some(seq));
+        }
+        Ok::<_, std::io::Error>((rtts, reordered))
+    };
+    let (sent, received) = tokio::join!(send, receive);
+    sent.map_err(|e| call_error(format!("Unable to send to {target}. {e}")))?;
+    let (rtts, reordered) =
+        received.map_err(|e| call_error(format!("Unable to receive from {target}. {e}")))?;
+
+    let mean = |values: &[f64]| {
+        (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+    };
+    let jitter: Vec<_> = rtts.windows(2).map(|w| (w[1] - w[0]).abs()).collect();
+    Ok(json!({
+        "udp": !rtts.is_empty(),
+        "sent": config.packets,
+        "received": rtts.len(),
+        "loss": 1.0 - rtts.len() as f64 / config.packets.max(1) as f64,
+        "rtt_ms": mean(&rtts),
+        "min_rtt_ms": rtts.iter().copied().reduce(f64::min),
+        "jitter_ms": mean(&jitter),
+        "reordered": reordered,
+    }))
+}
+
+#[async_trait]
+impl Builtin for UdpRelay {
+    fn name(&self) -> &'static str {
+        "udp-relay"
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            test_provider: true,
+            supports_tun: true,
+            ..Default::default()
+        }
+    }
+
+    async fn tests(&self) -> Result<Vec<TestDescriptor>> {
+        Ok(self
+            .config
+            .targets
+            .keys()
+            .map(|name| TestDescriptor { name: name.clone() })
+            .collect())
+    }
+
+    async fn run_test(&self, test: &TestDescriptor, proxy: &ConnectionDescriptor) -> Result<Value> {
+        let target = self
+            .config
+            .targets
+            .get(&test.name)
+            .ok_or_else(|| call_error(format!("Unknown test {}", test.name)))?
+            .clone();
+        let (config, proxy) = (self.config.clone(), proxy.clone());
+        match proxy.netns.clone() {
+            Some(netns) => netns::run_in(&netns, move || measure(config, target, proxy))
+                .await
+                .map_err(|e| {
+                    call_error(format!("Unable to enter network namespace {netns}. {e}"))
+                })?,
+            None => measure(config, target, proxy).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Echoes datagrams, with the timestamp of the probe overwritten if `mangle`.
+    async fn echo_server(mangle: bool) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0; 1500];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer).await {
+                if mangle {
+                    buffer[4..PROBE_LEN].fill(0xff);
+                }
+                let _ = socket.send_to(&buffer[..len], from).await;
+            }
+        });
+        addr
+    }
+
+    /// A SOCKS5 proxy relaying UDP, or refusing to with `refusal`.
+    async fn socks5_server(refusal: Option<u8>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut control, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            control.read_exact(&mut greeting).await.unwrap();
+            control.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0; 10];
+            control.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[1], 3, "UDP ASSOCIATE");
+            if let Some(refusal) = refusal {
+                control
+                    .write_all(&[5, refusal, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+                return;
+            }
+            let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let port = relay.local_addr().unwrap().port().to_be_bytes();
+            control
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, port[0], port[1]])
+                .await
+                .unwrap();
+            let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut buffer = [0; 1500];
+            let (len, client) = relay.recv_from(&mut buffer).await.unwrap();
+            let mut datagram = buffer[..len].to_vec();
+            let header = datagram[..10].to_vec();
+            let ip = IpAddr::from(<[u8; 4]>::try_from(&header[4..8]).unwrap());
+            let destination = SocketAddr::new(ip, u16::from_be_bytes([header[8], header[9]]));
+            loop {
+                upstream
+                    .send_to(&datagram[10..], destination)
+                    .await
+                    .unwrap();
+                let len = upstream.recv(&mut buffer).await.unwrap();
+                relay
+                    .send_to(&[&header, &buffer[..len]].concat(), client)
+                    .await
+                    .unwrap();
+                let len = relay.recv(&mut buffer).await.unwrap();
+                datagram = buffer[..len].to_vec();
+            }
+        });
+        addr
+    }
+
+    fn relay(echo: SocketAddr) -> UdpRelay {
+        UdpRelay::new(UdpRelayConfig {
+            targets: HashMap::from([("udp".to_owned(), echo.to_string())]),
+            timeout_ms: 500,
+            packets: 5,
+            interval_ms: 1,
+        })
+    }
+
+    fn through(proxy: SocketAddr) -> ConnectionDescriptor {
+        ConnectionDescriptor {
+            socks5: Some(format!("socks5://{proxy}")),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_datagrams_through_udp_associate() {
+        let echo = echo_server(false).await;
+        let proxy = socks5_server(None).await;
+        let test = TestDescriptor {
+            name: "udp".to_owned(),
+        };
+        let result = relay(echo).run_test(&test, &through(proxy)).await.unwrap();
+
+        assert_eq!(result["udp"], true);
+        assert_eq!(result["received"], 5);
+        assert_eq!(result["loss"], 0.0);
+        assert_eq!(result["reordered"], 0);
+        assert!(result["rtt_ms"].as_f64().unwrap() >= result["min_rtt_ms"].as_f64().unwrap());
+    }
+
+    #[tokio::test]
+    async fn reports_proxies_refusing_udp() {
+        let echo = echo_server(false).await;
+        // 7: command not supported
+        let proxy = socks5_server(Some(7)).await;
+        let test = TestDescriptor {
+            name: "udp".to_owned(),
+        };
+        let result = relay(echo).run_test(&test, &through(proxy)).await.unwrap();
+
+        assert_eq!(result["udp"], false);
+        assert_eq!(result["loss"], 1.0);
+    }
+
+    #[tokio::test]
+    async fn ignores_echoes_with_a_mangled_timestamp() {
+        let echo = echo_server(true).await;
+        let proxy = socks5_server(None).await;
+        let test = TestDescriptor {
+            name: "udp".to_owned(),
+        };
+        let result = relay(echo).run_test(&test, &through(proxy)).await.unwrap();
+
+        assert_eq!(result["udp"], false);
+        assert_eq!(result["received"], 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_credentials_socks5_cannot_carry() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut control, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 4];
+            control.read_exact(&mut greeting).await.unwrap();
+            control.write_all(&[5, 2]).await.unwrap();
+            let _ = control.read(&mut greeting).await;
+        });
+        let test = TestDescriptor {
+            name: "udp".to_owned(),
+        };
+        let proxy = ConnectionDescriptor {
+            socks5: Some(format!("socks5://{}:pass@{proxy}", "u".repeat(256))),
+            ..Default::default()
+        };
+        let error = relay(echo_server(false).await)
+            .run_test(&test, &proxy)
+            .await
+            .unwrap_err();
+
+        assert!(
+            error.to_string().contains("username is too long"),
+            "{error}"
+        );
+    }
+}
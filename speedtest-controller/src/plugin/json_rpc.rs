@@ -1,40 +1,71 @@
+mod stream;
+mod tls;
+
+use std::sync::Arc;
+use std::time::Instant;
+
 use jsonrpsee::async_client::ClientBuilder;
-use jsonrpsee::client_transport::ws::{Url, WsTransportClientBuilder};
+use jsonrpsee::client_transport::ws::{HeaderMap, HeaderValue, Url, WsTransportClientBuilder};
+use jsonrpsee::core::params::ArrayParams;
+use jsonrpsee::core::traits::ToRpcParams;
+use jsonrpsee::core::ClientError;
 use jsonrpsee::rpc_params;
 use jsonrpsee::{async_client::Client, core::client::ClientT};
 use serde_json::Value;
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use url::Position;
 
+use super::record::Recorder;
 use super::{ConnectionDescriptor, Plugin, Result, TestDescriptor};
+use crate::process::{PluginProcess, TERMINATE_GRACE};
+use crate::server::authorization;
+
+pub use tls::TlsConfig;
+
 pub struct JSONRPCPlugin {
     client: Client,
     config: Value,
+    recorder: Option<Arc<Recorder>>,
+    /// The process serving `client`, when the controller launched it
+    process: Option<Mutex<PluginProcess>>,
 }
 
 #[async_trait::async_trait]
 impl Plugin for JSONRPCPlugin {
     async fn init(&self) -> Result<()> {
-        let result = self
-            .client
-            .request("init", rpc_params![&self.config])
-            .await?;
+        let result = self.call("init", rpc_params![&self.config]).await?;
         Ok(serde_json::from_value(result)?)
     }
 
     async fn setup_proxy(&self, proxy: serde_json::Value) -> Result<ConnectionDescriptor> {
+        let result = self.call("setup_proxy", rpc_params![proxy]).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn teardown_proxy(&self, proxy: &ConnectionDescriptor) -> Result<()> {
+        let _: Value = self.call("teardown_proxy", rpc_params![proxy]).await?;
+        Ok(())
+    }
+
+    async fn setup_proxy_in_netns(
+        &self,
+        proxy: serde_json::Value,
+        netns: &str,
+    ) -> Result<ConnectionDescriptor> {
         let result = self
-            .client
-            .request("setup_proxy", rpc_params![proxy])
+            .call("setup_proxy_in_netns", rpc_params![proxy, netns])
             .await?;
         Ok(serde_json::from_value(result)?)
     }
 
     async fn metadata(&self) -> Result<super::PluginMetaData> {
-        let result = self.client.request("metadata", rpc_params![]).await?;
+        let result = self.call("metadata", rpc_params![]).await?;
         Ok(serde_json::from_value(result)?)
     }
 
     async fn tests(&self) -> Result<Vec<super::TestDescriptor>> {
-        let result = self.client.request("tests", rpc_params![]).await?;
+        let result = self.call("tests", rpc_params![]).await?;
         Ok(serde_json::from_value(result)?)
     }
 
@@ -44,17 +75,34 @@ impl Plugin for JSONRPCPlugin {
         proxy: &ConnectionDescriptor,
     ) -> Result<serde_json::Value> {
         let result = self
-            .client
-            .request("run_test", rpc_params![&test.name, proxy])
+            .call("run_test", rpc_params![&test.name, proxy])
             .await?;
         Ok(result)
     }
 
     async fn data_transforms(&self) -> Result<Vec<super::DataTransformDescriptor>> {
+        let result = self.call("data_transforms", rpc_params![]).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn transform(
+        &self,
+        transform: &super::DataTransformDescriptor,
+        content: Value,
+    ) -> Result<Value> {
         let result = self
-            .client
-            .request("data_transforms", rpc_params![])
+            .call("transform", rpc_params![&transform.name, content])
             .await?;
+        Ok(result)
+    }
+
+    async fn proxy_schemes(&self) -> Result<Vec<String>> {
+        let result = self.call("proxy_schemes", rpc_params![]).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn proxy_protocols(&self) -> Result<Vec<String>> {
+        let result = self.call("proxy_protocols", rpc_params![]).await?;
         Ok(serde_json::from_value(result)?)
     }
 
@@ -63,20 +111,134 @@ impl Plugin for JSONRPCPlugin {
         connection_string: &str,
     ) -> Result<Vec<super::ProtocolDescriptor>> {
         let result = self
-            .client
-            .request("parse_protocol", rpc_params![connection_string])
+            .call("parse_protocol", rpc_params![connection_string])
             .await?;
         Ok(serde_json::from_value(result)?)
     }
+
+    async fn shutdown(&self) -> Result<()> {
+        // Plugins predating `shutdown` are just terminated.
+        let result = match self.call("shutdown", rpc_params![]).await {
+            Err(e) if e.is_method_not_found() => Ok(()),
+            result => result.map(|_| ()),
+        };
+        if let Some(process) = &self.process {
+            process.lock().await.terminate(TERMINATE_GRACE).await;
+        }
+        result
+    }
+
+    async fn process_id(&self) -> Option<u32> {
+        self.process.as_ref()?.lock().await.id()
+    }
+}
+
+fn transport_error(e: impl std::error::Error + Send + Sync + 'static) -> ClientError {
+    ClientError::Transport(e.into())
 }
 
 impl JSONRPCPlugin {
     pub async fn new(endpoint: &str, config: Value) -> Result<Self> {
-        let uri = Url::parse(&format!("ws://{}", endpoint))?;
+        Self::connect(endpoint, config, None).await
+    }
 
-        let (tx, rx) = WsTransportClientBuilder::default().build(uri).await?;
-        let client: Client = ClientBuilder::default().build_with_tokio(tx, rx);
-        Ok(JSONRPCPlugin { client, config })
+    /// Connects to `host:port`, or to `unix:/path/to/socket`, presenting `token` if any.
+    pub async fn connect(endpoint: &str, config: Value, token: Option<&str>) -> Result<Self> {
+        let Some(path) = endpoint.strip_prefix("unix:") else {
+            let url = Url::parse(&format!("ws://{}", endpoint))?;
+            return Self::connect_url(&url, config, token, &TlsConfig::default()).await;
+        };
+        let authorization = token.map(authorization);
+        let socket = UnixStream::connect(path).await.map_err(transport_error)?;
+        let (tx, rx) = stream::connect(socket, "localhost", "/", authorization.as_deref())
+            .await
+            .map_err(transport_error)?;
+        Ok(Self::with_client(
+            ClientBuilder::default().build_with_tokio(tx, rx),
+            config,
+        ))
+    }
+
+    /// Connects to a `ws://` or `wss://` URL, presenting `token` if any.
+    pub async fn connect_url(
+        url: &Url,
+        config: Value,
+        token: Option<&str>,
+        tls: &TlsConfig,
+    ) -> Result<Self> {
+        let authorization = token.map(authorization);
+        let client = if url.scheme() == "wss" {
+            // Connects by hand, `WsTransportClientBuilder` does not take custom CAs or client
+            // certificates.
+            let host = url.host_str().unwrap_or_default().trim_matches(['[', ']']);
+            let port = url.port_or_known_default().unwrap_or(443);
+            let socket = tls::connect(host, port, tls)
+                .await
+                .map_err(transport_error)?;
+            let (tx, rx) = stream::connect(
+                socket,
+                &url[Position::BeforeHost..Position::AfterPort],
+                &url[Position::BeforePath..Position::AfterQuery],
+                authorization.as_deref(),
+            )
+            .await
+            .map_err(transport_error)?;
+            ClientBuilder::default().build_with_tokio(tx, rx)
+        } else {
+            let mut headers = HeaderMap::new();
+            if let Some(authorization) = &authorization {
+                let value = HeaderValue::from_str(authorization).map_err(transport_error)?;
+                headers.insert("authorization", value);
+            }
+            let (tx, rx) = WsTransportClientBuilder::default()
+                .set_headers(headers)
+                .build(url.clone())
+                .await?;
+            ClientBuilder::default().build_with_tokio(tx, rx)
+        };
+        Ok(Self::with_client(client, config))
+    }
+
+    fn with_client(client: Client, config: Value) -> Self {
+        JSONRPCPlugin {
+            client,
+            config,
+            recorder: None,
+            process: None,
+        }
+    }
+
+    /// Records every request and response with `recorder`.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(Arc::new(recorder));
+        self
+    }
+
+    /// Owns `process`, which is terminated on `shutdown` and killed on drop.
+    pub fn with_process(mut self, process: PluginProcess) -> Self {
+        self.process = Some(Mutex::new(process));
+        self
+    }
+
+    async fn call(&self, method: &str, params: ArrayParams) -> Result<Value> {
+        let Some(recorder) = &self.recorder else {
+            return Ok(self.client.request(method, params).await?);
+        };
+        let recorded_params = params
+            .clone()
+            .to_rpc_params()
+            .ok()
+            .flatten()
+            .and_then(|raw| serde_json::from_str(raw.get()).ok())
+            .unwrap_or(Value::Null);
+        let started = Instant::now();
+        let result = self
+            .client
+            .request(method, params)
+            .await
+            .map_err(Into::into);
+        recorder.record(method, recorded_params, started, result.as_ref());
+        result
     }
 }
 
@@ -84,11 +246,12 @@ impl JSONRPCPlugin {
 mod tests {
     use std::net::SocketAddr;
 
-    use crate::plugin::PluginMetaData;
+    use crate::plugin::{Capabilities, PluginMetaData, PROTOCOL_VERSION};
     use jsonrpsee::{
         server::{RpcModule, Server},
         types::ErrorObject,
     };
+    use rustls::pki_types::PrivateKeyDer;
 
     use super::*;
 
@@ -97,6 +260,12 @@ mod tests {
         let mut module = RpcModule::new(());
         module.register_method("metadata", |_, _| PluginMetaData {
             name: "foo".to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            version: None,
+            capabilities: Capabilities {
+                proxy_backend: true,
+                ..Default::default()
+            },
         })?;
         module.register_method(
             "setup_proxy",
@@ -107,6 +276,7 @@ mod tests {
                     http: Some("http://127.0.0.1:1234".to_owned()),
                     socks5: Some("socks5://127.0.0.1:2345".to_owned()),
                     tun: false,
+                    ..Default::default()
                 })
             },
         )?;
@@ -123,19 +293,82 @@ mod tests {
         Ok(addr)
     }
 
+    #[tokio::test]
+    async fn connects_over_a_unix_socket() {
+        let addr = create_rpc_service().await.unwrap();
+        let path = std::env::temp_dir().join(format!("plugin-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // Forwards to the TCP server, which speaks the same websocket protocol.
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (mut unix, _) = listener.accept().await.unwrap();
+            let mut tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut unix, &mut tcp).await;
+        });
+        let plugin = JSONRPCPlugin::new(&format!("unix:{}", path.display()), Value::Null)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(plugin.metadata().await.unwrap().name, "foo");
+    }
+
+    #[tokio::test]
+    async fn connects_over_tls() {
+        let addr = create_rpc_service().await.unwrap();
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(["localhost".to_owned()]).unwrap();
+        let ca = std::env::temp_dir().join(format!("plugin-ca-{}.pem", std::process::id()));
+        std::fs::write(&ca, cert.pem()).unwrap();
+        let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![cert.der().clone()],
+            PrivateKeyDer::Pkcs8(key_pair.serialize_der().into()),
+        )
+        .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        // Terminates TLS in front of the plain server, like a reverse proxy on a measurement box.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut tls = acceptor.accept(tcp).await.unwrap();
+            let mut plain = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut tls, &mut plain).await;
+        });
+        let url = Url::parse(&format!("wss://localhost:{}", port)).unwrap();
+        let tls = TlsConfig {
+            ca: Some(ca.clone()),
+            ..Default::default()
+        };
+        let plugin = JSONRPCPlugin::connect_url(&url, Value::Null, None, &tls)
+            .await
+            .unwrap();
+        std::fs::remove_file(&ca).unwrap();
+        assert_eq!(plugin.metadata().await.unwrap().name, "foo");
+    }
+
     #[tokio::test]
     async fn it_works() {
         let addr = create_rpc_service().await.unwrap();
         let plugin = JSONRPCPlugin::new(&format!("{}", addr), Value::Null)
             .await
             .unwrap();
-        assert_eq!(plugin.metadata().await.unwrap().name, "foo");
+        let metadata = plugin.metadata().await.unwrap();
+        assert_eq!(metadata.name, "foo");
+        assert!(metadata.is_compatible());
+        assert!(metadata.capabilities.proxy_backend && !metadata.capabilities.parser);
         plugin
             .setup_proxy(
                 serde_json::to_value(ConnectionDescriptor {
                     http: Some("http://127.0.0.1:1234".to_owned()),
                     socks5: Some("socks5://127.0.0.1:2345".to_owned()),
                     tun: false,
+                    ..Default::default()
                 })
                 .unwrap(),
             )
@@ -144,11 +377,10 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "needs a plugin listening on 127.0.0.1:54040"]
     async fn it_works_on_gost() {
         let addr = "127.0.0.1:54040";
-        let plugin = JSONRPCPlugin::new(&format!("{}", addr), Value::Null)
-            .await
-            .unwrap();
+        let plugin = JSONRPCPlugin::new(addr, Value::Null).await.unwrap();
         println!("{}", plugin.metadata().await.unwrap().name);
         println!(
             "{:?}",
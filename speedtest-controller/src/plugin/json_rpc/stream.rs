@@ -0,0 +1,87 @@
+//! The websocket transport of `JSONRPCPlugin` over any stream, such as a unix domain socket or
+//! a TLS connection.
+use jsonrpsee::core::client::{ReceivedMessage, TransportReceiverT, TransportSenderT};
+use soketto::handshake::client::Header;
+use soketto::handshake::{Client, ServerResponse};
+use soketto::{Data, Incoming};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+#[derive(Error, Debug)]
+pub enum StreamTransportError {
+    #[error("Unable to connect to the plugin")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to perform the ws handshake")]
+    Handshake(#[from] soketto::handshake::Error),
+    #[error("The plugin rejected the ws handshake with status {0}")]
+    Rejected(u16),
+    #[error("ws connection error")]
+    Connection(#[from] soketto::connection::Error),
+    #[error("The plugin sent text that is not UTF-8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("The plugin closed the connection")]
+    Closed,
+}
+
+pub struct Sender<S>(soketto::Sender<Compat<S>>);
+
+pub struct Receiver<S>(soketto::Receiver<Compat<S>>);
+
+/// Performs the websocket handshake for `resource` of `host` on `stream`, presenting
+/// `authorization` if any.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    host: &str,
+    resource: &str,
+    authorization: Option<&str>,
+) -> Result<(Sender<S>, Receiver<S>), StreamTransportError> {
+    let mut client = Client::new(stream.compat(), host, resource);
+    let headers: Vec<_> = authorization
+        .map(|value| Header {
+            name: "Authorization",
+            value: value.as_bytes(),
+        })
+        .into_iter()
+        .collect();
+    client.set_headers(&headers);
+    match client.handshake().await? {
+        ServerResponse::Accepted { .. } => {}
+        ServerResponse::Redirect { status_code, .. } | ServerResponse::Rejected { status_code } => {
+            return Err(StreamTransportError::Rejected(status_code))
+        }
+    }
+    let (sender, receiver) = client.into_builder().finish();
+    Ok((Sender(sender), Receiver(receiver)))
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> TransportSenderT for Sender<S> {
+    type Error = StreamTransportError;
+
+    async fn send(&mut self, msg: String) -> Result<(), Self::Error> {
+        self.0.send_text_owned(msg).await?;
+        self.0.flush().await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), Self::Error> {
+        self.0.close().await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> TransportReceiverT for Receiver<S> {
+    type Error = StreamTransportError;
+
+    async fn receive(&mut self) -> Result<ReceivedMessage, Self::Error> {
+        let mut message = Vec::new();
+        match self.0.receive(&mut message).await? {
+            Incoming::Data(Data::Text(_)) => Ok(ReceivedMessage::Text(String::from_utf8(message)?)),
+            Incoming::Data(Data::Binary(_)) => Ok(ReceivedMessage::Bytes(message)),
+            Incoming::Pong(_) => Ok(ReceivedMessage::Pong),
+            Incoming::Closed(_) => Err(StreamTransportError::Closed),
+        }
+    }
+}
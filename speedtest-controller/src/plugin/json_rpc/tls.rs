@@ -0,0 +1,80 @@
+//! TLS connections to plugins running on another machine, behind `wss://` sources.
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Unable to connect to the plugin")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to read the PEM file")]
+    Pem(#[from] pem::Error),
+    #[error("Invalid TLS config")]
+    Rustls(#[from] rustls::Error),
+    #[error("`cert` and `key` must be given together")]
+    IncompleteClientAuth,
+    #[error("Invalid server name `{0}`")]
+    InvalidServerName(String),
+}
+
+/// How the controller verifies a plugin, and authenticates to it.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file of CAs trusted besides the public ones, e.g. for a self-signed plugin
+    pub ca: Option<PathBuf>,
+    /// PEM file of the client certificate, for plugins requiring mutual TLS
+    pub cert: Option<PathBuf>,
+    /// PEM file of the private key of `cert`
+    pub key: Option<PathBuf>,
+    /// Verifies the certificate of the plugin for this name instead of the host of the URL
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    fn client_config(&self) -> Result<ClientConfig, TlsError> {
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if let Some(ca) = &self.ca {
+            for cert in CertificateDer::pem_file_iter(ca)? {
+                roots.add(cert?)?;
+            }
+        }
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots);
+        let config = match (&self.cert, &self.key) {
+            (None, None) => builder.with_no_client_auth(),
+            (Some(cert), Some(key)) => {
+                let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<_, _>>()?;
+                builder.with_client_auth_cert(certs, PrivateKeyDer::from_pem_file(key)?)?
+            }
+            _ => return Err(TlsError::IncompleteClientAuth),
+        };
+        Ok(config)
+    }
+}
+
+/// Opens a TLS connection to `host:port`.
+pub async fn connect(
+    host: &str,
+    port: u16,
+    config: &TlsConfig,
+) -> Result<TlsStream<TcpStream>, TlsError> {
+    let connector = TlsConnector::from(Arc::new(config.client_config()?));
+    let name = config.server_name.as_deref().unwrap_or(host).to_owned();
+    let server_name =
+        ServerName::try_from(name.clone()).map_err(|_| TlsError::InvalidServerName(name))?;
+    let stream = TcpStream::connect((host, port)).await?;
+    Ok(connector.connect(server_name, stream).await?)
+}
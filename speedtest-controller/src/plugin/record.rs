@@ -0,0 +1,233 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Instant;
+
+use jsonrpsee::types::error::ErrorCode;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::{
+    ConnectionDescriptor, DataTransformDescriptor, Plugin, PluginError, PluginErrorKind,
+    PluginMetaData, ProtocolDescriptor, Result, TestDescriptor,
+};
+
+/// Stands in a recording for the namespace a proxy was set up in, which differs every run.
+pub const NETNS_PLACEHOLDER: &str = "${NETNS}";
+
+/// The namespaces the controller creates, which are held open as a file of its own process.
+static NETNS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^/proc/\d+/(fd/\d+|ns/net)$").unwrap());
+
+/// Replaces every namespace path in `value` with `NETNS_PLACEHOLDER`.
+pub fn normalize(value: Value) -> Value {
+    match value {
+        Value::String(s) if NETNS.is_match(&s) => Value::String(NETNS_PLACEHOLDER.to_owned()),
+        Value::Array(values) => Value::Array(values.into_iter().map(normalize).collect()),
+        Value::Object(map) => {
+            Value::Object(map.into_iter().map(|(k, v)| (k, normalize(v))).collect())
+        }
+        value => value,
+    }
+}
+
+/// A failed call, as much of it as can be replayed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecordedError {
+    pub kind: PluginErrorKind,
+    /// The code of the error object, for `Call` errors
+    #[serde(default)]
+    pub code: Option<i32>,
+    pub message: String,
+}
+
+/// One request to a plugin and its response, a line of a recording.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecordedCall {
+    pub method: String,
+    pub params: Value,
+    /// How long the plugin took to respond
+    pub elapsed_ms: u128,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RecordedError>,
+}
+
+impl From<&PluginError> for RecordedError {
+    fn from(error: &PluginError) -> Self {
+        let code = match error {
+            PluginError::ClientError(jsonrpsee::core::ClientError::Call(e)) => Some(e.code()),
+            PluginError::Unsupported => Some(ErrorCode::MethodNotFound.code()),
+            _ => None,
+        };
+        RecordedError {
+            kind: error.kind(),
+            code,
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Writes the traffic of a plugin to a file, one JSON object per line.
+pub struct Recorder {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Recorder {
+            writer: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub fn record(
+        &self,
+        method: &str,
+        params: Value,
+        sent: Instant,
+        result: std::result::Result<&Value, &PluginError>,
+    ) {
+        let call = RecordedCall {
+            method: method.to_owned(),
+            params: normalize(params),
+            elapsed_ms: sent.elapsed().as_millis(),
+            result: result.ok().cloned().map(normalize),
+            error: result.err().map(Into::into),
+        };
+        let mut writer = self.writer.lock().unwrap();
+        // Every line is flushed, so that a recording survives the controller crashing.
+        let written = serde_json::to_writer(&mut *writer, &call)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writer.write_all(b"\n"))
+            .and_then(|_| writer.flush());
+        if let Err(e) = written {
+            log::error!("Unable to record a call to {method}. {e}");
+        }
+    }
+}
+
+/// Records the calls to a plugin that is not reached over JSON-RPC, as the requests a JSON-RPC
+/// plugin would have been sent, so that `ReplayPlugin` replays them alike.
+pub struct RecordingPlugin {
+    plugin: Arc<dyn Plugin>,
+    /// Sent to `init`
+    config: Value,
+    recorder: Recorder,
+}
+
+impl RecordingPlugin {
+    pub fn new(plugin: Arc<dyn Plugin>, config: Value, recorder: Recorder) -> Self {
+        RecordingPlugin {
+            plugin,
+            config,
+            recorder,
+        }
+    }
+
+    async fn record<T: Serialize>(
+        &self,
+        method: &str,
+        params: Value,
+        call: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let started = Instant::now();
+        let result = call.await;
+        match &result {
+            Ok(value) => {
+                let value = serde_json::to_value(value).unwrap_or(Value::Null);
+                self.recorder.record(method, params, started, Ok(&value));
+            }
+            Err(e) => self.recorder.record(method, params, started, Err(e)),
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for RecordingPlugin {
+    async fn setup_proxy(&self, proxy: Value) -> Result<ConnectionDescriptor> {
+        let params = json!([proxy]);
+        self.record("setup_proxy", params, self.plugin.setup_proxy(proxy))
+            .await
+    }
+
+    async fn teardown_proxy(&self, proxy: &ConnectionDescriptor) -> Result<()> {
+        let params = json!([proxy]);
+        self.record("teardown_proxy", params, self.plugin.teardown_proxy(proxy))
+            .await
+    }
+
+    async fn setup_proxy_in_netns(
+        &self,
+        proxy: Value,
+        netns: &str,
+    ) -> Result<ConnectionDescriptor> {
+        let params = json!([proxy, netns]);
+        let call = self.plugin.setup_proxy_in_netns(proxy, netns);
+        self.record("setup_proxy_in_netns", params, call).await
+    }
+
+    async fn init(&self) -> Result<()> {
+        let params = json!([self.config]);
+        self.record("init", params, self.plugin.init()).await
+    }
+
+    async fn metadata(&self) -> Result<PluginMetaData> {
+        self.record("metadata", json!([]), self.plugin.metadata())
+            .await
+    }
+
+    async fn tests(&self) -> Result<Vec<TestDescriptor>> {
+        self.record("tests", json!([]), self.plugin.tests()).await
+    }
+
+    async fn run_test(&self, test: &TestDescriptor, proxy: &ConnectionDescriptor) -> Result<Value> {
+        let params = json!([test.name, proxy]);
+        self.record("run_test", params, self.plugin.run_test(test, proxy))
+            .await
+    }
+
+    async fn data_transforms(&self) -> Result<Vec<DataTransformDescriptor>> {
+        self.record("data_transforms", json!([]), self.plugin.data_transforms())
+            .await
+    }
+
+    async fn transform(
+        &self,
+        transform: &DataTransformDescriptor,
+        content: Value,
+    ) -> Result<Value> {
+        let params = json!([transform.name, content]);
+        let call = self.plugin.transform(transform, content);
+        self.record("transform", params, call).await
+    }
+
+    async fn proxy_schemes(&self) -> Result<Vec<String>> {
+        self.record("proxy_schemes", json!([]), self.plugin.proxy_schemes())
+            .await
+    }
+
+    async fn proxy_protocols(&self) -> Result<Vec<String>> {
+        self.record("proxy_protocols", json!([]), self.plugin.proxy_protocols())
+            .await
+    }
+
+    async fn parse_protocol(&self, connection_string: &str) -> Result<Vec<ProtocolDescriptor>> {
+        let params = json!([connection_string]);
+        let call = self.plugin.parse_protocol(connection_string);
+        self.record("parse_protocol", params, call).await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.record("shutdown", json!([]), self.plugin.shutdown())
+            .await
+    }
+
+    async fn process_id(&self) -> Option<u32> {
+        self.plugin.process_id().await
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use jsonrpsee::types::error::ErrorCode;
+use jsonrpsee::types::ErrorObject;
+use serde_json::{json, Value};
+
+use super::record::{normalize, RecordedCall, RecordedError, NETNS_PLACEHOLDER};
+use super::{
+    ConnectionDescriptor, DataTransformDescriptor, Plugin, PluginError, PluginErrorKind,
+    PluginMetaData, ProtocolDescriptor, Result, TestDescriptor,
+};
+
+/// Serves a recording made with `Recorder` back, without the plugin that was recorded.
+///
+/// A call gets the responses recorded for the same method and params, in their recorded order.
+/// Once only one is left it is repeated for every further call. Namespace paths are matched
+/// regardless of the run they were recorded in.
+pub struct ReplayPlugin {
+    config: Value,
+    /// Waits as long as the plugin took to respond
+    realtime: bool,
+    /// (method, params) -> recorded responses
+    calls: HashMap<(String, String), Mutex<VecDeque<RecordedCall>>>,
+}
+
+impl From<&RecordedError> for PluginError {
+    fn from(error: &RecordedError) -> Self {
+        use jsonrpsee::core::ClientError;
+        PluginError::ClientError(match error.kind {
+            PluginErrorKind::Timeout => ClientError::RequestTimeout,
+            PluginErrorKind::Transport => {
+                ClientError::Transport(anyhow::anyhow!(error.message.clone()))
+            }
+            // Only the message survives of the other kinds.
+            _ => ClientError::Call(ErrorObject::owned(
+                error.code.unwrap_or(ErrorCode::InternalError.code()),
+                error.message.clone(),
+                None::<()>,
+            )),
+        })
+    }
+}
+
+impl ReplayPlugin {
+    /// Loads a recording, replaying `init` for the same `config` it was recorded with.
+    pub fn load(path: &Path, config: Value, realtime: bool) -> std::io::Result<Self> {
+        let mut calls: HashMap<_, VecDeque<_>> = HashMap::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let call: RecordedCall = serde_json::from_str(&line)?;
+            calls
+                .entry((call.method.clone(), call.params.to_string()))
+                .or_default()
+                .push_back(call);
+        }
+        Ok(ReplayPlugin {
+            config,
+            realtime,
+            calls: calls
+                .into_iter()
+                .map(|(key, calls)| (key, Mutex::new(calls)))
+                .collect(),
+        })
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let params = normalize(params);
+        let call = self
+            .calls
+            .get(&(method.to_owned(), params.to_string()))
+            .and_then(|calls| {
+                let mut calls = calls.lock().unwrap();
+                if calls.len() > 1 {
+                    calls.pop_front()
+                } else {
+                    calls.front().cloned()
+                }
+            });
+        let Some(call) = call else {
+            return Err(PluginError::from(&RecordedError {
+                kind: PluginErrorKind::Call,
+                code: Some(ErrorCode::MethodNotFound.code()),
+                message: format!("No response to {method} {params} was recorded"),
+            }));
+        };
+        if self.realtime {
+            tokio::time::sleep(Duration::from_millis(call.elapsed_ms as u64)).await;
+        }
+        match (&call.error, call.result) {
+            (Some(error), _) => Err(error.into()),
+            (None, result) => Ok(result.unwrap_or(Value::Null)),
+        }
+    }
+}
+
+/// Puts the namespace of this run back where the recording has `NETNS_PLACEHOLDER`.
+fn in_netns(value: Value, netns: &str) -> Value {
+    match value {
+        Value::String(s) if s == NETNS_PLACEHOLDER => Value::String(netns.to_owned()),
+        Value::Array(values) => {
+            Value::Array(values.into_iter().map(|v| in_netns(v, netns)).collect())
+        }
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, in_netns(v, netns)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for ReplayPlugin {
+    async fn init(&self) -> Result<()> {
+        self.call("init", json!([self.config])).await?;
+        Ok(())
+    }
+
+    async fn setup_proxy(&self, proxy: Value) -> Result<ConnectionDescriptor> {
+        let result = self.call("setup_proxy", json!([proxy])).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn teardown_proxy(&self, proxy: &ConnectionDescriptor) -> Result<()> {
+        self.call("teardown_proxy", json!([proxy])).await?;
+        Ok(())
+    }
+
+    async fn setup_proxy_in_netns(
+        &self,
+        proxy: Value,
+        netns: &str,
+    ) -> Result<ConnectionDescriptor> {
+        let result = self
+            .call("setup_proxy_in_netns", json!([proxy, netns]))
+            .await?;
+        Ok(serde_json::from_value(in_netns(result, netns))?)
+    }
+
+    async fn metadata(&self) -> Result<PluginMetaData> {
+        let result = self.call("metadata", json!([])).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn tests(&self) -> Result<Vec<TestDescriptor>> {
+        let result = self.call("tests", json!([])).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn run_test(&self, test: &TestDescriptor, proxy: &ConnectionDescriptor) -> Result<Value> {
+        self.call("run_test", json!([test.name, proxy])).await
+    }
+
+    async fn data_transforms(&self) -> Result<Vec<DataTransformDescriptor>> {
+        let result = self.call("data_transforms", json!([])).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn transform(
+        &self,
+        transform: &DataTransformDescriptor,
+        content: Value,
+    ) -> Result<Value> {
+        self.call("transform", json!([transform.name, content]))
+            .await
+    }
+
+    async fn proxy_schemes(&self) -> Result<Vec<String>> {
+        let result = self.call("proxy_schemes", json!([])).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn proxy_protocols(&self) -> Result<Vec<String>> {
+        let result = self.call("proxy_protocols", json!([])).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn parse_protocol(&self, connection_string: &str) -> Result<Vec<ProtocolDescriptor>> {
+        let result = self
+            .call("parse_protocol", json!([connection_string]))
+            .await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        // Recordings made before `shutdown` existed end without it.
+        match self.call("shutdown", json!([])).await {
+            Err(e) if e.is_method_not_found() => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockScript};
+    use crate::plugin::json_rpc::JSONRPCPlugin;
+    use crate::plugin::record::Recorder;
+
+    #[tokio::test]
+    async fn replays_recorded_responses() {
+        let script: MockScript = serde_json::from_value(json!({
+            "proxies": [{"name": "a", "content": "a"}],
+            "tests": [
+                {"name": "latency", "response": {"result": {"ms": 120}}},
+                {"name": "download", "response": {"error": "connection reset"}},
+            ],
+        }))
+        .unwrap();
+        let server = mock::serve(script).await.unwrap();
+        let path = std::env::temp_dir().join(format!("replay-{}.ndjson", std::process::id()));
+        let plugin = JSONRPCPlugin::new(&server.addr.to_string(), Value::Null)
+            .await
+            .unwrap()
+            .with_recorder(Recorder::create(&path).unwrap());
+        let proxies = plugin.parse_protocol("sub").await.unwrap();
+        let connection = plugin
+            .setup_proxy(proxies[0].content.clone())
+            .await
+            .unwrap();
+        let latency = TestDescriptor {
+            name: "latency".to_owned(),
+        };
+        let download = TestDescriptor {
+            name: "download".to_owned(),
+        };
+        let measured = plugin.run_test(&latency, &connection).await.unwrap();
+        assert!(plugin.run_test(&download, &connection).await.is_err());
+        drop(server);
+
+        let replay = ReplayPlugin::load(&path, Value::Null, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let proxies = replay.parse_protocol("sub").await.unwrap();
+        assert_eq!(proxies[0].name, "a");
+        let replayed = replay
+            .setup_proxy(proxies[0].content.clone())
+            .await
+            .unwrap();
+        assert_eq!(replayed.socks5, connection.socks5);
+        assert_eq!(
+            replay.run_test(&latency, &replayed).await.unwrap(),
+            measured
+        );
+        let error = replay.run_test(&download, &replayed).await.unwrap_err();
+        assert_eq!(error.kind(), PluginErrorKind::Call);
+        assert!(replay.parse_protocol("other").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn replays_proxies_set_up_in_another_namespace() {
+        let script: MockScript = serde_json::from_value(json!({
+            "proxies": [{"name": "a", "content": "a"}],
+            "tests": [{"name": "latency", "response": {"result": {"ms": 120}}}],
+        }))
+        .unwrap();
+        let server = mock::serve(script).await.unwrap();
+        let path = std::env::temp_dir().join(format!("replay-netns-{}.ndjson", std::process::id()));
+        let plugin = JSONRPCPlugin::new(&server.addr.to_string(), Value::Null)
+            .await
+            .unwrap()
+            .with_recorder(Recorder::create(&path).unwrap());
+        let latency = TestDescriptor {
+            name: "latency".to_owned(),
+        };
+        let proxies = plugin.parse_protocol("sub").await.unwrap();
+        let connection = plugin
+            .setup_proxy_in_netns(proxies[0].content.clone(), "/proc/100/fd/7")
+            .await
+            .unwrap();
+        let measured = plugin.run_test(&latency, &connection).await.unwrap();
+        drop(server);
+
+        let replay = ReplayPlugin::load(&path, Value::Null, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let replayed = replay
+            .setup_proxy_in_netns(proxies[0].content.clone(), "/proc/200/fd/9")
+            .await
+            .unwrap();
+        assert_eq!(replayed.netns.as_deref(), Some("/proc/200/fd/9"));
+        assert_eq!(
+            replay.run_test(&latency, &replayed).await.unwrap(),
+            measured
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! Plugins compiled to WebAssembly, run inside the controller with no access to the network or
+//! the filesystem.
+//!
+//! The module exports its `memory`, an `alloc(len: i32) -> i32` the controller writes arguments
+//! with, optionally a `dealloc(ptr: i32, len: i32)`, and one function per method of the plugin
+//! protocol it implements, named after the method:
+//!
+//! ```text
+//! (func (export "parse_protocol") (param $ptr i32) (param $len i32) (result i64))
+//! ```
+//!
+//! A method receives the JSON array of its params, the same as a JSON-RPC call by position, and
+//! returns `ptr << 32 | len` of a JSON `{"result": ...}` or `{"error": {"code": ..., "message": ...}}`.
+//! Once the response is read, the controller hands both the params and the response back to
+//! `dealloc`. A module without `dealloc` owns its heap and has to reset it on each call, as the
+//! memory of every call is otherwise kept until it reaches `memory_mb`.
+//! WASI reactors get their `_initialize` called once after instantiation.
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use jsonrpsee::types::error::ErrorCode;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use wasmtime::{Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits};
+use wasmtime::{StoreLimitsBuilder, TypedFunc};
+use wasmtime_wasi::preview1::WasiP1Ctx;
+use wasmtime_wasi::WasiCtxBuilder;
+
+use super::{
+    call_error, ConnectionDescriptor, DataTransformDescriptor, Plugin, PluginError, PluginMetaData,
+    ProtocolDescriptor, Result, TestDescriptor,
+};
+use crate::plugin_loader::PluginLoaderError;
+
+fn default_memory_mb() -> usize {
+    64
+}
+
+fn default_fuel() -> u64 {
+    1_000_000_000
+}
+
+/// What a WebAssembly plugin may consume.
+#[derive(Debug, Deserialize, Clone)]
+pub struct WasmLimits {
+    /// The most linear memory the module may grow to
+    #[serde(default = "default_memory_mb")]
+    memory_mb: usize,
+    /// Instructions, roughly, a single call may execute
+    #[serde(default = "default_fuel")]
+    fuel: u64,
+}
+
+impl Default for WasmLimits {
+    fn default() -> Self {
+        WasmLimits {
+            memory_mb: default_memory_mb(),
+            fuel: default_fuel(),
+        }
+    }
+}
+
+struct State {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+}
+
+#[derive(Deserialize)]
+struct GuestError {
+    code: Option<i32>,
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GuestResponse {
+    Result(Value),
+    Error(GuestError),
+}
+
+/// A running instance of the module.
+struct Guest {
+    store: Store<State>,
+    instance: Instance,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+}
+
+impl Guest {
+    fn call(&mut self, method: &str, params: &Value, fuel: u64) -> Result<Value> {
+        let Ok(function) = self
+            .instance
+            .get_typed_func::<(i32, i32), i64>(&mut self.store, method)
+        else {
+            return Err(PluginError::Unsupported);
+        };
+        let trapped = |e: wasmtime::Error| call_error(format!("{method} trapped. {e:#}"));
+        self.store.set_fuel(fuel).map_err(trapped)?;
+        let params = serde_json::to_vec(params)?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, params.len() as i32)
+            .map_err(trapped)?;
+        self.memory
+            .write(&mut self.store, ptr as usize, &params)
+            .map_err(|e| call_error(format!("alloc returned an invalid pointer. {e}")))?;
+        let packed = function
+            .call(&mut self.store, (ptr, params.len() as i32))
+            .map_err(trapped)? as u64;
+        let (response_ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let response = self
+            .memory
+            .data(&self.store)
+            .get(response_ptr..response_ptr + len)
+            .ok_or_else(|| call_error(format!("{method} returned an invalid pointer")))?
+            .to_vec();
+        if let Some(dealloc) = &self.dealloc {
+            dealloc
+                .call(&mut self.store, (ptr, params.len() as i32))
+                .and_then(|_| dealloc.call(&mut self.store, (response_ptr as i32, len as i32)))
+                .map_err(trapped)?;
+        }
+        match serde_json::from_slice(&response)? {
+            GuestResponse::Result(result) => Ok(result),
+            GuestResponse::Error(error) if error.code == Some(ErrorCode::MethodNotFound.code()) => {
+                Err(PluginError::Unsupported)
+            }
+            GuestResponse::Error(error) => Err(call_error(error.message)),
+        }
+    }
+}
+
+/// A parser or transformer compiled to a WASI module.
+///
+/// The module gets no preopened directories, environment or sockets, only stderr for its logs.
+/// It can therefore not set up proxies or run tests, and never claims to.
+pub struct WasmPlugin {
+    guest: Arc<Mutex<Guest>>,
+    config: Value,
+    fuel: u64,
+}
+
+impl WasmPlugin {
+    /// Compiles and instantiates the module at `path`, which may also be in the text format.
+    pub fn load(
+        path: &Path,
+        config: Value,
+        limits: &WasmLimits,
+    ) -> std::result::Result<Self, PluginLoaderError> {
+        Self::instantiate(path, limits)
+            .map(|guest| WasmPlugin {
+                guest: Arc::new(Mutex::new(guest)),
+                config,
+                fuel: limits.fuel,
+            })
+            .map_err(PluginLoaderError::WasmError)
+    }
+
+    fn instantiate(path: &Path, limits: &WasmLimits) -> anyhow::Result<Guest> {
+        let engine = Engine::new(Config::new().consume_fuel(true))?;
+        let module = Module::from_file(&engine, path)?;
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::preview1::add_to_linker_sync(&mut linker, |state: &mut State| {
+            &mut state.wasi
+        })?;
+        let mut store = Store::new(
+            &engine,
+            State {
+                wasi: WasiCtxBuilder::new().inherit_stderr().build_p1(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(limits.memory_mb << 20)
+                    .trap_on_grow_failure(true)
+                    .build(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(limits.fuel)?;
+        let instance = linker.instantiate(&mut store, &module)?;
+        if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            initialize.call(&mut store, ())?;
+        }
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("The module does not export its memory"))?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let dealloc = instance.get_typed_func(&mut store, "dealloc").ok();
+        Ok(Guest {
+            store,
+            instance,
+            memory,
+            alloc,
+            dealloc,
+        })
+    }
+
+    async fn call(&self, method: &'static str, params: Value) -> Result<Value> {
+        let guest = self.guest.clone();
+        let fuel = self.fuel;
+        tokio::task::spawn_blocking(move || guest.lock().unwrap().call(method, &params, fuel))
+            .await
+            .map_err(|e| call_error(format!("{method} panicked. {e}")))?
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for WasmPlugin {
+    async fn init(&self) -> Result<()> {
+        // Modules without any config need not implement it.
+        match self.call("init", json!([self.config])).await {
+            Err(e) if e.is_method_not_found() => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    async fn setup_proxy(&self, _proxy: Value) -> Result<ConnectionDescriptor> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn teardown_proxy(&self, _proxy: &ConnectionDescriptor) -> Result<()> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn metadata(&self) -> Result<PluginMetaData> {
+        let mut metadata: PluginMetaData =
+            serde_json::from_value(self.call("metadata", json!([])).await?)?;
+        let capabilities = &mut metadata.capabilities;
+        capabilities.proxy_backend = false;
+        capabilities.test_provider = false;
+        capabilities.supports_tun = false;
+        capabilities.supports_netns = false;
+        Ok(metadata)
+    }
+
+    async fn tests(&self) -> Result<Vec<TestDescriptor>> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn run_test(
+        &self,
+        _test: &TestDescriptor,
+        _proxy: &ConnectionDescriptor,
+    ) -> Result<Value> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn data_transforms(&self) -> Result<Vec<DataTransformDescriptor>> {
+        let result = self.call("data_transforms", json!([])).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn transform(
+        &self,
+        transform: &DataTransformDescriptor,
+        content: Value,
+    ) -> Result<Value> {
+        self.call("transform", json!([transform.name, content]))
+            .await
+    }
+
+    async fn proxy_schemes(&self) -> Result<Vec<String>> {
+        Err(PluginError::Unsupported)
+    }
+
+    async fn proxy_protocols(&self) -> Result<Vec<String>> {
+        let result = self.call("proxy_protocols", json!([])).await?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    async fn parse_protocol(&self, connection_string: &str) -> Result<Vec<ProtocolDescriptor>> {
+        let result = self
+            .call("parse_protocol", json!([connection_string]))
+            .await?;
+        Ok(serde_json::from_value(result)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::PluginErrorKind;
+
+    fn data(offset: u32, json: &str) -> (String, i64) {
+        let segment = format!(
+            "(data (i32.const {offset}) \"{}\")",
+            json.replace('"', "\\\"")
+        );
+        (segment, ((offset as i64) << 32) | json.len() as i64)
+    }
+
+    fn module() -> String {
+        let (metadata, metadata_at) = data(
+            0,
+            r#"{"result":{"name":"wat","protocol_version":1,"capabilities":{"parser":true,"test_provider":true}}}"#,
+        );
+        let (proxies, proxies_at) = data(512, r#"{"result":[{"name":"a","content":"a"}]}"#);
+        format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                {metadata}
+                {proxies}
+                (func (export "alloc") (param $len i32) (result i32)
+                    (global.get $next)
+                    (global.set $next (i32.add (global.get $next) (local.get $len))))
+                (func (export "metadata") (param i32 i32) (result i64)
+                    (i64.const {metadata_at}))
+                (func (export "parse_protocol") (param i32 i32) (result i64)
+                    (i64.const {proxies_at}))
+                (func (export "transform") (param i32 i32) (result i64)
+                    (loop $spin (br $spin))
+                    (unreachable))
+                (func (export "data_transforms") (param i32 i32) (result i64)
+                    (drop (memory.grow (i32.const 100)))
+                    (unreachable)))"#
+        )
+    }
+
+    #[tokio::test]
+    async fn runs_within_limits() {
+        let path = std::env::temp_dir().join(format!("plugin-{}.wat", std::process::id()));
+        std::fs::write(&path, module()).unwrap();
+        let limits = WasmLimits {
+            memory_mb: 1,
+            fuel: 100_000,
+        };
+        let plugin = WasmPlugin::load(&path, Value::Null, &limits).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        plugin.init().await.unwrap();
+        let metadata = plugin.metadata().await.unwrap();
+        assert_eq!(metadata.name, "wat");
+        assert!(metadata.capabilities.parser);
+        assert!(
+            !metadata.capabilities.test_provider,
+            "a sandboxed plugin cannot run tests"
+        );
+        let proxies = plugin.parse_protocol("sub").await.unwrap();
+        assert_eq!(proxies[0].name, "a");
+
+        let transform = DataTransformDescriptor {
+            name: "spin".to_owned(),
+            accpeted_scheme: "a".to_owned(),
+            produced_scheme: "b".to_owned(),
+        };
+        let spin = plugin.transform(&transform, Value::Null).await.unwrap_err();
+        assert!(format!("{spin:?}").contains("fuel"), "{spin:?}");
+        let grow = plugin.data_transforms().await.unwrap_err();
+        assert_eq!(grow.kind(), PluginErrorKind::Call);
+        assert!(plugin.proxy_protocols().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hands_params_and_responses_to_dealloc() {
+        let (proxies, proxies_at) = data(0, r#"{"result":[]}"#);
+        let module = format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (global $freed (export "freed") (mut i32) (i32.const 0))
+                {proxies}
+                (func (export "alloc") (param $len i32) (result i32)
+                    (i32.const 512))
+                (func (export "dealloc") (param $ptr i32) (param $len i32)
+                    (global.set $freed (i32.add (global.get $freed) (local.get $len))))
+                (func (export "parse_protocol") (param i32 i32) (result i64)
+                    (i64.const {proxies_at})))"#
+        );
+        let path = std::env::temp_dir().join(format!("dealloc-{}.wat", std::process::id()));
+        std::fs::write(&path, module).unwrap();
+        let plugin = WasmPlugin::load(&path, Value::Null, &WasmLimits::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(plugin.parse_protocol("sub").await.unwrap().is_empty());
+        let mut guest = plugin.guest.lock().unwrap();
+        let guest = &mut *guest;
+        let freed = guest
+            .instance
+            .get_global(&mut guest.store, "freed")
+            .unwrap();
+        let params = json!(["sub"]).to_string().len() + r#"{"result":[]}"#.len();
+        assert_eq!(freed.get(&mut guest.store).unwrap_i32(), params as i32);
+    }
+
+    #[test]
+    fn explains_why_a_module_does_not_load() {
+        let path = std::env::temp_dir().join(format!("no-alloc-{}.wat", std::process::id()));
+        std::fs::write(&path, r#"(module (memory (export "memory") 1))"#).unwrap();
+        let error = WasmPlugin::load(&path, Value::Null, &WasmLimits::default()).err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.unwrap().to_string().contains("alloc"));
+    }
+}
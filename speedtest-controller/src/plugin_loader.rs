@@ -5,6 +5,33 @@ pub enum PluginLoaderError {
     #[error("Invalid plugin source `{0}`, scheme is unexpected")]
     UnexpectedScheme(String),
 
+    #[error("Plugin `{name}` speaks protocol version {version}, which is not supported")]
+    IncompatibleProtocol { name: String, version: u32 },
+
+    #[error("There is no built-in plugin `{0}`")]
+    UnknownBuiltin(String),
+
+    #[error("Environment variable `{0}` used in the plugin config is not set")]
+    UndefinedVariable(String),
+
+    #[error("Working directory `{}` of the plugin does not exist", .0.display())]
+    MissingDirectory(std::path::PathBuf),
+
+    #[error("Invalid plugin config")]
+    ConfigError(#[from] serde_json::Error),
+
+    #[error("Unable to load the WebAssembly module. {0:#}")]
+    WasmError(#[source] anyhow::Error),
+
+    #[error("Unable to launch the plugin process. {0}")]
+    LaunchError(#[source] std::io::Error),
+
+    #[error("Unable to sandbox the plugin process. {0}")]
+    SandboxError(#[source] std::io::Error),
+
+    #[error("Unable to open the recording")]
+    RecordingError(#[from] std::io::Error),
+
     #[error("Unable to load the plugin")]
     PluginError(#[from] crate::plugin::PluginError),
 }
@@ -1,12 +1,37 @@
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::io;
 use std::process::Stdio;
+use std::sync::LazyLock;
+use std::time::Duration;
 
-use futures::StreamExt;
+use futures::future::ready;
+use futures::{Stream, StreamExt};
 use regex::Regex;
+use serde::Deserialize;
 use tokio::{
+    io::AsyncRead,
     process::{Child, Command},
     select,
 };
-use tokio_util::codec::{FramedRead, LinesCodec};
+use tokio_util::codec::{AnyDelimiterCodec, FramedRead};
+
+/// The lines `output` gives, decoded lossily so that output which is not UTF-8 is still logged.
+fn lines(output: impl AsyncRead) -> impl Stream<Item = String> {
+    FramedRead::new(output, AnyDelimiterCodec::new(b"\n".to_vec(), vec![])).filter_map(|line| {
+        ready(match line {
+            Ok(line) => Some(
+                String::from_utf8_lossy(&line)
+                    .trim_end_matches('\r')
+                    .to_owned(),
+            ),
+            Err(e) => {
+                log::warn!("Unable to read the output of a process. {e}");
+                None
+            }
+        })
+    })
+}
 
 /// Creates a process using the given `Command`, waits for a pattern to match in the process output,
 /// and returns the transformed output and the child process.
@@ -26,30 +51,35 @@ use tokio_util::codec::{FramedRead, LinesCodec};
 /// # Returns
 ///
 /// Returns a tuple containing the transformed output and the child process.
+/// The rest of the process output is logged. The process is killed when the thread spawning it
+/// exits, not the controller, so this has to be called from a thread that lives as long as the
+/// controller, e.g. a runtime worker but not a blocking or network namespace thread.
 ///
-/// # Panics
+/// # Errors
 ///
-/// Panics if the process does not give any output that matches the specified regular expression pattern.
+/// Fails if the process cannot be spawned, or exits without giving any output that matches the
+/// specified regular expression pattern.
 pub async fn create_process_and_wait_for_pattern<const N: usize, T, Output>(
     mut c: Command,
     re: Regex,
     transform: T,
-) -> (Output, Child)
+) -> io::Result<(Output, PluginProcess)>
 where
     T: FnOnce([&str; N]) -> Output,
 {
+    // SAFETY: prctl is async-signal-safe.
+    #[cfg(target_os = "linux")]
+    unsafe {
+        c.pre_exec(|| check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL)));
+    }
     let mut process = c
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .kill_on_drop(true)
-        .spawn()
-        .expect("failed to execute process");
+        .spawn()?;
 
-    let mut stdout = FramedRead::new(process.stdout.take().unwrap(), LinesCodec::new())
-        .map(|data| data.expect("fail on stdout!"));
-
-    let mut stderr = FramedRead::new(process.stderr.take().unwrap(), LinesCodec::new())
-        .map(|data| data.expect("fail on stderr!"));
+    let mut stdout = lines(process.stdout.take().unwrap());
+    let mut stderr = lines(process.stderr.take().unwrap());
 
     loop {
         let line = select! {
@@ -58,12 +88,508 @@ where
              else => break,
         };
         if let Some((_, group)) = re.captures_iter(&line).map(|c| c.extract()).next() {
-            return (transform(group), process);
+            let output = transform(group);
+            // A process writing to a closed pipe would fail.
+            let pid = process.id().unwrap_or_default();
+            tokio::spawn(async move {
+                let mut output = futures::stream::select(stdout, stderr);
+                while let Some(line) = output.next().await {
+                    log::debug!("[{pid}] {line}");
+                }
+            });
+            return Ok((output, PluginProcess::new(process)));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("The process did not give any output that is accepted by the regex {re}"),
+    ))
+}
+
+/// How long a process gets to exit after SIGTERM before it is killed.
+pub const TERMINATE_GRACE: Duration = Duration::from_secs(5);
+
+/// A process started by `create_process_and_wait_for_pattern`.
+///
+/// When it leads a process group, see `Sandbox`, every signal goes to the whole group, so that
+/// the processes it spawned do not outlive it. Dropping it kills the processes right away.
+pub struct PluginProcess {
+    child: Child,
+    pgid: Option<libc::pid_t>,
+}
+
+impl PluginProcess {
+    fn new(child: Child) -> Self {
+        let pgid = child
+            .id()
+            .map(|pid| pid as libc::pid_t)
+            // SAFETY: getpgid has no memory safety requirements.
+            .filter(|pid| unsafe { libc::getpgid(*pid) } == *pid);
+        PluginProcess { child, pgid }
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    fn signal(&self, signal: libc::c_int) {
+        let target = match (self.pgid, self.child.id()) {
+            (Some(pgid), _) => -pgid,
+            (None, Some(pid)) => pid as libc::pid_t,
+            (None, None) => return,
+        };
+        // SAFETY: kill has no memory safety requirements.
+        unsafe {
+            libc::kill(target, signal);
+        }
+    }
+
+    /// Waits for the process, and the rest of its group, to exit.
+    async fn wait(&mut self) {
+        let _ = self.child.wait().await;
+        if let Some(pgid) = self.pgid {
+            while group_running(pgid) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+    }
+
+    /// Sends SIGTERM, and SIGKILL to whatever is still running after `grace`.
+    pub async fn terminate(&mut self, grace: Duration) {
+        self.signal(libc::SIGTERM);
+        if tokio::time::timeout(grace, self.wait()).await.is_err() {
+            log::warn!(
+                "Process {} did not exit in {grace:?}, killing it",
+                self.id().unwrap_or_default()
+            );
+            self.signal(libc::SIGKILL);
+            let _ = tokio::time::timeout(grace, self.wait()).await;
+        }
+        self.pgid = None;
+    }
+}
+
+/// Whether a process of group `pgid` is still running, zombies are not.
+#[cfg(target_os = "linux")]
+fn group_running(pgid: libc::pid_t) -> bool {
+    let mut entries = std::fs::read_dir("/proc").into_iter().flatten().flatten();
+    entries.any(|entry| {
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            return false;
+        };
+        // The command name before may contain anything, even ") ".
+        let Some((_, fields)) = stat.rsplit_once(") ") else {
+            return false;
+        };
+        let fields: Vec<_> = fields.split_whitespace().take(3).collect();
+        matches!(fields[..], [state, _ppid, pgrp] if state != "Z" && pgrp == pgid.to_string())
+    })
+}
+
+/// Whether a process of group `pgid` is still running, zombies included.
+#[cfg(not(target_os = "linux"))]
+fn group_running(pgid: libc::pid_t) -> bool {
+    // SAFETY: kill has no memory safety requirements.
+    unsafe { libc::kill(-pgid, 0) == 0 }
+}
+
+impl Drop for PluginProcess {
+    fn drop(&mut self) {
+        self.signal(libc::SIGKILL);
+    }
+}
+
+/// Limits and isolation applied to a plugin process, and inherited by everything it spawns.
+///
+/// Only supported on Linux, `seccomp` only on x86_64 and aarch64.
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Sandbox {
+    /// Address space of each process (`RLIMIT_AS`)
+    pub memory_mb: Option<u64>,
+    /// CPU time of each process (`RLIMIT_CPU`)
+    pub cpu_seconds: Option<u64>,
+    /// Open file descriptors of each process (`RLIMIT_NOFILE`)
+    pub open_files: Option<u64>,
+    /// Keeps setuid executables from gaining privileges
+    pub no_new_privs: bool,
+    /// Runs in a new user namespace, as the same user but without its capabilities elsewhere.
+    /// This includes the network namespaces of `isolation`, which the plugin then cannot enter
+    pub user_namespace: bool,
+    /// Denies the syscalls in `DENIED_SYSCALLS`, implies `no_new_privs`
+    pub seccomp: bool,
+    /// Leaves `setns` out of the denied syscalls, so that the plugin can set up proxies in the
+    /// network namespaces of `isolation`. Set for every plugin while `isolation` is enabled
+    pub allow_setns: bool,
+}
+
+/// The seccomp filter of `Sandbox::seccomp`.
+#[cfg(target_os = "linux")]
+mod seccomp {
+    use std::io;
+
+    /// What a plugin or proxy core has no business doing.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setns,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_userfaultfd,
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+    /// Syscalls of the x32 ABI, which share the x86_64 audit arch
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn bpf(code: u32, jt: u8, jf: u8, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// A seccomp filter failing `DENIED_SYSCALLS` with `EPERM` and killing foreign-arch syscalls.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub(super) fn filter(allow_setns: bool) -> io::Result<Vec<libc::sock_filter>> {
+        use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+        // Offsets into `struct seccomp_data`
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+        let mut filter = vec![
+            bpf(BPF_LD | BPF_W | BPF_ABS, 0, 0, ARCH),
+            bpf(BPF_JMP | BPF_JEQ | BPF_K, 1, 0, AUDIT_ARCH),
+            bpf(BPF_RET | BPF_K, 0, 0, libc::SECCOMP_RET_KILL_PROCESS),
+            bpf(BPF_LD | BPF_W | BPF_ABS, 0, 0, NR),
+        ];
+        let denied: Vec<_> = DENIED_SYSCALLS
+            .iter()
+            .filter(|nr| !allow_setns || **nr != libc::SYS_setns)
+            .collect();
+        // Each check jumps over the ones after it and the allow, to the deny.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let checks = denied.len() + 1;
+            filter.push(bpf(
+                BPF_JMP | libc::BPF_JGE | BPF_K,
+                checks as u8,
+                0,
+                X32_SYSCALL_BIT,
+            ));
+        }
+        for (i, nr) in denied.iter().enumerate() {
+            let remaining = denied.len() - i;
+            filter.push(bpf(
+                BPF_JMP | BPF_JEQ | BPF_K,
+                remaining as u8,
+                0,
+                **nr as u32,
+            ));
+        }
+        filter.push(bpf(BPF_RET | BPF_K, 0, 0, libc::SECCOMP_RET_ALLOW));
+        filter.push(bpf(
+            BPF_RET | BPF_K,
+            0,
+            0,
+            libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        ));
+        Ok(filter)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub(super) fn filter(_allow_setns: bool) -> io::Result<Vec<libc::sock_filter>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "seccomp is only supported on x86_64 and aarch64",
+        ))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes a file with raw syscalls, which is fine between fork and exec.
+#[cfg(target_os = "linux")]
+fn write_file(path: &CString, content: &[u8]) -> io::Result<()> {
+    // SAFETY: `path` is a valid C string and `content` outlives the call.
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written = libc::write(fd, content.as_ptr().cast(), content.len());
+        libc::close(fd);
+        if written == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl Sandbox {
+    /// Applies the sandbox when `command` is spawned, in a process group of its own.
+    ///
+    /// Fails when an option is not supported on this platform.
+    pub fn apply(&self, command: &mut Command) -> io::Result<()> {
+        command.process_group(0);
+        #[cfg(target_os = "linux")]
+        return self.apply_limits(command);
+        #[cfg(not(target_os = "linux"))]
+        {
+            let unset = self.memory_mb.is_none()
+                && self.cpu_seconds.is_none()
+                && self.open_files.is_none()
+                && !self.no_new_privs
+                && !self.user_namespace
+                && !self.seccomp;
+            if !unset {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the plugin sandbox is only supported on Linux",
+                ));
+            }
+            Ok(())
         }
     }
 
-    panic!(
-        "The process did not give any output that is accept by the regex {}",
-        re
-    )
+    #[cfg(target_os = "linux")]
+    fn apply_limits(&self, command: &mut Command) -> io::Result<()> {
+        let limits = [
+            (libc::RLIMIT_AS, self.memory_mb.map(|mb| mb << 20)),
+            (libc::RLIMIT_CPU, self.cpu_seconds),
+            (libc::RLIMIT_NOFILE, self.open_files),
+        ];
+        let no_new_privs = self.no_new_privs || self.seccomp;
+        let filter = self
+            .seccomp
+            .then(|| seccomp::filter(self.allow_setns))
+            .transpose()?;
+        // Everything allocated before the fork, only syscalls are made after it.
+        // SAFETY: getuid and getgid cannot fail.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let user_namespace = self.user_namespace.then(|| {
+            let path = |file: &str| CString::new(format!("/proc/self/{file}")).unwrap();
+            (
+                path("setgroups"),
+                (path("uid_map"), format!("{uid} {uid} 1")),
+                (path("gid_map"), format!("{gid} {gid} 1")),
+            )
+        });
+        let pre_exec = move || -> io::Result<()> {
+            // SAFETY: only async-signal-safe syscalls on memory owned by the closure.
+            unsafe {
+                if let Some((setgroups, (uid_map, uids), (gid_map, gids))) = &user_namespace {
+                    check(libc::unshare(libc::CLONE_NEWUSER))?;
+                    write_file(setgroups, b"deny")?;
+                    write_file(uid_map, uids.as_bytes())?;
+                    write_file(gid_map, gids.as_bytes())?;
+                }
+                for (resource, limit) in limits {
+                    if let Some(limit) = limit {
+                        let limit = libc::rlimit {
+                            rlim_cur: limit,
+                            rlim_max: limit,
+                        };
+                        check(libc::setrlimit(resource, &limit))?;
+                    }
+                }
+                if no_new_privs {
+                    check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+                }
+                if let Some(filter) = &filter {
+                    let program = libc::sock_fprog {
+                        len: filter.len() as u16,
+                        filter: filter.as_ptr() as *mut _,
+                    };
+                    check(libc::prctl(
+                        libc::PR_SET_SECCOMP,
+                        libc::SECCOMP_MODE_FILTER as libc::c_ulong,
+                        &program as *const libc::sock_fprog,
+                    ))?;
+                }
+            }
+            Ok(())
+        };
+        // SAFETY: see the closure.
+        unsafe {
+            command.pre_exec(pre_exec);
+        }
+        Ok(())
+    }
+}
+
+static VARIABLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$\{(\w+)\}").unwrap());
+
+/// Replaces every `${VAR}` in `value` by what `lookup` gives for `VAR`.
+///
+/// Fails with the name of the first variable `lookup` knows nothing about.
+pub fn interpolate(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut undefined = None;
+    let interpolated = VARIABLE.replace_all(value, |captures: &regex::Captures| {
+        lookup(&captures[1]).unwrap_or_else(|| {
+            undefined.get_or_insert_with(|| captures[1].to_owned());
+            String::new()
+        })
+    });
+    match undefined {
+        Some(name) => Err(name),
+        None => Ok(interpolated.into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn terminates_the_process_group() {
+        let mut command = Command::new("sh");
+        // The grandchild ignores SIGTERM, so it is only gone after the SIGKILL.
+        command.args([
+            "-c",
+            "sh -c 'trap \"\" TERM; sleep 60' & echo started; wait",
+        ]);
+        Sandbox::default().apply(&mut command).unwrap();
+        let re = Regex::new(r"(started)").unwrap();
+        let ((), mut process) = create_process_and_wait_for_pattern(command, re, |[_]| ())
+            .await
+            .unwrap();
+        let pgid = process.pgid.unwrap();
+        process.terminate(Duration::from_millis(200)).await;
+        assert!(!group_running(pgid));
+    }
+
+    #[tokio::test]
+    async fn reads_output_that_is_not_utf8() {
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "printf 'caf\\351\\r\\nListen on 1\\n'; printf '\\377\\n'",
+        ]);
+        let re = Regex::new(r"Listen on (.+)").unwrap();
+        let (endpoint, _process) =
+            create_process_and_wait_for_pattern(command, re, |[endpoint]| endpoint.to_owned())
+                .await
+                .unwrap();
+        assert_eq!(endpoint, "1");
+
+        let output: Vec<_> = lines(&b"caf\xe9\r\nok\n"[..]).collect().await;
+        assert_eq!(output, ["caf\u{fffd}", "ok"]);
+    }
+
+    #[tokio::test]
+    async fn fails_processes_that_do_not_start() {
+        let re = Regex::new(r"Listen on (.+)").unwrap();
+        let missing = Command::new("/nonexistent/plugin");
+        let result = create_process_and_wait_for_pattern(missing, re.clone(), |[_]| ()).await;
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        let mut silent = Command::new("sh");
+        silent.args(["-c", "echo starting; exit 1"]);
+        let result = create_process_and_wait_for_pattern(silent, re, |[_]| ()).await;
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn allows_setns_for_isolation() {
+        let denied = seccomp::filter(false).unwrap();
+        let allowed = seccomp::filter(true).unwrap();
+        assert_eq!(allowed.len() + 1, denied.len());
+        let setns = libc::SYS_setns as u32;
+        assert!(denied.iter().any(|instruction| instruction.k == setns));
+        assert!(!allowed.iter().any(|instruction| instruction.k == setns));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn sandboxes_the_process() {
+        let sandbox = Sandbox {
+            open_files: Some(64),
+            seccomp: true,
+            ..Default::default()
+        };
+        let mut command = Command::new("sh");
+        command.args([
+            "-c",
+            "ulimit -n; grep -E 'NoNewPrivs|Seccomp:' /proc/self/status",
+        ]);
+        sandbox.apply(&mut command).unwrap();
+        let output = command.output().await.unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<_> = output
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .collect();
+        assert_eq!(
+            lines,
+            [vec!["64"], vec!["NoNewPrivs:", "1"], vec!["Seccomp:", "2"]]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn maps_the_user_into_a_namespace_of_its_own() {
+        let sandbox = Sandbox {
+            user_namespace: true,
+            ..Default::default()
+        };
+        let mut command = Command::new("cat");
+        command.args(["/proc/self/uid_map", "/proc/self/gid_map"]);
+        sandbox.apply(&mut command).unwrap();
+        let output = command.output().await.unwrap();
+        assert!(output.status.success(), "{output:?}");
+        let output = String::from_utf8(output.stdout).unwrap();
+        let maps: Vec<_> = output
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .collect();
+        // SAFETY: getuid and getgid cannot fail.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let (uid, gid) = (uid.to_string(), gid.to_string());
+        assert_eq!(maps, [[&*uid, &*uid, "1"], [&*gid, &*gid, "1"]]);
+    }
+
+    #[test]
+    fn interpolates_variables() {
+        let lookup = |name: &str| (name == "HOME").then(|| "/home/me".to_owned());
+        assert_eq!(
+            interpolate("${HOME}/bin/gost", lookup).unwrap(),
+            "/home/me/bin/gost"
+        );
+        assert_eq!(interpolate("$HOME {HOME}", lookup).unwrap(), "$HOME {HOME}");
+        assert_eq!(interpolate("${HOME}:${GOST}", lookup).unwrap_err(), "GOST");
+    }
 }
@@ -0,0 +1,347 @@
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// An event emitted while a speedtest run is in progress.
+#[derive(Debug, Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    RunStarted {
+        proxies: usize,
+        tests: usize,
+    },
+    ProxySetup {
+        provider: String,
+        proxy: String,
+        attempts: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    TestFinished {
+        provider: String,
+        proxy: String,
+        test_provider: String,
+        test: String,
+        attempts: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    ProxyFinished {
+        provider: String,
+        proxy: String,
+    },
+    RunFinished {
+        elapsed_ms: u128,
+    },
+}
+
+/// A sink for progress events.
+pub trait Reporter: Send + Sync {
+    fn report(&self, event: &Event);
+
+    /// Reports an event of the run at `vantage_point`, which runs at the same time as the others.
+    fn report_at(&self, _vantage_point: &str, event: &Event) {
+        self.report(event);
+    }
+}
+
+/// An event as it is written, naming the vantage point of the run unless it runs here.
+#[derive(Serialize)]
+struct Located<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vantage_point: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Picks a reporter for stderr: a progress line if it is a terminal, otherwise NDJSON events.
+pub fn stderr_reporter() -> Arc<dyn Reporter> {
+    if std::io::stderr().is_terminal() {
+        Arc::new(TerminalReporter::new(std::io::stderr()))
+    } else {
+        Arc::new(NdjsonReporter::new(std::io::stderr()))
+    }
+}
+
+/// Writes every event as one JSON object per line.
+pub struct NdjsonReporter<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> NdjsonReporter<W> {
+    pub fn new(writer: W) -> Self {
+        NdjsonReporter {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write + Send> NdjsonReporter<W> {
+    fn write(&self, event: Located) {
+        let mut writer = self.writer.lock().unwrap();
+        let written = serde_json::to_writer(&mut *writer, &event)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(writer))
+            .and_then(|_| writer.flush());
+        if let Err(e) = written {
+            log::warn!("Unable to write progress event. {e}");
+        }
+    }
+}
+
+impl<W: Write + Send> Reporter for NdjsonReporter<W> {
+    fn report(&self, event: &Event) {
+        self.write(Located {
+            vantage_point: None,
+            event,
+        });
+    }
+
+    fn report_at(&self, vantage_point: &str, event: &Event) {
+        self.write(Located {
+            vantage_point: Some(vantage_point),
+            event,
+        });
+    }
+}
+
+#[derive(Default)]
+struct TerminalState {
+    started: Option<Instant>,
+    /// Runs started but not finished, one per vantage point
+    running: usize,
+    total: usize,
+    done: usize,
+    failures: usize,
+    current: Option<String>,
+}
+
+/// Renders a single, continuously updated status line on a terminal.
+pub struct TerminalReporter<W> {
+    state: Mutex<(TerminalState, W)>,
+}
+
+impl<W: Write + Send> TerminalReporter<W> {
+    pub fn new(writer: W) -> Self {
+        TerminalReporter {
+            state: Mutex::new((TerminalState::default(), writer)),
+        }
+    }
+}
+
+impl TerminalState {
+    /// The remaining time, assuming the proxies left take as long as the ones done by `now`.
+    fn eta(&self, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(self.started?);
+        if self.done == 0 {
+            return None;
+        }
+        let remaining = self.total.saturating_sub(self.done) as u32;
+        Some(elapsed / self.done as u32 * remaining)
+    }
+
+    fn render(&self, now: Instant) -> String {
+        let eta = match self.eta(now) {
+            Some(eta) => format!("{:02}:{:02}", eta.as_secs() / 60, eta.as_secs() % 60),
+            None => "--:--".to_owned(),
+        };
+        format!(
+            "[{}/{}] {} | failures: {} | ETA {}",
+            self.done,
+            self.total,
+            self.current.as_deref().unwrap_or("-"),
+            self.failures,
+            eta
+        )
+    }
+}
+
+impl<W: Write + Send> TerminalReporter<W> {
+    fn update(&self, vantage_point: Option<&str>, event: &Event) {
+        let mut guard = self.state.lock().unwrap();
+        let (state, writer) = &mut *guard;
+        match event {
+            Event::RunStarted { proxies, .. } => {
+                state.started.get_or_insert_with(Instant::now);
+                state.running += 1;
+                state.total += *proxies;
+            }
+            Event::ProxySetup {
+                provider,
+                proxy,
+                error,
+                ..
+            } => {
+                state.current = Some(match vantage_point {
+                    Some(vantage_point) => format!("{vantage_point}: {provider}/{proxy}"),
+                    None => format!("{provider}/{proxy}"),
+                });
+                if error.is_some() {
+                    state.failures += 1;
+                }
+            }
+            Event::TestFinished { error, .. } => {
+                if error.is_some() {
+                    state.failures += 1;
+                }
+            }
+            Event::ProxyFinished { .. } => state.done += 1,
+            Event::RunFinished { .. } => state.running = state.running.saturating_sub(1),
+        }
+        // The line is only finished once every run is.
+        let finished = matches!(event, Event::RunFinished { .. }) && state.running == 0;
+        if finished {
+            state.current = None;
+        }
+        let end = if finished { "\n" } else { "" };
+        let written = write!(writer, "\r\x1b[2K{}{end}", state.render(Instant::now()))
+            .and_then(|_| writer.flush());
+        if let Err(e) = written {
+            log::warn!("Unable to write progress. {e}");
+        }
+    }
+}
+
+impl<W: Write + Send> Reporter for TerminalReporter<W> {
+    fn report(&self, event: &Event) {
+        self.update(None, event);
+    }
+
+    fn report_at(&self, vantage_point: &str, event: &Event) {
+        self.update(Some(vantage_point), event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn setup(proxy: &str, error: Option<&str>) -> Event {
+        Event::ProxySetup {
+            provider: "sub".to_owned(),
+            proxy: proxy.to_owned(),
+            attempts: 1,
+            error: error.map(str::to_owned),
+        }
+    }
+
+    fn finished(proxy: &str) -> Event {
+        Event::ProxyFinished {
+            provider: "sub".to_owned(),
+            proxy: proxy.to_owned(),
+        }
+    }
+
+    #[test]
+    fn estimates_the_remaining_time_from_the_proxies_done() {
+        let started = Instant::now();
+        let mut state = TerminalState {
+            started: Some(started),
+            total: 4,
+            ..Default::default()
+        };
+        let now = started + Duration::from_secs(30);
+        assert_eq!(state.eta(now), None);
+        assert!(state.render(now).ends_with("ETA --:--"));
+        state.done = 1;
+        assert_eq!(state.eta(now), Some(Duration::from_secs(90)));
+        state.current = Some("sub/a".to_owned());
+        assert_eq!(state.render(now), "[1/4] sub/a | failures: 0 | ETA 01:30");
+    }
+
+    #[test]
+    fn renders_a_status_line() {
+        let reporter = TerminalReporter::new(Vec::new());
+        reporter.report(&Event::RunStarted {
+            proxies: 2,
+            tests: 1,
+        });
+        reporter.report(&setup("a", Some("unsupported cipher")));
+        reporter.report(&finished("a"));
+        reporter.report(&Event::RunFinished { elapsed_ms: 10 });
+
+        let (state, output) = &*reporter.state.lock().unwrap();
+        assert_eq!((state.done, state.failures), (1, 1));
+        let output = String::from_utf8(output.clone()).unwrap();
+        let lines: Vec<_> = output.split("\r\x1b[2K").skip(1).collect();
+        assert_eq!(lines[0], "[0/2] - | failures: 0 | ETA --:--");
+        assert_eq!(lines[1], "[0/2] sub/a | failures: 1 | ETA --:--");
+        assert!(lines[2].starts_with("[1/2] sub/a | failures: 1 | ETA "));
+        assert!(lines[3].starts_with("[1/2] - | failures: 1 | ETA ") && lines[3].ends_with('\n'));
+    }
+
+    #[test]
+    fn writes_one_event_per_line() {
+        let reporter = NdjsonReporter::new(Vec::new());
+        reporter.report(&setup("a", None));
+        reporter.report(&Event::TestFinished {
+            provider: "sub".to_owned(),
+            proxy: "a".to_owned(),
+            test_provider: "tests".to_owned(),
+            test: "latency".to_owned(),
+            attempts: 2,
+            result: None,
+            error: Some("timed out".to_owned()),
+        });
+
+        let output = String::from_utf8(reporter.writer.into_inner().unwrap()).unwrap();
+        let events: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                json!({"event": "proxy_setup", "provider": "sub", "proxy": "a", "attempts": 1}),
+                json!({
+                    "event": "test_finished",
+                    "provider": "sub",
+                    "proxy": "a",
+                    "test_provider": "tests",
+                    "test": "latency",
+                    "attempts": 2,
+                    "error": "timed out",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn combines_the_runs_at_every_vantage_point() {
+        let reporter = TerminalReporter::new(Vec::new());
+        let started = Event::RunStarted {
+            proxies: 1,
+            tests: 1,
+        };
+        reporter.report_at("tokyo", &started);
+        reporter.report_at("paris", &started);
+        reporter.report_at("tokyo", &setup("a", None));
+        reporter.report_at("tokyo", &finished("a"));
+        reporter.report_at("tokyo", &Event::RunFinished { elapsed_ms: 10 });
+        {
+            let (state, output) = &*reporter.state.lock().unwrap();
+            assert_eq!((state.done, state.total), (1, 2));
+            let output = String::from_utf8(output.clone()).unwrap();
+            assert!(output.ends_with("[1/2] tokyo: sub/a | failures: 0 | ETA 00:00"));
+        }
+        reporter.report_at("paris", &Event::RunFinished { elapsed_ms: 10 });
+        let (_, output) = &*reporter.state.lock().unwrap();
+        assert!(String::from_utf8(output.clone()).unwrap().ends_with('\n'));
+
+        let reporter = NdjsonReporter::new(Vec::new());
+        reporter.report_at("tokyo", &finished("a"));
+        let output = String::from_utf8(reporter.writer.into_inner().unwrap()).unwrap();
+        let event: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            event,
+            json!({"vantage_point": "tokyo", "event": "proxy_finished", "provider": "sub", "proxy": "a"})
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+use crate::plugin::{PluginError, PluginErrorKind};
+
+/// How often a plugin call is attempted, and how long to wait in between.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the attempt following attempt number `attempt` (starting at 1).
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff =
+            self.initial_backoff_ms as f64 * self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        Duration::from_millis(backoff.min(self.max_backoff_ms as f64) as u64)
+    }
+}
+
+fn default_retry_on() -> Vec<PluginErrorKind> {
+    vec![
+        PluginErrorKind::Call,
+        PluginErrorKind::Transport,
+        PluginErrorKind::Timeout,
+    ]
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RetryConfig {
+    #[serde(default)]
+    pub setup: RetryPolicy,
+    /// Policy for every test without an entry in `tests`
+    #[serde(default)]
+    pub test: RetryPolicy,
+    /// Test name -> policy
+    #[serde(default)]
+    pub tests: HashMap<String, RetryPolicy>,
+    /// Error kinds that are worth another attempt
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<PluginErrorKind>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            setup: RetryPolicy::default(),
+            test: RetryPolicy::default(),
+            tests: HashMap::new(),
+            retry_on: default_retry_on(),
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn test_policy(&self, test: &str) -> &RetryPolicy {
+        self.tests.get(test).unwrap_or(&self.test)
+    }
+
+    /// Calls `f` until it succeeds, fails with an error that is not retryable, runs out of
+    /// attempts or `cancel` fires during a backoff.
+    ///
+    /// Returns the last result together with the number of attempts made.
+    pub async fn retry<T, F, Fut>(
+        &self,
+        policy: &RetryPolicy,
+        cancel: &CancellationToken,
+        mut f: F,
+    ) -> (Result<T, PluginError>, u32)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, PluginError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let result = f().await;
+            match &result {
+                Err(e) if attempt < policy.attempts && self.retry_on.contains(&e.kind()) => {
+                    log::warn!("Attempt {attempt}/{} failed. {e}", policy.attempts);
+                }
+                _ => return (result, attempt),
+            }
+            select! {
+                _ = cancel.cancelled() => return (result, attempt),
+                _ = tokio::time::sleep(policy.backoff(attempt)) => {},
+            }
+            attempt += 1;
+        }
+    }
+}
+
+/// Number of attempts it took to set up a proxy and to run each of its tests.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct ProxyAttempts {
+    pub setup: u32,
+    /// Test provider -> test name -> attempts
+    pub tests: HashMap<String, HashMap<String, u32>>,
+    /// Test providers that could not reach the proxy inside its network namespace, whose tests
+    /// do not count as failed
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub skipped: HashSet<String>,
+}
+
+/// Proxy provider -> proxy name -> attempts
+pub type AttemptMap = HashMap<String, HashMap<String, ProxyAttempts>>;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn failure() -> PluginError {
+        PluginError::ClientError(jsonrpsee::core::ClientError::RequestTimeout)
+    }
+
+    #[test]
+    fn backoff_grows_until_the_cap() {
+        let policy = RetryPolicy {
+            attempts: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 300,
+            multiplier: 2.0,
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let config = RetryConfig::default();
+        let policy = RetryPolicy {
+            attempts: 3,
+            initial_backoff_ms: 1,
+            ..Default::default()
+        };
+        let calls = AtomicU32::new(0);
+        let (result, attempts) = config
+            .retry(&policy, &CancellationToken::new(), || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(failure()),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_other_kinds() {
+        let config = RetryConfig {
+            retry_on: vec![PluginErrorKind::Call],
+            ..Default::default()
+        };
+        let policy = RetryPolicy {
+            attempts: 3,
+            initial_backoff_ms: 1,
+            ..Default::default()
+        };
+        let (result, attempts) = config
+            .retry(&policy, &CancellationToken::new(), || async {
+                Err::<(), _>(failure())
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,770 @@
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use futures::stream;
+use futures::Future;
+use futures::FutureExt;
+use futures::StreamExt;
+use serde_json::Value;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+use crate::checkpoint::{Checkpoint, CheckpointWriter};
+use crate::netns::{IsolationConfig, Namespace};
+use crate::plugin::{ConnectionDescriptor, Plugin, PluginError, TestDescriptor};
+use crate::progress::{Event, Reporter};
+use crate::retry::{AttemptMap, ProxyAttempts, RetryConfig};
+use crate::speedtest::{ProviderResults, ProxyResults, RunResults, TestProviderMap, TestResults};
+use crate::transform::{PlannedProxy, ProxyRouter, SetupPlan};
+
+type TestFuture = Pin<Box<dyn Future<Output = Option<(String, Value)>>>>;
+
+/// (provider, proxy) -> held while the proxy is set up
+pub type ProxyLocks = Mutex<HashMap<(String, String), Arc<tokio::sync::Mutex<()>>>>;
+
+/// State shared by everything scheduled in one run.
+pub struct RunContext {
+    pub reporter: Arc<dyn Reporter>,
+    pub cancel: CancellationToken,
+    /// Results restored from a checkpoint, which are not run again.
+    pub resumed: Checkpoint,
+    pub retry: RetryConfig,
+    pub attempts: Mutex<AttemptMap>,
+    pub router: ProxyRouter,
+    /// Test providers able to test through a TUN device
+    pub tun_test_providers: HashSet<String>,
+    pub isolation: IsolationConfig,
+    /// Proxy backends on this host able to set up proxies inside a given network namespace
+    pub netns_backends: HashSet<String>,
+    /// Keeps a rerun from setting up a proxy the run is still testing
+    pub proxy_locks: ProxyLocks,
+    /// Saves what completes, unless nothing is checkpointed
+    pub checkpoint: Option<Arc<CheckpointWriter>>,
+    /// The vantage point the run is at, unless it runs here
+    pub vantage_point: Option<String>,
+}
+
+impl RunContext {
+    /// Reports an event of the run, naming its vantage point.
+    fn report(&self, event: &Event) {
+        match &self.vantage_point {
+            Some(vantage_point) => self.reporter.report_at(vantage_point, event),
+            None => self.reporter.report(event),
+        }
+    }
+
+    /// Records an outcome in the checkpoint, saving it if `save`.
+    fn checkpoint(&self, save: bool, record: impl FnOnce(&mut Checkpoint)) {
+        let Some(writer) = &self.checkpoint else {
+            return;
+        };
+        if let Err(e) = writer.record(self.vantage_point.as_deref(), save, record) {
+            log::warn!("Unable to save the checkpoint. {e}");
+        }
+    }
+
+    fn proxy_lock(&self, provider: &str, proxy: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.proxy_locks.lock().unwrap();
+        let key = (provider.to_owned(), proxy.to_owned());
+        locks.entry(key).or_default().clone()
+    }
+
+    fn record_attempts(
+        &self,
+        provider: &str,
+        proxy: &str,
+        record: impl FnOnce(&mut ProxyAttempts),
+    ) {
+        let mut attempts = self.attempts.lock().unwrap();
+        record(
+            attempts
+                .entry(provider.to_owned())
+                .or_default()
+                .entry(proxy.to_owned())
+                .or_default(),
+        );
+    }
+}
+
+/// Identifies the proxy under test in progress events.
+#[derive(Clone)]
+struct ProxyContext {
+    provider: String,
+    proxy: String,
+    run: Arc<RunContext>,
+    /// Runs again what the checkpoint has already completed
+    rerun: bool,
+}
+
+async fn collect_test_results(
+    context: ProxyContext,
+    test_provider: String,
+    plugin: Arc<dyn Plugin>,
+    proxy_connection: ConnectionDescriptor,
+    tests: Vec<TestDescriptor>,
+) -> TestResults {
+    let tests: Vec<_> = tests
+        .into_iter()
+        .filter(|test| {
+            context.rerun
+                || !context.run.resumed.is_completed(
+                    &context.provider,
+                    &context.proxy,
+                    &test_provider,
+                    &test.name,
+                )
+        })
+        .collect();
+    let run_test_future = try_run_test(context, test_provider, plugin, proxy_connection);
+    stream::iter(tests)
+        .filter_map(run_test_future)
+        .collect::<HashMap<_, _>>()
+        .await
+}
+
+async fn collect_test_results_from_test_providers(
+    context: ProxyContext,
+    test_providers: TestProviderMap,
+    proxy_connection: ConnectionDescriptor,
+) -> ProxyResults {
+    // A proxy only reachable through TUN, or from inside its namespace, cannot be tested by
+    // plugins that need a local port.
+    let tun_only = proxy_connection.netns.is_some()
+        || (proxy_connection.uses_tun()
+            && proxy_connection.http.is_none()
+            && proxy_connection.socks5.is_none());
+    let tun_test_providers = context.run.tun_test_providers.clone();
+    let skipping = context.clone();
+    stream::iter(test_providers)
+        .filter(move |(test_provider, _)| {
+            let usable = !tun_only || tun_test_providers.contains(test_provider);
+            if !usable {
+                log::warn!(
+                    "Test provider {test_provider} cannot reach {} without TUN, skipped",
+                    skipping.proxy
+                );
+                skipping
+                    .run
+                    .record_attempts(&skipping.provider, &skipping.proxy, |record| {
+                        record.skipped.insert(test_provider.clone());
+                    });
+            }
+            async move { usable }
+        })
+        .then(|(test_provider, (plugin, tests))| {
+            let proxy_connection: ConnectionDescriptor = proxy_connection.clone();
+            let context = context.clone();
+            async move {
+                (
+                    test_provider.clone(),
+                    collect_test_results(context, test_provider, plugin, proxy_connection, tests)
+                        .await,
+                )
+            }
+        })
+        .collect()
+        .await
+}
+
+async fn perform_speedtest_for_proxies(
+    provider: String,
+    proxies: Vec<PlannedProxy>,
+    test_providers: TestProviderMap,
+    run: Arc<RunContext>,
+    rerun: bool,
+) -> ProviderResults {
+    stream::iter(proxies)
+        .filter_map(|proxy| {
+            let context = ProxyContext {
+                provider: provider.clone(),
+                proxy: proxy.name.clone(),
+                run: run.clone(),
+                rerun,
+            };
+            let test_providers = test_providers.clone();
+            async move {
+                let lock = context.run.proxy_lock(&context.provider, &context.proxy);
+                let _guard = select! {
+                    biased;
+                    _ = context.run.cancel.cancelled() => return None,
+                    guard = lock.lock() => guard,
+                };
+                let results = if !context.rerun
+                    && context.run.resumed.is_proxy_completed(
+                        &context.provider,
+                        &context.proxy,
+                        &test_providers,
+                    ) {
+                    None
+                } else {
+                    match try_set_up_proxy(context.clone(), proxy).await {
+                        None => {
+                            // An interrupted setup is tried again by a resumed run.
+                            if !context.run.cancel.is_cancelled() {
+                                context.run.checkpoint(true, |checkpoint| {
+                                    checkpoint
+                                        .record_failed_setup(&context.provider, &context.proxy)
+                                });
+                            }
+                            None
+                        }
+                        Some((plugin, proxy_connection, namespace)) => {
+                            let results = collect_test_results_from_test_providers(
+                                context.clone(),
+                                test_providers,
+                                proxy_connection.clone(),
+                            )
+                            .await;
+                            if let Err(e) = plugin.teardown_proxy(&proxy_connection).await {
+                                log::debug!("Cannot tear down proxy {}. {e}", context.proxy);
+                            }
+                            drop(namespace);
+                            context.run.checkpoint(true, |checkpoint| {
+                                checkpoint.record_results(
+                                    &context.provider,
+                                    &context.proxy,
+                                    &results,
+                                )
+                            });
+                            Some((context.proxy.clone(), results))
+                        }
+                    }
+                };
+                context.run.report(&Event::ProxyFinished {
+                    provider: context.provider,
+                    proxy: context.proxy,
+                });
+                results
+            }
+        })
+        .collect()
+        .await
+}
+
+pub async fn perform_speedtest_for_proxy_providers(
+    plan: SetupPlan,
+    test_providers: TestProviderMap,
+    run: Arc<RunContext>,
+) -> RunResults {
+    run_plan(plan, test_providers, run, false).await
+}
+
+/// Runs the plan again, including what the checkpoint the run resumed has completed.
+///
+/// A proxy still being tested is only set up again once it is torn down.
+pub async fn rerun(
+    plan: SetupPlan,
+    test_providers: TestProviderMap,
+    run: Arc<RunContext>,
+) -> RunResults {
+    run_plan(plan, test_providers, run, true).await
+}
+
+async fn run_plan(
+    plan: SetupPlan,
+    test_providers: TestProviderMap,
+    run: Arc<RunContext>,
+    rerun: bool,
+) -> RunResults {
+    stream::iter(plan)
+        .then(|(provider, proxies)| {
+            let test_providers = test_providers.clone();
+            let run = run.clone();
+            async move {
+                (
+                    provider.clone(),
+                    perform_speedtest_for_proxies(provider, proxies, test_providers, run, rerun)
+                        .await,
+                )
+            }
+        })
+        .collect()
+        .await
+}
+
+fn try_run_test(
+    context: ProxyContext,
+    test_provider: String,
+    plugin: Arc<dyn Plugin>,
+    proxy_connection: ConnectionDescriptor,
+) -> impl Fn(TestDescriptor) -> TestFuture {
+    move |test| {
+        let plugin = plugin.clone();
+        let proxy_connection = proxy_connection.clone();
+        let context = context.clone();
+        let test_provider = test_provider.clone();
+        async move {
+            let run = &context.run;
+            let policy = run.retry.test_policy(&test.name);
+            let (test_result, attempts): (Result<Value, PluginError>, _) = select! {
+                // Interrupted tests are left out so that a resumed run picks them up again.
+                _ = run.cancel.cancelled() => return None,
+                result = run.retry.retry(policy, &run.cancel, || {
+                    plugin.run_test(&test, &proxy_connection)
+                }) => result,
+            };
+            run.record_attempts(&context.provider, &context.proxy, |record| {
+                record
+                    .tests
+                    .entry(test_provider.clone())
+                    .or_default()
+                    .insert(test.name.clone(), attempts);
+            });
+            let (result, error) = match &test_result {
+                Ok(p) => (Some(p.clone()), None),
+                Err(e) => {
+                    log::error!("Failed to run test {test:?} given {proxy_connection:?}. {e}");
+                    let error = e.to_string();
+                    run.checkpoint(false, |checkpoint| {
+                        checkpoint.record_failed_test(
+                            &context.provider,
+                            &context.proxy,
+                            &test_provider,
+                            &test.name,
+                            &error,
+                        )
+                    });
+                    (None, Some(error))
+                }
+            };
+            context.run.report(&Event::TestFinished {
+                provider: context.provider,
+                proxy: context.proxy,
+                test_provider,
+                test: test.name.clone(),
+                attempts,
+                result,
+                error,
+            });
+            test_result.ok().map(|p| (test.name, p))
+        }
+        .boxed()
+    }
+}
+
+/// Sets up the proxy on the plugin it is routed to, returning that plugin with the connection,
+/// and the namespace the proxy is isolated in, which lives until the proxy is torn down.
+async fn try_set_up_proxy(
+    context: ProxyContext,
+    proxy: PlannedProxy,
+) -> Option<(Arc<dyn Plugin>, ConnectionDescriptor, Option<Namespace>)> {
+    let run = &context.run;
+    let report_failure = |error: String| {
+        run.report(&Event::ProxySetup {
+            provider: context.provider.clone(),
+            proxy: context.proxy.clone(),
+            attempts: 0,
+            error: Some(error),
+        });
+    };
+    let routed = match run.router.route(&context.provider, &proxy).await {
+        Ok(routed) => routed,
+        Err(e) => {
+            log::error!("Cannot route proxy {}. {e}", context.proxy);
+            report_failure(e.to_string());
+            return None;
+        }
+    };
+    let isolated = run.isolation.enabled && run.netns_backends.contains(&routed.backend);
+    if run.isolation.enabled && !isolated {
+        log::warn!(
+            "Backend {} cannot set up proxies in a network namespace, {} is not isolated",
+            routed.backend,
+            context.proxy
+        );
+    }
+    let namespace = if isolated {
+        match Namespace::create(&run.isolation).await {
+            Ok(namespace) => Some(namespace),
+            Err(e) => {
+                log::error!(
+                    "Cannot create a network namespace for {}. {e}",
+                    context.proxy
+                );
+                report_failure(format!("Cannot create a network namespace. {e}"));
+                return None;
+            }
+        }
+    } else {
+        None
+    };
+    let plugin = routed.plugin;
+    let (proxy_connection, attempts) = run
+        .retry
+        .retry(&run.retry.setup, &run.cancel, || async {
+            match &namespace {
+                Some(namespace) => {
+                    let mut connection = plugin
+                        .setup_proxy_in_netns(routed.content.clone(), namespace.path())
+                        .await?;
+                    connection
+                        .netns
+                        .get_or_insert_with(|| namespace.path().to_owned());
+                    Ok(connection)
+                }
+                None => plugin.setup_proxy(routed.content.clone()).await,
+            }
+        })
+        .await;
+    run.record_attempts(&context.provider, &context.proxy, |record| {
+        record.setup = attempts
+    });
+    let error = proxy_connection.as_ref().err().map(|e| e.to_string());
+    run.report(&Event::ProxySetup {
+        provider: context.provider.clone(),
+        proxy: context.proxy.clone(),
+        attempts,
+        error,
+    });
+    match proxy_connection {
+        Err(e) => {
+            log::error!("Cannot setup proxy. {e}");
+            None
+        }
+        Ok(proxy_connection) => Some((plugin, proxy_connection, namespace)),
+    }
+}
+
+pub fn merge_results(into: &mut RunResults, from: RunResults) {
+    for (provider, proxies) in from {
+        let provider = into.entry(provider).or_default();
+        for (proxy, test_providers) in proxies {
+            let proxy = provider.entry(proxy).or_default();
+            for (test_provider, tests) in test_providers {
+                proxy.entry(test_provider).or_default().extend(tests);
+            }
+        }
+    }
+}
+
+/// Runs the whole plan, reporting the start and the end of the run.
+pub async fn run_with_reporter(
+    plan: SetupPlan,
+    test_providers: TestProviderMap,
+    run: Arc<RunContext>,
+) -> RunResults {
+    run.report(&Event::RunStarted {
+        proxies: plan.values().map(Vec::len).sum(),
+        tests: test_providers.values().map(|(_, tests)| tests.len()).sum(),
+    });
+    let started = std::time::Instant::now();
+    let results = perform_speedtest_for_proxy_providers(plan, test_providers, run.clone()).await;
+    run.report(&Event::RunFinished {
+        elapsed_ms: started.elapsed().as_millis(),
+    });
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock::{self, MockScript, MockServer};
+    use crate::plugin::json_rpc::JSONRPCPlugin;
+    use crate::retry::RetryPolicy;
+    use crate::speedtest::{PluginMap, SpeedTest};
+    use crate::transform::SetupConfig;
+
+    #[derive(Default)]
+    struct CollectingReporter(Mutex<Vec<Event>>);
+
+    impl Reporter for CollectingReporter {
+        fn report(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    async fn start(script: serde_json::Value) -> (MockServer, SpeedTest) {
+        let script: MockScript = serde_json::from_value(script).unwrap();
+        let server = mock::serve(script).await.unwrap();
+        let plugin = JSONRPCPlugin::new(&server.addr.to_string(), Value::Null)
+            .await
+            .unwrap();
+        let plugins: PluginMap = HashMap::from([("mock".to_owned(), Arc::new(plugin) as _)]);
+        (server, SpeedTest::from_plugins(plugins).await)
+    }
+
+    /// The plan of the `mock://` proxies, with the context to run it in.
+    async fn prepare(
+        speedtest: &SpeedTest,
+        retry: RetryConfig,
+        cancel: CancellationToken,
+        resumed: Checkpoint,
+    ) -> (
+        SetupPlan,
+        TestProviderMap,
+        Arc<RunContext>,
+        Arc<CollectingReporter>,
+    ) {
+        let proxy_providers = speedtest.get_proxy_provider("mock://").await;
+        let test_providers = speedtest.get_test_provider().await;
+        let router = speedtest.get_proxy_router().await;
+        let plan = router.plan(&proxy_providers, &SetupConfig::default());
+        let reporter = Arc::new(CollectingReporter::default());
+        let run = Arc::new(RunContext {
+            reporter: reporter.clone(),
+            cancel,
+            resumed,
+            retry,
+            attempts: Default::default(),
+            router,
+            tun_test_providers: HashSet::new(),
+            isolation: Default::default(),
+            netns_backends: HashSet::new(),
+            proxy_locks: Default::default(),
+            checkpoint: None,
+            vantage_point: None,
+        });
+        (plan, test_providers, run, reporter)
+    }
+
+    async fn run(
+        speedtest: &SpeedTest,
+        retry: RetryConfig,
+        cancel: CancellationToken,
+    ) -> (RunResults, Arc<RunContext>, Arc<CollectingReporter>) {
+        let (plan, test_providers, run, reporter) =
+            prepare(speedtest, retry, cancel, Checkpoint::default()).await;
+        let results = run_with_reporter(plan, test_providers, run.clone()).await;
+        (results, run, reporter)
+    }
+
+    #[tokio::test]
+    async fn runs_every_test_on_every_proxy() {
+        let (server, speedtest) = start(json!({
+            "proxies": [
+                {"name": "a", "content": "a"},
+                {"name": "b", "content": "b", "setup": {"error": "unsupported cipher"}},
+                {"name": "c", "content": "c"},
+            ],
+            "tests": [
+                {"name": "latency", "response": {"result": 100}, "proxies": {"c": {"result": 300}}},
+                {"name": "download", "proxies": {"c": {"error": "connection reset"}}},
+            ],
+        }))
+        .await;
+        let (results, _, reporter) =
+            run(&speedtest, RetryConfig::default(), CancellationToken::new()).await;
+
+        let proxies = &results["mock"];
+        assert_eq!(proxies.len(), 2, "b failed to set up");
+        assert_eq!(proxies["a"]["mock"]["latency"], 100);
+        assert_eq!(proxies["c"]["mock"]["latency"], 300);
+        assert!(!proxies["c"]["mock"].contains_key("download"));
+        let calls = server.calls();
+        assert!(calls.contains(&"teardown_proxy:a".to_owned()));
+        assert!(calls.contains(&"teardown_proxy:c".to_owned()));
+
+        let events = reporter.0.lock().unwrap();
+        let failed_setups = events.iter().filter(
+            |e| matches!(e, Event::ProxySetup { proxy, error: Some(_), .. } if proxy == "b"),
+        );
+        assert_eq!(failed_setups.count(), 1);
+        assert!(matches!(events.last(), Some(Event::RunFinished { .. })));
+    }
+
+    #[tokio::test]
+    async fn skips_test_providers_that_cannot_reach_the_namespace() {
+        let connection = json!({"http": null, "socks5": null, "tun": false, "netns": "isolated"});
+        let (_server, speedtest) = start(json!({
+            "proxies": [{"name": "a", "content": "a", "setup": {"result": connection}}],
+            "tests": [{"name": "latency", "response": {"result": 100}}],
+        }))
+        .await;
+        let (results, run, _) =
+            run(&speedtest, RetryConfig::default(), CancellationToken::new()).await;
+
+        assert!(results["mock"]["a"].is_empty());
+        let attempts = &run.attempts.lock().unwrap()["mock"]["a"];
+        assert_eq!(attempts.skipped, HashSet::from(["mock".to_owned()]));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let (_server, speedtest) = start(json!({
+            "proxies": [{"name": "a", "content": "a", "setup": {"fail_times": 1}}],
+            "tests": [{"name": "latency", "response": {"result": 100, "fail_times": 2}}],
+        }))
+        .await;
+        let policy = RetryPolicy {
+            attempts: 3,
+            initial_backoff_ms: 1,
+            ..Default::default()
+        };
+        let retry = RetryConfig {
+            setup: policy.clone(),
+            test: policy,
+            ..Default::default()
+        };
+        let (results, run, _) = run(&speedtest, retry, CancellationToken::new()).await;
+
+        assert_eq!(results["mock"]["a"]["mock"]["latency"], 100);
+        let attempts = &run.attempts.lock().unwrap()["mock"]["a"];
+        assert_eq!(attempts.setup, 2);
+        assert_eq!(attempts.tests["mock"]["latency"], 3);
+    }
+
+    #[tokio::test]
+    async fn transforms_proxies_the_backend_cannot_set_up() {
+        let (server, speedtest) = start(json!({
+            "metadata": {
+                "name": "mock",
+                "capabilities": {
+                    "parser": true,
+                    "proxy_backend": true,
+                    "test_provider": true,
+                    "transformer": true,
+                },
+            },
+            "proxies": [
+                {"name": "a", "content": "a", "scheme": "clash"},
+                {"name": "b", "content": "b", "scheme": "sing-box"},
+            ],
+            "tests": [{"name": "latency", "response": {"result": 100}}],
+            "proxy_schemes": ["sing-box"],
+            "data_transforms": [
+                {"name": "clash-to-sing-box", "accpeted_scheme": "clash", "produced_scheme": "sing-box"},
+            ],
+        }))
+        .await;
+        let (results, _, _) =
+            run(&speedtest, RetryConfig::default(), CancellationToken::new()).await;
+
+        assert_eq!(results["mock"]["a"]["mock"]["latency"], 100);
+        assert_eq!(results["mock"]["b"]["mock"]["latency"], 100);
+        let calls = server.calls();
+        let transformed: Vec<_> = calls
+            .iter()
+            .filter(|c| c.starts_with("transform:"))
+            .collect();
+        assert_eq!(transformed, ["transform:a"]);
+    }
+
+    #[tokio::test]
+    async fn cancelling_interrupts_hung_tests() {
+        let (_server, speedtest) = start(json!({
+            "proxies": [{"name": "a", "content": "a"}],
+            "tests": [
+                {"name": "latency", "response": {"result": 100, "delay_ms": 10}},
+                {"name": "download", "response": {"hang": true}},
+            ],
+        }))
+        .await;
+        let cancel = CancellationToken::new();
+        let timer = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            timer.cancel();
+        });
+        let (results, _, _) = run(&speedtest, RetryConfig::default(), cancel).await;
+
+        let tests = &results["mock"]["a"]["mock"];
+        assert!(!tests.contains_key("download"));
+    }
+
+    #[tokio::test]
+    async fn reruns_one_at_a_time_ignoring_the_checkpoint() {
+        let (server, speedtest) = start(json!({
+            "proxies": [{"name": "a", "content": "a", "setup": {"delay_ms": 20}}],
+            "tests": [{"name": "latency", "response": {"result": 100, "delay_ms": 20}}],
+        }))
+        .await;
+        let resumed = serde_json::from_value(json!({
+            "test_results": {"mock": {"a": {"mock": {"latency": 90}}}},
+        }))
+        .unwrap();
+        let (plan, test_providers, run, _) = prepare(
+            &speedtest,
+            RetryConfig::default(),
+            CancellationToken::new(),
+            resumed,
+        )
+        .await;
+        let (resumed, first, second) = tokio::join!(
+            perform_speedtest_for_proxy_providers(
+                plan.clone(),
+                test_providers.clone(),
+                run.clone()
+            ),
+            rerun(plan.clone(), test_providers.clone(), run.clone()),
+            rerun(plan, test_providers, run),
+        );
+
+        assert!(resumed["mock"].is_empty(), "a completed before");
+        assert_eq!(first["mock"]["a"]["mock"]["latency"], 100);
+        assert_eq!(second["mock"]["a"]["mock"]["latency"], 100);
+        let calls: Vec<_> = server
+            .calls()
+            .into_iter()
+            .filter(|call| call.ends_with("_proxy:a"))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                "setup_proxy:a",
+                "teardown_proxy:a",
+                "setup_proxy:a",
+                "teardown_proxy:a"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn checkpoints_every_proxy_including_failures() {
+        let script = json!({
+            "proxies": [
+                {"name": "a", "content": "a"},
+                {"name": "b", "content": "b", "setup": {"error": "unsupported cipher"}},
+            ],
+            "tests": [
+                {"name": "latency", "response": {"result": 100}},
+                {"name": "download", "response": {"error": "connection reset"}},
+            ],
+        });
+        let path = std::env::temp_dir().join(format!("run-{}.checkpoint", std::process::id()));
+        let (_server, speedtest) = start(script.clone()).await;
+        let (plan, test_providers, run, _) = prepare(
+            &speedtest,
+            RetryConfig::default(),
+            CancellationToken::new(),
+            Checkpoint::default(),
+        )
+        .await;
+        let run = Arc::new(RunContext {
+            checkpoint: Some(Arc::new(CheckpointWriter::new(
+                path.clone(),
+                Checkpoint::default(),
+            ))),
+            ..Arc::into_inner(run).unwrap()
+        });
+        perform_speedtest_for_proxy_providers(plan, test_providers, run).await;
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(checkpoint.test_results["mock"]["a"]["mock"]["latency"], 100);
+        assert!(checkpoint.failed_tests["mock"]["a"]["mock"].contains_key("download"));
+        assert!(checkpoint.failed_setups["mock"].contains("b"));
+
+        let (server, speedtest) = start(script).await;
+        let (plan, test_providers, run, _) = prepare(
+            &speedtest,
+            RetryConfig::default(),
+            CancellationToken::new(),
+            checkpoint,
+        )
+        .await;
+        perform_speedtest_for_proxy_providers(plan, test_providers, run).await;
+        assert!(
+            !server
+                .calls()
+                .iter()
+                .any(|call| call.starts_with("setup_proxy")),
+            "{:?}",
+            server.calls()
+        );
+    }
+}
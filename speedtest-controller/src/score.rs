@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::retry::AttemptMap;
+use crate::speedtest::{ProxyResults, RunResults};
+
+/// Whether larger or smaller values of a metric are better.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    #[default]
+    Higher,
+    Lower,
+}
+
+/// Describes how a metric is extracted from the test results and how it counts towards the score.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MetricConfig {
+    /// The test whose result holds the metric
+    pub test: String,
+    /// Restricts the lookup to one test provider, otherwise the first provider with the test is used
+    #[serde(default)]
+    pub test_provider: Option<String>,
+    /// JSON pointer into the test result, e.g. `/latency_ms`. The whole result is used when unset
+    #[serde(default)]
+    pub pointer: Option<String>,
+    /// Must not be negative
+    #[serde(default = "default_weight", deserialize_with = "non_negative")]
+    pub weight: f64,
+    #[serde(default)]
+    pub direction: Direction,
+    /// Proxies below this value fail
+    #[serde(default)]
+    pub min: Option<f64>,
+    /// Proxies above this value fail
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+/// Rejects weights that could add up to zero while not all being zero.
+fn non_negative<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let weight = f64::deserialize(deserializer)?;
+    if weight.is_finite() && weight >= 0.0 {
+        Ok(weight)
+    } else {
+        Err(D::Error::custom(format!(
+            "weight {weight} is not a non-negative number"
+        )))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ScoringConfig {
+    /// Metric name -> metric
+    #[serde(default)]
+    pub metrics: HashMap<String, MetricConfig>,
+    /// Subtracted from the score for every test the proxy failed
+    #[serde(default)]
+    pub failure_penalty: f64,
+}
+
+/// A proxy with its score, in the order of the ranking.
+#[derive(Debug, Serialize, Clone)]
+pub struct RankedProxy {
+    pub provider: String,
+    pub proxy: String,
+    pub score: f64,
+    /// `false` if the proxy could not be set up, misses a metric or violates a threshold
+    pub passed: bool,
+    /// Metric name -> raw value
+    pub metrics: HashMap<String, f64>,
+    pub failures: usize,
+    pub violations: Vec<String>,
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+impl MetricConfig {
+    fn extract(&self, results: &ProxyResults) -> Option<f64> {
+        let value = match &self.test_provider {
+            Some(test_provider) => results.get(test_provider)?.get(&self.test)?,
+            None => results.values().find_map(|tests| tests.get(&self.test))?,
+        };
+        match &self.pointer {
+            Some(pointer) => as_number(value.pointer(pointer)?),
+            None => as_number(value),
+        }
+    }
+}
+
+impl ScoringConfig {
+    /// Scores and ranks `proxies`, best first. Proxies that did not pass come last.
+    ///
+    /// * `proxies` - every (provider, proxy) that was tested, including those which could not be set up
+    /// * `tests` - every (test provider, test) that was expected to run on each proxy
+    /// * `attempts` - the attempts of the run, whose skipped test providers are not failures
+    pub fn rank(
+        &self,
+        results: &RunResults,
+        proxies: &[(String, String)],
+        tests: &[(String, String)],
+        attempts: &AttemptMap,
+    ) -> Vec<RankedProxy> {
+        let mut ranked: Vec<RankedProxy> = proxies
+            .iter()
+            .map(|(provider, proxy)| {
+                let proxy_results = results.get(provider).and_then(|p| p.get(proxy));
+                let mut ranked = RankedProxy {
+                    provider: provider.clone(),
+                    proxy: proxy.clone(),
+                    score: 0.0,
+                    passed: proxy_results.is_some(),
+                    metrics: HashMap::new(),
+                    failures: 0,
+                    violations: vec![],
+                };
+                let Some(proxy_results) = proxy_results else {
+                    ranked.failures = tests.len().max(1);
+                    ranked.violations.push("setup failed".to_owned());
+                    return ranked;
+                };
+                let skipped = attempts
+                    .get(provider)
+                    .and_then(|p| p.get(proxy))
+                    .map(|attempts| &attempts.skipped);
+                ranked.failures = tests
+                    .iter()
+                    .filter(|(test_provider, test)| {
+                        !skipped.is_some_and(|skipped| skipped.contains(test_provider))
+                            && !proxy_results
+                                .get(test_provider)
+                                .is_some_and(|tests| tests.contains_key(test))
+                    })
+                    .count();
+                for (name, metric) in &self.metrics {
+                    let Some(value) = metric.extract(proxy_results) else {
+                        ranked.passed = false;
+                        ranked.violations.push(format!("{name} is missing"));
+                        continue;
+                    };
+                    if metric.min.is_some_and(|min| value < min) {
+                        ranked.passed = false;
+                        ranked
+                            .violations
+                            .push(format!("{name} {value} is below the minimum"));
+                    }
+                    if metric.max.is_some_and(|max| value > max) {
+                        ranked.passed = false;
+                        ranked
+                            .violations
+                            .push(format!("{name} {value} is above the maximum"));
+                    }
+                    ranked.metrics.insert(name.clone(), value);
+                }
+                ranked
+            })
+            .collect();
+
+        // When every weight is zero, the metrics count equally instead of dividing by zero.
+        let equal_weights = self.metrics.values().all(|m| m.weight == 0.0);
+        let weight = |metric: &MetricConfig| if equal_weights { 1.0 } else { metric.weight };
+        let total_weight: f64 = self.metrics.values().map(weight).sum();
+        for (name, metric) in &self.metrics {
+            // A proxy that failed a threshold would squeeze the range of the passing ones.
+            let values = ranked
+                .iter()
+                .filter(|r| r.passed)
+                .filter_map(|r| r.metrics.get(name));
+            let (low, high) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(l, h), v| {
+                (l.min(*v), h.max(*v))
+            });
+            for r in ranked.iter_mut() {
+                let Some(value) = r.metrics.get(name) else {
+                    continue;
+                };
+                // Min-max normalisation to [0, 1], where 1 is the best proxy.
+                let normalised = if high > low {
+                    ((value - low) / (high - low)).clamp(0.0, 1.0)
+                } else {
+                    1.0
+                };
+                let normalised = match metric.direction {
+                    Direction::Higher => normalised,
+                    Direction::Lower => 1.0 - normalised,
+                };
+                r.score += weight(metric) * normalised / total_weight;
+            }
+        }
+        for r in ranked.iter_mut() {
+            r.score -= self.failure_penalty * r.failures as f64;
+        }
+
+        ranked.sort_by(|a, b| {
+            b.passed
+                .cmp(&a.passed)
+                .then(b.score.total_cmp(&a.score))
+                .then_with(|| (&a.provider, &a.proxy).cmp(&(&b.provider, &b.proxy)))
+        });
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn results(latencies: &[(&str, Value)]) -> RunResults {
+        let proxies = latencies
+            .iter()
+            .map(|(proxy, latency)| {
+                let tests = HashMap::from([("latency".to_owned(), latency.clone())]);
+                (
+                    proxy.to_string(),
+                    HashMap::from([("ping".to_owned(), tests)]),
+                )
+            })
+            .collect();
+        HashMap::from([("sub".to_owned(), proxies)])
+    }
+
+    fn scoring() -> ScoringConfig {
+        ScoringConfig {
+            metrics: HashMap::from([(
+                "latency".to_owned(),
+                MetricConfig {
+                    test: "latency".to_owned(),
+                    test_provider: None,
+                    pointer: Some("/ms".to_owned()),
+                    weight: 1.0,
+                    direction: Direction::Lower,
+                    min: None,
+                    max: Some(500.0),
+                },
+            )]),
+            failure_penalty: 1.0,
+        }
+    }
+
+    #[test]
+    fn ranks_by_normalised_metric() {
+        let results = results(&[
+            ("slow", json!({"ms": 300})),
+            ("fast", json!({"ms": 100})),
+            ("mid", json!({"ms": 200})),
+            ("too-slow", json!({"ms": 900})),
+        ]);
+        let proxies: Vec<_> = ["slow", "fast", "mid", "too-slow", "broken"]
+            .iter()
+            .map(|p| ("sub".to_owned(), p.to_string()))
+            .collect();
+        let tests = [("ping".to_owned(), "latency".to_owned())];
+        let ranked = scoring().rank(&results, &proxies, &tests, &AttemptMap::new());
+
+        let order: Vec<_> = ranked.iter().map(|r| r.proxy.as_str()).collect();
+        assert_eq!(order, ["fast", "mid", "slow", "too-slow", "broken"]);
+        // The range is that of the passing proxies, without the one above the maximum.
+        assert_eq!(ranked[0].score, 1.0);
+        assert_eq!(ranked[1].score, 0.5);
+        assert_eq!(ranked[2].score, 0.0);
+        assert!(!ranked[3].passed);
+        assert_eq!(ranked[3].score, 0.0);
+        assert_eq!(ranked[4].failures, 1);
+        assert_eq!(ranked[4].score, -1.0);
+    }
+
+    #[test]
+    fn falls_back_to_equal_weights_when_every_weight_is_zero() {
+        let results = results(&[("slow", json!({"ms": 300})), ("fast", json!({"ms": 100}))]);
+        let proxies: Vec<_> = ["slow", "fast"]
+            .iter()
+            .map(|p| ("sub".to_owned(), p.to_string()))
+            .collect();
+        let mut scoring = scoring();
+        scoring.metrics.get_mut("latency").unwrap().weight = 0.0;
+        let ranked = scoring.rank(&results, &proxies, &[], &AttemptMap::new());
+
+        let order: Vec<_> = ranked.iter().map(|r| r.proxy.as_str()).collect();
+        assert_eq!(order, ["fast", "slow"]);
+        assert_eq!(ranked[0].score, 1.0);
+        assert_eq!(ranked[1].score, 0.0);
+    }
+
+    #[test]
+    fn rejects_negative_weights() {
+        let metric = |weight: f64| {
+            serde_json::from_value::<MetricConfig>(json!({"test": "latency", "weight": weight}))
+        };
+        assert_eq!(metric(2.0).unwrap().weight, 2.0);
+        assert!(metric(-1.0).is_err());
+    }
+
+    #[test]
+    fn fails_proxies_missing_a_metric() {
+        let results = results(&[("fast", json!({"ms": 100})), ("odd", json!({"bytes": 5}))]);
+        let proxies: Vec<_> = ["odd", "fast"]
+            .iter()
+            .map(|p| ("sub".to_owned(), p.to_string()))
+            .collect();
+        let ranked = scoring().rank(&results, &proxies, &[], &AttemptMap::new());
+
+        assert_eq!(ranked[0].proxy, "fast");
+        assert!(ranked[0].passed);
+        assert_eq!(ranked[1].proxy, "odd");
+        assert!(!ranked[1].passed);
+        assert_eq!(ranked[1].violations, ["latency is missing"]);
+        assert_eq!(ranked[1].score, 0.0);
+    }
+
+    #[test]
+    fn skipped_test_providers_are_not_failures() {
+        let results = results(&[("isolated", json!({"ms": 100}))]);
+        let proxies = [("sub".to_owned(), "isolated".to_owned())];
+        let tests = [
+            ("ping".to_owned(), "latency".to_owned()),
+            ("speed".to_owned(), "download".to_owned()),
+        ];
+        let mut attempts = AttemptMap::new();
+        attempts
+            .entry("sub".to_owned())
+            .or_default()
+            .entry("isolated".to_owned())
+            .or_default()
+            .skipped
+            .insert("speed".to_owned());
+
+        let ranked = scoring().rank(&results, &proxies, &tests, &AttemptMap::new());
+        assert_eq!(ranked[0].failures, 1);
+        let ranked = scoring().rank(&results, &proxies, &tests, &attempts);
+        assert_eq!(ranked[0].failures, 0);
+        assert!(ranked[0].passed);
+    }
+}
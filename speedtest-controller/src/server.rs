@@ -0,0 +1,270 @@
+//! Serving the plugin protocol, for plugins written in Rust.
+//!
+//! The controller launches every plugin with a secret in `TOKEN_ENV`, and presents it in the
+//! websocket handshake as `Authorization: Bearer <token>`. Other local users cannot drive the
+//! plugin without it.
+//!
+//! A plugin run by hand on another machine, for a `ws://` source, listens on `LISTEN_ENV` and
+//! takes the token the controller is configured with from `TOKEN_ENV`.
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::{self, Either, Ready};
+use hyper::server::conn::Http;
+use hyper::{header, Body, Request, Response, StatusCode};
+use jsonrpsee::server::{stop_channel, Methods, RpcModule, Server, ServerHandle};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use tokio::net::UnixListener;
+use tokio::select;
+use tower::{Layer, Service, ServiceBuilder};
+
+/// The environment variable a launched plugin finds the token of the controller in.
+pub const TOKEN_ENV: &str = "SPEEDTEST_PLUGIN_TOKEN";
+
+/// The environment variable holding the address to serve on, instead of a free local port.
+pub const LISTEN_ENV: &str = "SPEEDTEST_PLUGIN_LISTEN";
+
+/// Generates the secret for a single launch of a plugin.
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// The value of the `Authorization` header presenting `token`.
+pub fn authorization(token: &str) -> String {
+    format!("Bearer {token}")
+}
+
+/// Compares in a time independent of where the first difference is.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Rejects requests that do not present the token with 401.
+#[derive(Clone)]
+pub struct AuthLayer {
+    token: Option<Arc<str>>,
+}
+
+impl AuthLayer {
+    /// Requires `token`, or nothing when there is none.
+    pub fn new(token: Option<String>) -> Self {
+        AuthLayer {
+            token: token.map(Into::into),
+        }
+    }
+
+    /// Requires the token in `TOKEN_ENV`, a plugin launched by hand accepts everyone.
+    pub fn from_env() -> Self {
+        Self::new(std::env::var(TOKEN_ENV).ok())
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = Auth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Auth {
+            inner,
+            token: self.token.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Auth<S> {
+    inner: S,
+    token: Option<Arc<str>>,
+}
+
+impl<S> Service<Request<Body>> for Auth<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<Response<Body>, S::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let authorized = self.token.as_deref().is_none_or(|token| {
+            request
+                .headers()
+                .get(header::AUTHORIZATION)
+                .is_some_and(|value| {
+                    constant_time_eq(value.as_bytes(), authorization(token).as_bytes())
+                })
+        });
+        if authorized {
+            return Either::Left(self.inner.call(request));
+        }
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        Either::Right(future::ok(response))
+    }
+}
+
+/// Where a plugin is served, in the form the controller connects to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{addr}"),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+async fn serve_with<Context: Send + Sync + 'static>(
+    module: RpcModule<Context>,
+    auth: AuthLayer,
+    listen: &str,
+) -> anyhow::Result<(Endpoint, ServerHandle)> {
+    if let Some(path) = listen.strip_prefix("unix:") {
+        return serve_unix(module.into(), auth, Path::new(path));
+    }
+    let server = Server::builder()
+        .set_http_middleware(ServiceBuilder::new().layer(auth))
+        .build(listen)
+        .await?;
+    let addr = server.local_addr()?;
+    Ok((Endpoint::Tcp(addr), server.start(module)))
+}
+
+/// Serves `methods` on a Unix socket at `path`, which is removed once the server stops.
+fn serve_unix(
+    methods: Methods,
+    auth: AuthLayer,
+    path: &Path,
+) -> anyhow::Result<(Endpoint, ServerHandle)> {
+    let listener = UnixListener::bind(path)?;
+    let service = Server::builder()
+        .set_http_middleware(ServiceBuilder::new().layer(auth))
+        .to_service_builder();
+    let (stop, handle) = stop_channel();
+    let endpoint = Endpoint::Unix(path.to_owned());
+    let path = path.to_owned();
+    tokio::spawn(async move {
+        let stopped = stop.clone().shutdown();
+        tokio::pin!(stopped);
+        loop {
+            let socket = select! {
+                _ = &mut stopped => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((socket, _)) => socket,
+                    Err(e) => {
+                        log::error!("Unable to accept a connection on {}. {e}", path.display());
+                        continue;
+                    }
+                },
+            };
+            let service = service.clone().build(methods.clone(), stop.clone());
+            tokio::spawn(async move {
+                // Websocket connections are upgraded from HTTP.
+                let served = Http::new()
+                    .serve_connection(socket, service)
+                    .with_upgrades();
+                if let Err(e) = served.await {
+                    log::debug!("Connection closed. {e}");
+                }
+            });
+        }
+        let _ = std::fs::remove_file(&path);
+    });
+    Ok((endpoint, handle))
+}
+
+/// Serves `module` on `LISTEN_ENV` or a free local port, only to clients presenting the token.
+///
+/// `LISTEN_ENV` is either `host:port` or `unix:/path/to/socket`. The plugin still has to print
+/// `Listen on <endpoint>` with the returned endpoint for the controller to find it.
+pub async fn serve<Context: Send + Sync + 'static>(
+    module: RpcModule<Context>,
+) -> anyhow::Result<(Endpoint, ServerHandle)> {
+    let listen = std::env::var(LISTEN_ENV).unwrap_or_else(|_| "127.0.0.1:0".to_owned());
+    serve_with(module, AuthLayer::from_env(), &listen).await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::plugin::json_rpc::JSONRPCPlugin;
+    use crate::plugin::Plugin;
+
+    #[tokio::test]
+    async fn rejects_clients_without_the_token() {
+        let mut module = RpcModule::new(());
+        module
+            .register_method("metadata", |_, _| serde_json::json!({"name": "auth"}))
+            .unwrap();
+        let token = generate_token();
+        let (addr, _handle) =
+            serve_with(module, AuthLayer::new(Some(token.clone())), "127.0.0.1:0")
+                .await
+                .unwrap();
+        let endpoint = addr.to_string();
+
+        assert!(JSONRPCPlugin::connect(&endpoint, Value::Null, None)
+            .await
+            .is_err());
+        assert!(
+            JSONRPCPlugin::connect(&endpoint, Value::Null, Some("guess"))
+                .await
+                .is_err()
+        );
+        let plugin = JSONRPCPlugin::connect(&endpoint, Value::Null, Some(&token))
+            .await
+            .unwrap();
+        assert_eq!(plugin.metadata().await.unwrap().name, "auth");
+    }
+
+    #[tokio::test]
+    async fn serves_on_a_unix_socket() {
+        let mut module = RpcModule::new(());
+        module
+            .register_method("metadata", |_, _| serde_json::json!({"name": "unix"}))
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("serve-{}.sock", std::process::id()));
+        let token = generate_token();
+        let listen = format!("unix:{}", path.display());
+        let (endpoint, handle) = serve_with(module, AuthLayer::new(Some(token.clone())), &listen)
+            .await
+            .unwrap();
+        assert_eq!(endpoint.to_string(), listen);
+
+        assert!(JSONRPCPlugin::connect(&listen, Value::Null, None)
+            .await
+            .is_err());
+        let plugin = JSONRPCPlugin::connect(&listen, Value::Null, Some(&token))
+            .await
+            .unwrap();
+        assert_eq!(plugin.metadata().await.unwrap().name, "unix");
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+        for _ in 0..10 {
+            if !path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(!path.exists(), "the socket is removed once stopped");
+    }
+}
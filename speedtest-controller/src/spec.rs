@@ -0,0 +1,198 @@
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+use crate::plugin::{
+    ConnectionDescriptor, DataTransformDescriptor, PluginMetaData, ProtocolDescriptor,
+    TestDescriptor, PROTOCOL_VERSION,
+};
+
+/// A method of the plugin protocol.
+struct Method {
+    name: &'static str,
+    summary: &'static str,
+    /// The capability a plugin declares to be called with this method, if any
+    capability: Option<&'static str>,
+    params: Vec<(&'static str, Schema)>,
+    result: Schema,
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+fn methods(gen: &mut SchemaGenerator) -> Vec<Method> {
+    vec![
+        Method {
+            name: "metadata",
+            summary: "Describes the plugin, called right after it is loaded",
+            capability: None,
+            params: vec![],
+            result: schema::<PluginMetaData>(gen),
+        },
+        Method {
+            name: "init",
+            summary: "Initializes the plugin with the `config` of its plugin entry",
+            capability: None,
+            params: vec![("config", schema::<Value>(gen))],
+            result: schema::<()>(gen),
+        },
+        Method {
+            name: "shutdown",
+            summary: "Asks the plugin to clean up before it is terminated, e.g. stop its proxies",
+            capability: None,
+            params: vec![],
+            result: schema::<()>(gen),
+        },
+        Method {
+            name: "parse_protocol",
+            summary: "Parses a subscription into proxies",
+            capability: Some("parser"),
+            params: vec![("connection_string", schema::<String>(gen))],
+            result: schema::<Vec<ProtocolDescriptor>>(gen),
+        },
+        Method {
+            name: "proxy_schemes",
+            summary: "Lists the schemes of proxy content the plugin can set up",
+            capability: Some("proxy_backend"),
+            params: vec![],
+            result: schema::<Vec<String>>(gen),
+        },
+        Method {
+            name: "proxy_protocols",
+            summary: "Lists the proxy protocols the plugin can set up, any when empty",
+            capability: Some("proxy_backend"),
+            params: vec![],
+            result: schema::<Vec<String>>(gen),
+        },
+        Method {
+            name: "setup_proxy",
+            summary: "Sets up the `content` of a parsed proxy and tells how to connect to it",
+            capability: Some("proxy_backend"),
+            params: vec![("proxy", schema::<Value>(gen))],
+            result: schema::<ConnectionDescriptor>(gen),
+        },
+        Method {
+            name: "setup_proxy_in_netns",
+            summary:
+                "Like `setup_proxy`, but runs the proxy inside the network namespace at `netns`",
+            capability: Some("supports_netns"),
+            params: vec![
+                ("proxy", schema::<Value>(gen)),
+                ("netns", schema::<String>(gen)),
+            ],
+            result: schema::<ConnectionDescriptor>(gen),
+        },
+        Method {
+            name: "teardown_proxy",
+            summary: "Tears down a proxy returned by `setup_proxy`",
+            capability: Some("proxy_backend"),
+            params: vec![("proxy", schema::<ConnectionDescriptor>(gen))],
+            result: schema::<()>(gen),
+        },
+        Method {
+            name: "tests",
+            summary: "Lists the tests the plugin runs",
+            capability: Some("test_provider"),
+            params: vec![],
+            result: schema::<Vec<TestDescriptor>>(gen),
+        },
+        Method {
+            name: "run_test",
+            summary: "Runs a test through a proxy, the result is any JSON value",
+            capability: Some("test_provider"),
+            params: vec![
+                ("test", schema::<String>(gen)),
+                ("proxy", schema::<ConnectionDescriptor>(gen)),
+            ],
+            result: schema::<Value>(gen),
+        },
+        Method {
+            name: "data_transforms",
+            summary: "Lists the transforms the plugin offers",
+            capability: Some("transformer"),
+            params: vec![],
+            result: schema::<Vec<DataTransformDescriptor>>(gen),
+        },
+        Method {
+            name: "transform",
+            summary: "Converts proxy content with the named transform",
+            capability: Some("transformer"),
+            params: vec![
+                ("name", schema::<String>(gen)),
+                ("content", schema::<Value>(gen)),
+            ],
+            result: schema::<Value>(gen),
+        },
+    ]
+}
+
+/// The OpenRPC document of the plugin protocol, which plugins written in any language follow.
+pub fn openrpc() -> Value {
+    let mut gen = SchemaSettings::draft07()
+        .with(|s| s.definitions_path = "#/components/schemas/".to_owned())
+        .into_generator();
+    let methods: Vec<_> = methods(&mut gen)
+        .into_iter()
+        .map(|method| {
+            let params: Vec<_> = method
+                .params
+                .into_iter()
+                .map(|(name, schema)| json!({ "name": name, "required": true, "schema": schema }))
+                .collect();
+            json!({
+                "name": method.name,
+                "summary": method.summary,
+                "x-capability": method.capability,
+                "paramStructure": "by-position",
+                "params": params,
+                "result": { "name": "result", "schema": method.result },
+            })
+        })
+        .collect();
+    json!({
+        "openrpc": "1.2.6",
+        "info": {
+            "title": "speedtest-controller plugin protocol",
+            "version": PROTOCOL_VERSION.to_string(),
+        },
+        "methods": methods,
+        "components": { "schemas": gen.take_definitions() },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+
+    #[test]
+    fn published_spec_is_up_to_date() {
+        let published: Value = serde_json::from_str(include_str!("../plugin-spec.json")).unwrap();
+        assert!(
+            published == openrpc(),
+            "plugin-spec.json is outdated, regenerate it with `speedtest-controller plugin-spec`"
+        );
+    }
+
+    #[test]
+    fn spec_covers_every_called_method() {
+        let spec = openrpc();
+        let specified: Vec<_> = spec["methods"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["name"].as_str().unwrap())
+            .collect();
+        let request = Regex::new(r#"self\.call\(\s*"(\w+)""#).unwrap();
+        for called in request.captures_iter(include_str!("plugin/json_rpc.rs")) {
+            assert!(
+                specified.contains(&&called[1]),
+                "{} is not specified",
+                &called[1]
+            );
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use futures::future::join_all;
@@ -6,33 +7,124 @@ use futures::Future;
 use regex::Regex;
 use serde::Deserialize;
 use serde_json::Value;
-use tokio::process::{Child, Command};
+use tokio::process::Command;
 use url::Url;
 
-use crate::plugin::json_rpc::JSONRPCPlugin;
-use crate::plugin::{Plugin, PluginType, ProtocolDescriptor, TestDescriptor};
+use crate::plugin::builtin;
+use crate::plugin::json_rpc::{JSONRPCPlugin, TlsConfig};
+use crate::plugin::record::{Recorder, RecordingPlugin};
+use crate::plugin::replay::ReplayPlugin;
+use crate::plugin::wasm::{WasmLimits, WasmPlugin};
+use crate::plugin::{
+    Capabilities, Plugin, PluginMetaData, PluginType, ProtocolDescriptor, TestDescriptor,
+};
 use crate::plugin_loader::{PluginLoaderError, Result};
-use crate::process::create_process_and_wait_for_pattern;
+use crate::process::{self, create_process_and_wait_for_pattern, Sandbox};
+use crate::server;
+use crate::transform::ProxyRouter;
+use crate::vantage::VantagePoint;
 
 #[derive(Debug, Deserialize)]
 pub struct PluginConfig {
     /// Available format:
-    /// ```
+    /// ```text
     /// docker://image:tag
     /// file://path/to/plugin/executable
+    /// ws://host:port (an already running plugin, also `wss://`)
+    /// builtin://clash-parser
+    /// file://path/to/plugin.wasm (with `plugin_type: Wasm`)
+    /// replay://path/to/recording.ndjson?realtime=true
     /// ```
+    ///
+    /// `args`, `env`, `cwd` and `token` may refer to the controller environment as `${VAR}`.
     /// TODO: Parse the source
-    source: Url,
+    pub(crate) source: Url,
+    #[serde(default)]
+    pub(crate) plugin_type: PluginType,
+    #[serde(default)]
+    pub(crate) config: Value,
+    /// Arguments of the plugin executable
+    #[serde(default)]
+    pub(crate) args: Vec<String>,
+    /// Environment variables set for the plugin executable
+    #[serde(default)]
+    pub(crate) env: HashMap<String, String>,
+    /// Only these variables of the controller environment are passed on to the plugin,
+    /// instead of all of them
+    #[serde(default)]
+    pub(crate) env_allowlist: Option<Vec<String>>,
+    /// Working directory of the plugin executable
+    #[serde(default)]
+    pub(crate) cwd: Option<PathBuf>,
+    /// Limits and isolation of the plugin process
+    #[serde(default)]
+    pub(crate) sandbox: Sandbox,
+    /// Narrows down what the plugin is used for, whatever it reports
+    #[serde(default)]
+    pub(crate) capabilities: Option<Capabilities>,
+    /// Records the traffic of the plugin to this file
+    #[serde(default)]
+    pub(crate) record: Option<PathBuf>,
+    /// Only used by `Wasm` plugins
+    #[serde(default)]
+    pub(crate) limits: WasmLimits,
+    /// Presented to a `ws://` or `wss://` plugin, the controller generates one for the plugins
+    /// it launches
     #[serde(default)]
-    plugin_type: PluginType,
+    pub(crate) token: Option<String>,
+    /// Only used by `wss://` plugins
     #[serde(default)]
-    config: Value,
+    pub(crate) tls: TlsConfig,
+}
+
+impl PluginConfig {
+    pub fn new(source: Url, config: Value) -> Self {
+        PluginConfig {
+            source,
+            plugin_type: PluginType::default(),
+            config,
+            args: vec![],
+            env: HashMap::new(),
+            env_allowlist: None,
+            cwd: None,
+            sandbox: Sandbox::default(),
+            capabilities: None,
+            record: None,
+            limits: WasmLimits::default(),
+            token: None,
+            tls: TlsConfig::default(),
+        }
+    }
+
+    pub fn record_to(&mut self, path: PathBuf) {
+        self.record = Some(path);
+    }
+
+    /// Lets the plugin enter the network namespaces proxies are isolated in, see `Sandbox`.
+    pub fn allow_isolation(&mut self, name: &str) {
+        self.sandbox.allow_setns = true;
+        if self.sandbox.user_namespace {
+            log::warn!(
+                "Plugin {name} runs in a user namespace, where it cannot set up proxies in \
+                 network namespaces"
+            );
+        }
+    }
 }
 
 pub type PluginMap = HashMap<String, Arc<dyn Plugin>>;
 pub type ProxyProviderMap = HashMap<String, (Arc<dyn Plugin>, Vec<ProtocolDescriptor>)>;
 pub type TestProviderMap = HashMap<String, (Arc<dyn Plugin>, Vec<TestDescriptor>)>;
 
+/// Test name -> test result
+pub type TestResults = HashMap<String, Value>;
+/// Test provider -> test results
+pub type ProxyResults = HashMap<String, TestResults>;
+/// Proxy name -> results of every test provider
+pub type ProviderResults = HashMap<String, ProxyResults>;
+/// Proxy provider -> results of every proxy
+pub type RunResults = HashMap<String, ProviderResults>;
+
 async fn get_provider_map<Content, F, FR, Args, Err>(
     plugin_map: &PluginMap,
     transform: F,
@@ -65,68 +157,245 @@ where
 
 pub struct SpeedTest {
     plugin_map: PluginMap,
+    metadata: HashMap<String, PluginMetaData>,
 }
 
-struct FileJSONRPCPlugin {
-    inner: JSONRPCPlugin,
-    #[allow(dead_code)]
-    process: Child,
+/// Replaces `${VAR}` in `value` with the controller environment.
+fn interpolate(value: &str) -> Result<String> {
+    process::interpolate(value, |name| std::env::var(name).ok())
+        .map_err(PluginLoaderError::UndefinedVariable)
 }
 
-impl std::ops::Deref for FileJSONRPCPlugin {
-    type Target = JSONRPCPlugin;
-    fn deref(&self) -> &Self::Target {
-        &self.inner
+/// The command launching a `file://` plugin, with `${VAR}`s taken from the controller environment.
+fn plugin_command(config: &PluginConfig) -> Result<Command> {
+    let mut command = Command::new(config.source.path());
+    for arg in &config.args {
+        command.arg(interpolate(arg)?);
     }
+    if let Some(allowlist) = &config.env_allowlist {
+        command
+            .env_clear()
+            .envs(std::env::vars().filter(|(name, _)| allowlist.contains(name)));
+    }
+    for (name, value) in &config.env {
+        command.env(name, interpolate(value)?);
+    }
+    if let Some(cwd) = &config.cwd {
+        let cwd = PathBuf::from(interpolate(&cwd.to_string_lossy())?);
+        if !cwd.is_dir() {
+            return Err(PluginLoaderError::MissingDirectory(cwd));
+        }
+        command.current_dir(cwd);
+    }
+    config
+        .sandbox
+        .apply(&mut command)
+        .map_err(PluginLoaderError::SandboxError)?;
+    Ok(command)
 }
 
 async fn load_json_rpc_plugin(config: PluginConfig) -> Result<Arc<dyn Plugin>> {
     assert_eq!(config.plugin_type, PluginType::JSONRPC);
     match config.source.scheme() {
         "file" => {
-            let command = Command::new(config.source.path());
+            let token = server::generate_token();
+            let mut command = plugin_command(&config)?;
+            command.env(server::TOKEN_ENV, &token);
             let regex = Regex::new(r"Listen on (.+)").unwrap();
             let (endpoint, process) =
                 create_process_and_wait_for_pattern(command, regex, |[endpoint]| {
                     endpoint.to_owned()
                 })
-                .await;
-            let inner = JSONRPCPlugin::new(&endpoint, config.config).await?;
-            Ok(Arc::new(FileJSONRPCPlugin { inner, process }))
+                .await
+                .map_err(PluginLoaderError::LaunchError)?;
+            let mut plugin = JSONRPCPlugin::connect(&endpoint, config.config, Some(&token))
+                .await?
+                .with_process(process);
+            if let Some(path) = &config.record {
+                plugin = plugin.with_recorder(Recorder::create(path)?);
+            }
+            Ok(Arc::new(plugin))
+        }
+        "ws" | "wss" => {
+            let token = config.token.as_deref().map(interpolate).transpose()?;
+            let mut plugin = JSONRPCPlugin::connect_url(
+                &config.source,
+                config.config,
+                token.as_deref(),
+                &config.tls,
+            )
+            .await?;
+            if let Some(path) = &config.record {
+                plugin = plugin.with_recorder(Recorder::create(path)?);
+            }
+            Ok(Arc::new(plugin))
         }
         _ => Err(PluginLoaderError::UnexpectedScheme(config.source.into())),
     }
 }
 
+fn load_wasm_plugin(config: PluginConfig) -> Result<Arc<dyn Plugin>> {
+    match config.source.scheme() {
+        "file" => Ok(Arc::new(WasmPlugin::load(
+            Path::new(config.source.path()),
+            config.config,
+            &config.limits,
+        )?)),
+        _ => Err(PluginLoaderError::UnexpectedScheme(config.source.into())),
+    }
+}
+
+/// A running plugin together with the metadata it was negotiated with.
+pub type LoadedPlugin = (Arc<dyn Plugin>, PluginMetaData);
+
+/// Checks that the plugin speaks a protocol version the controller understands.
+async fn negotiate(plugin: &Arc<dyn Plugin>) -> Result<PluginMetaData> {
+    let metadata = plugin.metadata().await?;
+    if !metadata.is_compatible() {
+        return Err(PluginLoaderError::IncompatibleProtocol {
+            name: metadata.name,
+            version: metadata.protocol_version,
+        });
+    }
+    Ok(metadata)
+}
+
+/// Loads a plugin and checks that it speaks a protocol version the controller understands.
+pub async fn load_plugin(config: PluginConfig) -> Result<LoadedPlugin> {
+    let declared = config.capabilities.clone();
+    // JSON-RPC plugins record the requests they are sent, every other plugin its calls.
+    let recorded = match config.source.scheme() {
+        _ if config.plugin_type == PluginType::Wasm => config.record.clone(),
+        "builtin" | "replay" => config.record.clone(),
+        _ => None,
+    };
+    let init_config = config.config.clone();
+    let plugin: Arc<dyn Plugin> = match config.source.scheme() {
+        _ if config.plugin_type == PluginType::Wasm => load_wasm_plugin(config)?,
+        "builtin" => builtin::load(config.source.host_str().unwrap_or_default(), config.config)?,
+        "replay" => {
+            let realtime = config
+                .source
+                .query_pairs()
+                .any(|(key, value)| key == "realtime" && value == "true");
+            let path = PathBuf::from(config.source.path());
+            Arc::new(ReplayPlugin::load(&path, config.config, realtime)?)
+        }
+        _ => load_json_rpc_plugin(config).await?,
+    };
+    let plugin = match recorded {
+        Some(path) => Arc::new(RecordingPlugin::new(
+            plugin,
+            init_config,
+            Recorder::create(&path)?,
+        )),
+        None => plugin,
+    };
+    let mut metadata = negotiate(&plugin).await?;
+    if let Some(declared) = &declared {
+        metadata.capabilities = metadata.capabilities.intersection(declared);
+    }
+    Ok((plugin, metadata))
+}
+
 impl SpeedTest {
     pub async fn new(plugins: HashMap<String, PluginConfig>) -> Self {
-        let plugin_map: Vec<(_, _)> = join_all(
+        let plugin_map = join_all(
             plugins
                 .into_iter()
-                .map(|(k, v)| async { (k, load_json_rpc_plugin(v).await) }),
+                .map(|(k, v)| async { (k, load_plugin(v).await) }),
         )
         .await;
+        Self::from_loaded(plugin_map)
+    }
 
-        let plugin_map: HashMap<_, _> = plugin_map
-            .into_iter()
-            .filter_map(|(k, v)| match v {
-                Ok(v) => {
-                    log::info!("Plugin {} loaded", k);
-                    Some((k, v))
-                }
-                Err(e) => {
-                    log::error!("Unable to load plugin {}, {}", k, e);
-                    None
+    /// Uses plugins that are already running, e.g. connected to by endpoint.
+    pub async fn from_plugins(plugins: PluginMap) -> Self {
+        let plugin_map = join_all(plugins.into_iter().map(|(k, plugin)| async move {
+            let metadata = negotiate(&plugin).await;
+            (k, metadata.map(|metadata| (plugin, metadata)))
+        }))
+        .await;
+        Self::from_loaded(plugin_map)
+    }
+
+    fn from_loaded(plugin_map: Vec<(String, Result<LoadedPlugin>)>) -> Self {
+        let mut speedtest = SpeedTest {
+            plugin_map: HashMap::new(),
+            metadata: HashMap::new(),
+        };
+        for (k, v) in plugin_map {
+            match v {
+                Ok((plugin, metadata)) => {
+                    log::info!(
+                        "Plugin {} loaded, {} {}",
+                        k,
+                        metadata.name,
+                        metadata.version.as_deref().unwrap_or("(unknown version)")
+                    );
+                    speedtest.plugin_map.insert(k.clone(), plugin);
+                    speedtest.metadata.insert(k, metadata);
                 }
-            })
-            .collect();
+                Err(e) => log::error!("Unable to load plugin {}, {}", k, e),
+            }
+        }
+        speedtest
+    }
 
-        SpeedTest { plugin_map }
+    /// Asks every plugin to clean up, then stops the ones the controller launched.
+    pub async fn shutdown(&self) {
+        let results = join_all(
+            self.plugin_map
+                .iter()
+                .map(|(name, plugin)| async move { (name, plugin.shutdown().await) }),
+        )
+        .await;
+        for (name, result) in results {
+            if let Err(e) = result {
+                log::warn!("Plugin {} did not shut down cleanly, {}", name, e);
+            }
+        }
+    }
+
+    /// A view from `point`, where only its plugins set up and test proxies.
+    pub fn at(&self, point: &VantagePoint) -> SpeedTest {
+        for name in &point.plugins {
+            if !self.plugin_map.contains_key(name) {
+                log::warn!("Plugin {} of a vantage point is not loaded", name);
+            }
+        }
+        let mut metadata = self.metadata.clone();
+        for (name, metadata) in metadata.iter_mut() {
+            if !point.plugins.contains(name) {
+                let capabilities = &mut metadata.capabilities;
+                capabilities.proxy_backend = false;
+                capabilities.test_provider = false;
+                capabilities.supports_tun = false;
+                capabilities.supports_netns = false;
+            }
+        }
+        SpeedTest {
+            plugin_map: self.plugin_map.clone(),
+            metadata,
+        }
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, PluginMetaData> {
+        &self.metadata
+    }
+
+    /// The loaded plugins whose capabilities satisfy `predicate`.
+    pub fn plugins_with(&self, predicate: impl Fn(&Capabilities) -> bool) -> PluginMap {
+        self.plugin_map
+            .iter()
+            .filter(|(name, _)| predicate(&self.metadata[*name].capabilities))
+            .map(|(name, plugin)| (name.clone(), plugin.clone()))
+            .collect()
     }
 
     pub async fn get_proxy_provider(&self, connection_string: &str) -> ProxyProviderMap {
         get_provider_map(
-            &self.plugin_map,
+            &self.plugins_with(|c| c.parser),
             |_, plugin, connection_string| async move {
                 plugin.parse_protocol(&connection_string).await
             },
@@ -135,12 +404,134 @@ impl SpeedTest {
         .await
     }
 
+    pub async fn get_proxy_router(&self) -> ProxyRouter {
+        ProxyRouter::new(
+            &self.plugins_with(|c| c.proxy_backend),
+            &self.plugins_with(|c| c.transformer),
+        )
+        .await
+    }
+
     pub async fn get_test_provider(&self) -> TestProviderMap {
         get_provider_map(
-            &self.plugin_map,
+            &self.plugins_with(|c| c.test_provider),
             |_, plugin, _| async move { plugin.tests().await },
             &(),
         )
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn shell(script: &str) -> PluginConfig {
+        let mut config = PluginConfig::new(Url::parse("file:///bin/sh").unwrap(), Value::Null);
+        config.args = vec!["-c".to_owned(), script.to_owned()];
+        config
+    }
+
+    #[tokio::test]
+    async fn launches_plugins_with_their_args_env_and_cwd() {
+        let cwd = std::env::temp_dir().canonicalize().unwrap();
+        // Cargo sets CARGO_PKG_NAME for the tests, which is not allowed through.
+        let mut config = shell("echo \"$0\"; pwd; env");
+        config.args.push("${CARGO_PKG_NAME}".to_owned());
+        config.env = HashMap::from([("GREETING".to_owned(), "hi".to_owned())]);
+        config.env_allowlist = Some(vec!["PATH".to_owned()]);
+        config.cwd = Some(cwd.clone());
+        let output = plugin_command(&config).unwrap().output().await.unwrap();
+        let output = String::from_utf8(output.stdout).unwrap();
+        let mut lines = output.lines();
+
+        assert_eq!(lines.next(), Some(env!("CARGO_PKG_NAME")));
+        assert_eq!(lines.next(), Some(cwd.to_str().unwrap()));
+        let env: Vec<_> = lines.collect();
+        assert!(env.contains(&"GREETING=hi"), "{env:?}");
+        assert!(env.iter().any(|line| line.starts_with("PATH=")), "{env:?}");
+        assert!(
+            !env.iter().any(|line| line.starts_with("CARGO_PKG_NAME=")),
+            "{env:?}"
+        );
+    }
+
+    #[test]
+    fn rejects_a_missing_cwd() {
+        let mut config = shell("true");
+        config.cwd = Some(PathBuf::from("/nonexistent/plugin"));
+        assert!(matches!(
+            plugin_command(&config),
+            Err(PluginLoaderError::MissingDirectory(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn skips_plugins_that_fail_to_launch() {
+        let mut plugins = HashMap::from([("silent".to_owned(), shell("exit 1"))]);
+        let mut missing = shell("true");
+        missing.source = Url::parse("file:///nonexistent/plugin").unwrap();
+        plugins.insert("missing".to_owned(), missing);
+        let speedtest = SpeedTest::new(plugins).await;
+        assert!(speedtest.plugins_with(|_| true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn loads_builtin_plugins() {
+        let plugins = HashMap::from([
+            (
+                "clash".to_owned(),
+                PluginConfig::new(Url::parse("builtin://clash-parser").unwrap(), Value::Null),
+            ),
+            (
+                "ping".to_owned(),
+                PluginConfig::new(
+                    Url::parse("builtin://latency").unwrap(),
+                    json!({"targets": {"google": "http://www.google.com/generate_204"}}),
+                ),
+            ),
+        ]);
+        let speedtest = SpeedTest::new(plugins).await;
+
+        let path = std::env::temp_dir().join(format!("builtin-{}.yaml", std::process::id()));
+        std::fs::write(&path, "proxies:\n  - {name: hk, type: ss}\n").unwrap();
+        let proxy_providers = speedtest.get_proxy_provider(path.to_str().unwrap()).await;
+        std::fs::remove_file(&path).unwrap();
+        let test_providers = speedtest.get_test_provider().await;
+
+        assert_eq!(proxy_providers.len(), 1, "only clash-parser parses");
+        assert_eq!(proxy_providers["clash"].1[0].name, "hk");
+        assert_eq!(test_providers.len(), 1, "only latency provides tests");
+        assert_eq!(test_providers["ping"].1[0].name, "google");
+    }
+
+    #[tokio::test]
+    async fn records_builtin_plugins_for_replay() {
+        let dir = std::env::temp_dir();
+        let subscription = dir.join(format!("recorded-{}.yaml", std::process::id()));
+        let recording = dir.join(format!("recorded-{}.ndjson", std::process::id()));
+        std::fs::write(&subscription, "proxies:\n  - {name: hk, type: ss}\n").unwrap();
+        let subscription = subscription.to_str().unwrap();
+        let mut config =
+            PluginConfig::new(Url::parse("builtin://clash-parser").unwrap(), Value::Null);
+        config.record_to(recording.clone());
+        let (plugin, _) = load_plugin(config).await.unwrap();
+        let parsed = plugin.parse_protocol(subscription).await.unwrap();
+        drop(plugin);
+        std::fs::remove_file(subscription).unwrap();
+
+        let source = Url::from_file_path(&recording).unwrap().to_string();
+        let replay = PluginConfig::new(
+            source.replacen("file", "replay", 1).parse().unwrap(),
+            Value::Null,
+        );
+        let (replay, metadata) = load_plugin(replay).await.unwrap();
+        std::fs::remove_file(&recording).unwrap();
+        assert_eq!(metadata.name, "clash-parser");
+        let replayed = replay.parse_protocol(subscription).await.unwrap();
+        assert_eq!(replayed[0].name, parsed[0].name);
+        assert_eq!(replayed[0].content, parsed[0].content);
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use futures::future::join_all;
+use serde::Deserialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::plugin::{DataTransformDescriptor, Plugin, PluginError, ProtocolDescriptor};
+use crate::speedtest::{PluginMap, ProxyProviderMap};
+
+#[derive(Error, Debug)]
+pub enum RouteError {
+    #[error("No plugin can set up proxy `{0}`")]
+    NoRoute(String),
+    #[error("No backend accepts proxy `{0}`, directly or through transforms")]
+    NoBackend(String),
+    #[error("Plugin `{0}` does not exist")]
+    UnknownPlugin(String),
+    #[error("Unable to transform the proxy. {0}")]
+    PluginError(#[from] PluginError),
+}
+
+/// Chooses the plugins that set up the parsed proxies.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SetupConfig {
+    /// Plugins allowed to set up proxies, in order of preference.
+    /// When unset every capable plugin is allowed, preferring the one that parsed the proxy.
+    #[serde(default)]
+    pub backends: Option<Vec<String>>,
+    /// Sets up every proxy on each allowed backend instead of only the preferred one,
+    /// e.g. to compare xray and sing-box on the same nodes
+    #[serde(default)]
+    pub compare: bool,
+}
+
+/// A transform offered by a plugin.
+struct Transform {
+    plugin: Arc<dyn Plugin>,
+    descriptor: DataTransformDescriptor,
+}
+
+/// What a plugin declared it can set up.
+#[derive(Default)]
+struct SetupCapability {
+    schemes: Vec<String>,
+    /// Any protocol when empty
+    protocols: Vec<String>,
+}
+
+impl SetupCapability {
+    fn accepts_protocol(&self, protocol: Option<&str>) -> bool {
+        match protocol {
+            Some(protocol) if !self.protocols.is_empty() => {
+                self.protocols.iter().any(|p| p == protocol)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// A proxy ready to be handed to `setup_proxy`.
+pub struct RoutedProxy {
+    /// The name of `plugin`
+    pub backend: String,
+    pub plugin: Arc<dyn Plugin>,
+    pub content: Value,
+}
+
+/// A parsed proxy together with the backend that will set it up.
+#[derive(Debug, Clone)]
+pub struct PlannedProxy {
+    /// The name results are recorded under, which names the backend as well when comparing them
+    pub name: String,
+    /// The plugin that sets up the proxy, unless no plugin can
+    pub backend: Option<String>,
+    pub descriptor: ProtocolDescriptor,
+}
+
+/// Proxy provider -> the proxies it parsed, once per backend
+pub type SetupPlan = HashMap<String, Vec<PlannedProxy>>;
+
+/// Decides which plugins set up a proxy, converting its content with the transforms the
+/// plugins offer when the parsing plugin cannot set it up itself.
+pub struct ProxyRouter {
+    plugins: PluginMap,
+    /// Plugin name -> what the plugin can set up
+    capabilities: HashMap<String, SetupCapability>,
+    transforms: Vec<Transform>,
+}
+
+impl ProxyRouter {
+    /// * `backends` - plugins that set up proxies
+    /// * `transformers` - plugins that offer transforms
+    pub async fn new(backends: &PluginMap, transformers: &PluginMap) -> Self {
+        let capabilities = join_all(backends.iter().map(|(name, plugin)| async move {
+            let schemes = plugin.proxy_schemes().await.unwrap_or_else(|e| {
+                log::debug!("Plugin {name} does not set up any scheme. {e}");
+                vec![]
+            });
+            let protocols = plugin.proxy_protocols().await.unwrap_or_default();
+            (name.clone(), SetupCapability { schemes, protocols })
+        }))
+        .await;
+        let transforms = join_all(transformers.iter().map(|(name, plugin)| async move {
+            let transforms = plugin.data_transforms().await.unwrap_or_else(|e| {
+                log::debug!("Plugin {name} does not offer any transform. {e}");
+                vec![]
+            });
+            transforms.into_iter().map(|descriptor| Transform {
+                plugin: plugin.clone(),
+                descriptor,
+            })
+        }))
+        .await;
+
+        ProxyRouter {
+            plugins: backends.clone(),
+            capabilities: capabilities.into_iter().collect(),
+            transforms: transforms.into_iter().flatten().collect(),
+        }
+    }
+
+    /// Finds the shortest chain of transforms from `scheme` to a scheme accepted by `accepts`.
+    fn find_path(&self, scheme: &str, accepts: impl Fn(&str) -> bool) -> Option<Vec<&Transform>> {
+        let mut visited = HashSet::from([scheme]);
+        let mut queue = VecDeque::from([(scheme, vec![])]);
+        while let Some((scheme, path)) = queue.pop_front() {
+            if accepts(scheme) {
+                return Some(path);
+            }
+            for transform in &self.transforms {
+                let descriptor = &transform.descriptor;
+                if descriptor.accpeted_scheme == scheme
+                    && visited.insert(descriptor.produced_scheme.as_str())
+                {
+                    let mut path = path.clone();
+                    path.push(transform);
+                    queue.push_back((descriptor.produced_scheme.as_str(), path));
+                }
+            }
+        }
+        None
+    }
+
+    /// Parsers that set up proxies but declare no scheme predate routing and set up their
+    /// proxies themselves.
+    fn is_legacy(&self, plugin_name: &str) -> bool {
+        self.capabilities
+            .get(plugin_name)
+            .is_some_and(|capability| capability.schemes.is_empty())
+    }
+
+    /// The transforms that make `proxy` acceptable to `backend`, if there are any.
+    fn path_to(&self, backend: &str, proxy: &ProtocolDescriptor) -> Option<Vec<&Transform>> {
+        let capability = self.capabilities.get(backend)?;
+        let scheme = proxy.scheme.as_deref()?;
+        if !capability.accepts_protocol(proxy.protocol.as_deref()) {
+            return None;
+        }
+        self.find_path(scheme, |scheme| {
+            capability.schemes.iter().any(|s| s == scheme)
+        })
+    }
+
+    /// Lists the backends for a proxy parsed by `parser`, most preferred first.
+    ///
+    /// Proxies without a scheme can only be set up by their parser, and so can those of legacy
+    /// parsers no other plugin accepts.
+    pub fn backends(
+        &self,
+        parser: &str,
+        proxy: &ProtocolDescriptor,
+        config: &SetupConfig,
+    ) -> Vec<String> {
+        if proxy.scheme.is_none() {
+            return vec![parser.to_owned()];
+        }
+        let mut candidates: Vec<&String> = match &config.backends {
+            Some(backends) => backends.iter().collect(),
+            None => {
+                let mut others: Vec<_> = self.plugins.keys().filter(|p| *p != parser).collect();
+                others.sort();
+                let parser = self.plugins.get_key_value(parser).map(|(name, _)| name);
+                parser.into_iter().chain(others).collect()
+            }
+        };
+        candidates.retain(|backend| self.path_to(backend, proxy).is_some());
+        if candidates.is_empty() && self.is_legacy(parser) {
+            return vec![parser.to_owned()];
+        }
+        if !config.compare {
+            candidates.truncate(1);
+        }
+        candidates.into_iter().cloned().collect()
+    }
+
+    /// Expands the parsed proxies into the proxies to set up, one per backend.
+    ///
+    /// Proxies without any backend are kept without one, so that they are reported as failing to
+    /// set up instead of silently disappearing.
+    pub fn plan(&self, proxy_providers: &ProxyProviderMap, config: &SetupConfig) -> SetupPlan {
+        let unknown = config.backends.iter().flatten();
+        for backend in unknown.filter(|backend| !self.plugins.contains_key(*backend)) {
+            log::error!("Backend {backend} is not a loaded proxy backend, ignored");
+        }
+        proxy_providers
+            .iter()
+            .map(|(provider, (_, proxies))| {
+                let planned = proxies.iter().flat_map(|proxy| {
+                    let backends = self.backends(provider, proxy, config);
+                    if backends.is_empty() {
+                        log::error!("No plugin can set up proxy {}", proxy.name);
+                        return vec![PlannedProxy {
+                            name: proxy.name.clone(),
+                            backend: None,
+                            descriptor: proxy.clone(),
+                        }];
+                    }
+                    let compared = backends.len() > 1;
+                    backends
+                        .into_iter()
+                        .map(|backend| PlannedProxy {
+                            name: if compared {
+                                format!("{}@{backend}", proxy.name)
+                            } else {
+                                proxy.name.clone()
+                            },
+                            backend: Some(backend),
+                            descriptor: proxy.clone(),
+                        })
+                        .collect()
+                });
+                (provider.clone(), planned.collect())
+            })
+            .collect()
+    }
+
+    /// Converts a proxy parsed by `parser` for its backend.
+    pub async fn route(
+        &self,
+        parser: &str,
+        proxy: &PlannedProxy,
+    ) -> Result<RoutedProxy, RouteError> {
+        let backend = proxy
+            .backend
+            .clone()
+            .ok_or_else(|| RouteError::NoBackend(proxy.descriptor.name.clone()))?;
+        let plugin = self
+            .plugins
+            .get(&backend)
+            .ok_or_else(|| RouteError::UnknownPlugin(backend.clone()))?
+            .clone();
+        let mut content = proxy.descriptor.content.clone();
+        match self.path_to(&backend, &proxy.descriptor) {
+            Some(path) => {
+                for transform in path {
+                    content = transform
+                        .plugin
+                        .transform(&transform.descriptor, content)
+                        .await?;
+                }
+            }
+            None if backend == parser
+                && (proxy.descriptor.scheme.is_none() || self.is_legacy(parser)) => {}
+            None => return Err(RouteError::NoRoute(proxy.descriptor.name.clone())),
+        }
+        Ok(RoutedProxy {
+            backend,
+            plugin,
+            content,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::builtin;
+
+    /// A router offering a transform for each `(accepted, produced)` scheme pair.
+    fn router(transforms: &[(&str, &str)]) -> ProxyRouter {
+        with_backends(&[], transforms)
+    }
+
+    /// A router with backends setting up the given schemes.
+    fn with_backends(backends: &[(&str, &[&str])], transforms: &[(&str, &str)]) -> ProxyRouter {
+        let plugin = builtin::load("latency", Value::Null).unwrap();
+        let plugins = backends
+            .iter()
+            .map(|(name, _)| (name.to_string(), plugin.clone()))
+            .collect();
+        let capabilities = backends
+            .iter()
+            .map(|(name, schemes)| {
+                let schemes = schemes.iter().map(|s| s.to_string()).collect();
+                let capability = SetupCapability {
+                    schemes,
+                    protocols: vec![],
+                };
+                (name.to_string(), capability)
+            })
+            .collect();
+        let transforms = transforms
+            .iter()
+            .map(|(accepted, produced)| Transform {
+                plugin: plugin.clone(),
+                descriptor: DataTransformDescriptor {
+                    name: format!("{accepted}-to-{produced}"),
+                    accpeted_scheme: accepted.to_string(),
+                    produced_scheme: produced.to_string(),
+                },
+            })
+            .collect();
+        ProxyRouter {
+            plugins,
+            capabilities,
+            transforms,
+        }
+    }
+
+    fn path(router: &ProxyRouter, from: &str, to: &str) -> Option<Vec<String>> {
+        let path = router.find_path(from, |scheme| scheme == to)?;
+        Some(path.iter().map(|t| t.descriptor.name.clone()).collect())
+    }
+
+    #[test]
+    fn finds_the_shortest_chain_of_transforms() {
+        let router = router(&[
+            ("clash", "v2ray"),
+            ("v2ray", "sing-box"),
+            ("clash", "xray"),
+            ("xray", "v2ray"),
+        ]);
+        assert_eq!(
+            path(&router, "clash", "clash").unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(path(&router, "clash", "xray").unwrap(), ["clash-to-xray"]);
+        assert_eq!(
+            path(&router, "clash", "sing-box").unwrap(),
+            ["clash-to-v2ray", "v2ray-to-sing-box"]
+        );
+    }
+
+    #[test]
+    fn finds_no_path_between_unconnected_schemes() {
+        let router = router(&[("clash", "v2ray"), ("v2ray", "clash")]);
+        assert_eq!(path(&router, "clash", "sing-box"), None);
+        assert_eq!(path(&router, "sing-box", "clash"), None);
+    }
+
+    fn proxy_providers(scheme: &str) -> ProxyProviderMap {
+        let parser = builtin::load("latency", Value::Null).unwrap();
+        let proxy = ProtocolDescriptor {
+            name: "a".to_owned(),
+            scheme: Some(scheme.to_owned()),
+            protocol: None,
+            content: Value::Null,
+        };
+        HashMap::from([("clash".to_owned(), (parser, vec![proxy]))])
+    }
+
+    fn planned(plan: &SetupPlan) -> Vec<(String, String)> {
+        let mut planned: Vec<_> = plan["clash"]
+            .iter()
+            .map(|p| (p.name.clone(), p.backend.clone().unwrap_or_default()))
+            .collect();
+        planned.sort();
+        planned
+    }
+
+    fn config(backends: Option<&[&str]>, compare: bool) -> SetupConfig {
+        SetupConfig {
+            backends: backends.map(|b| b.iter().map(|s| s.to_string()).collect()),
+            compare,
+        }
+    }
+
+    fn backends() -> ProxyRouter {
+        with_backends(
+            &[
+                ("clash", &["clash"]),
+                ("sing-box", &["sing-box"]),
+                ("xray", &["xray"]),
+            ],
+            &[("clash", "sing-box")],
+        )
+    }
+
+    #[test]
+    fn prefers_the_parser_unless_backends_are_configured() {
+        let router = backends();
+        let plan = router.plan(&proxy_providers("clash"), &config(None, false));
+        assert_eq!(planned(&plan), [("a".to_owned(), "clash".to_owned())]);
+
+        let configured = config(Some(&["xray", "sing-box", "clash"]), false);
+        let plan = router.plan(&proxy_providers("clash"), &configured);
+        assert_eq!(planned(&plan), [("a".to_owned(), "sing-box".to_owned())]);
+    }
+
+    #[test]
+    fn compares_every_capable_backend() {
+        let router = backends();
+        let plan = router.plan(&proxy_providers("clash"), &config(None, true));
+        assert_eq!(
+            planned(&plan),
+            [
+                ("a@clash".to_owned(), "clash".to_owned()),
+                ("a@sing-box".to_owned(), "sing-box".to_owned()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_proxies_without_a_backend_to_report_them() {
+        let router = backends();
+        let configured = config(Some(&["v2ray"]), false);
+        let plan = router.plan(&proxy_providers("clash"), &configured);
+        assert_eq!(planned(&plan), [("a".to_owned(), String::new())]);
+        let error = router
+            .route("clash", &plan["clash"][0])
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, RouteError::NoBackend(proxy) if proxy == "a"));
+    }
+}
@@ -0,0 +1,619 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, Stderr, Write};
+use std::time::Duration;
+
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use crossterm::ExecutableCommand;
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState, Wrap};
+use serde_json::Value;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::progress::{Event, Reporter};
+
+/// A request from the TUI to run part of the speedtest again.
+#[derive(Debug, Clone)]
+pub enum Command {
+    RerunProxy {
+        provider: String,
+        proxy: String,
+    },
+    RerunTest {
+        provider: String,
+        proxy: String,
+        test_provider: String,
+        test: String,
+    },
+}
+
+/// Forwards progress events to the TUI.
+pub struct TuiReporter {
+    sender: UnboundedSender<Event>,
+}
+
+impl TuiReporter {
+    pub fn new(sender: UnboundedSender<Event>) -> Self {
+        TuiReporter { sender }
+    }
+}
+
+impl Reporter for TuiReporter {
+    fn report(&self, event: &Event) {
+        // The TUI may already be closed while the run is still finishing.
+        let _ = self.sender.send(event.clone());
+    }
+}
+
+/// Forwards log output to the log pane of the TUI, which owns the terminal while it is open.
+///
+/// Once the TUI is closed the output goes to stderr again.
+pub struct LogWriter {
+    sender: UnboundedSender<String>,
+    line: Vec<u8>,
+}
+
+impl LogWriter {
+    pub fn new(sender: UnboundedSender<String>) -> Self {
+        LogWriter {
+            sender,
+            line: vec![],
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            let text = String::from_utf8_lossy(&line[..end]).into_owned();
+            if self.sender.send(text).is_err() {
+                io::stderr().write_all(&line)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How many log lines the log pane keeps.
+const LOG_LINES: usize = 200;
+
+/// (provider, proxy)
+type NodeKey = (String, String);
+/// (test provider, test)
+type TestKey = (String, String);
+
+#[derive(Default)]
+struct Node {
+    results: BTreeMap<TestKey, Value>,
+    errors: Vec<String>,
+}
+
+struct App {
+    nodes: BTreeMap<NodeKey, Node>,
+    columns: BTreeSet<TestKey>,
+    table: TableState,
+    /// Unset until the first column arrives
+    selected_column: Option<TestKey>,
+    sort_column: Option<TestKey>,
+    filter: String,
+    editing_filter: bool,
+    status: String,
+    logs: VecDeque<String>,
+    commands: UnboundedSender<Command>,
+    /// Cancels the run, which is what Ctrl-C does outside of the TUI
+    cancel: CancellationToken,
+}
+
+/// Runs the TUI on stderr until the user quits, showing the lines received from `logs`.
+///
+/// This blocks the calling thread, so it should be run with `tokio::task::spawn_blocking`.
+pub fn run(
+    mut events: UnboundedReceiver<Event>,
+    mut logs: UnboundedReceiver<String>,
+    commands: UnboundedSender<Command>,
+    cancel: CancellationToken,
+) -> io::Result<()> {
+    enable_raw_mode()?;
+    io::stderr().execute(EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+    let result = App::new(commands, cancel).run(&mut terminal, &mut events, &mut logs);
+    disable_raw_mode()?;
+    io::stderr().execute(LeaveAlternateScreen)?;
+    result
+}
+
+fn summary(value: &Value) -> String {
+    let summary = match value {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    if summary.chars().count() > 16 {
+        summary.chars().take(15).chain(['…']).collect()
+    } else {
+        summary
+    }
+}
+
+fn compare_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            _ => a.to_string().cmp(&b.to_string()),
+        },
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+impl App {
+    fn new(commands: UnboundedSender<Command>, cancel: CancellationToken) -> Self {
+        App {
+            nodes: BTreeMap::new(),
+            columns: BTreeSet::new(),
+            table: TableState::default().with_selected(Some(0)),
+            selected_column: None,
+            sort_column: None,
+            filter: String::new(),
+            editing_filter: false,
+            status:
+                "q quit | ^C cancel | ↑↓ select | ←→ column | s sort | / filter | r rerun proxy | t rerun test"
+                    .to_owned(),
+            logs: VecDeque::new(),
+            commands,
+            cancel,
+        }
+    }
+
+    fn log(&mut self, line: String) {
+        if self.logs.len() == LOG_LINES {
+            self.logs.pop_front();
+        }
+        self.logs.push_back(line);
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::ProxySetup {
+                provider,
+                proxy,
+                error,
+                ..
+            } => {
+                let node = self.nodes.entry((provider, proxy)).or_default();
+                if let Some(error) = error {
+                    node.errors.push(format!("setup: {error}"));
+                }
+            }
+            Event::TestFinished {
+                provider,
+                proxy,
+                test_provider,
+                test,
+                result,
+                error,
+                ..
+            } => {
+                let key = (test_provider, test);
+                self.columns.insert(key.clone());
+                self.selected_column.get_or_insert_with(|| key.clone());
+                let node = self.nodes.entry((provider, proxy)).or_default();
+                if let Some(error) = error {
+                    node.errors.push(format!("{}/{}: {error}", key.0, key.1));
+                }
+                match result {
+                    Some(result) => node.results.insert(key, result),
+                    None => node.results.remove(&key),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn selected_column(&self) -> Option<&TestKey> {
+        self.selected_column.as_ref()
+    }
+
+    /// Moves the column selection by one to the left or, if `forward`, to the right.
+    fn move_column(&mut self, forward: bool) {
+        let next = match (self.selected_column(), forward) {
+            (None, _) => None,
+            (Some(current), true) => self.columns.range::<TestKey, _>(current..).nth(1),
+            (Some(current), false) => self.columns.range::<TestKey, _>(..current).next_back(),
+        };
+        if let Some(next) = next {
+            self.selected_column = Some(next.clone());
+        }
+    }
+
+    fn rows(&self) -> Vec<(&NodeKey, &Node)> {
+        let mut rows: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|((provider, proxy), _)| {
+                format!("{provider}/{proxy}").contains(self.filter.as_str())
+            })
+            .collect();
+        if let Some(column) = &self.sort_column {
+            rows.sort_by(|(_, a), (_, b)| {
+                compare_values(a.results.get(column), b.results.get(column))
+            });
+        }
+        rows
+    }
+
+    fn selected_node(&self) -> Option<(NodeKey, Option<TestKey>)> {
+        let rows = self.rows();
+        let (key, _) = rows.get(self.table.selected()?)?;
+        Some(((*key).clone(), self.selected_column().cloned()))
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let len = self.rows().len();
+        if len == 0 {
+            return;
+        }
+        let current = self.table.selected().unwrap_or(0) as isize;
+        let next = (current + delta).clamp(0, len as isize - 1);
+        self.table.select(Some(next as usize));
+    }
+
+    fn send(&mut self, command: Command) {
+        self.status = format!("Scheduled {command:?}");
+        if self.commands.send(command).is_err() {
+            self.status = "The controller is no longer accepting commands".to_owned();
+        }
+    }
+
+    /// Returns `false` once the user asked to quit.
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        let code = key.code;
+        // Raw mode turns Ctrl-C into a key instead of SIGINT.
+        if key.modifiers.contains(KeyModifiers::CONTROL) && code == KeyCode::Char('c') {
+            self.cancel.cancel();
+            return false;
+        }
+        if self.editing_filter {
+            match code {
+                KeyCode::Enter | KeyCode::Esc => self.editing_filter = false,
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => {}
+            }
+            self.table.select(Some(0));
+            return true;
+        }
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::Left | KeyCode::Char('h') => self.move_column(false),
+            KeyCode::Right | KeyCode::Char('l') => self.move_column(true),
+            KeyCode::Char('s') => {
+                let selected = self.selected_column().cloned();
+                self.sort_column = match &self.sort_column {
+                    Some(column) if Some(column) == selected.as_ref() => None,
+                    _ => selected,
+                }
+            }
+            KeyCode::Char('/') => self.editing_filter = true,
+            KeyCode::Char('r') => {
+                if let Some(((provider, proxy), _)) = self.selected_node() {
+                    self.send(Command::RerunProxy { provider, proxy });
+                }
+            }
+            KeyCode::Char('t') => {
+                if let Some(((provider, proxy), Some((test_provider, test)))) = self.selected_node()
+                {
+                    self.send(Command::RerunTest {
+                        provider,
+                        proxy,
+                        test_provider,
+                        test,
+                    });
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn run(
+        mut self,
+        terminal: &mut Terminal<CrosstermBackend<Stderr>>,
+        events: &mut UnboundedReceiver<Event>,
+        logs: &mut UnboundedReceiver<String>,
+    ) -> io::Result<()> {
+        loop {
+            // A disconnected channel only means the run is over, the UI stays open.
+            while let Ok(event) = events.try_recv() {
+                self.apply(event);
+            }
+            while let Ok(line) = logs.try_recv() {
+                self.log(line);
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            if let TermEvent::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.handle_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Percentage(50),
+                Constraint::Min(3),
+                Constraint::Length(8),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+
+        let selected_column = self.selected_column();
+        let header = Row::new(
+            std::iter::once(Cell::from("proxy")).chain(self.columns.iter().map(|column| {
+                let marker = if self.sort_column.as_ref() == Some(column) {
+                    " ▲"
+                } else {
+                    ""
+                };
+                let (test_provider, test) = column;
+                let cell = Cell::from(format!("{test_provider}/{test}{marker}"));
+                if selected_column == Some(column) {
+                    cell.style(Style::default().add_modifier(Modifier::UNDERLINED))
+                } else {
+                    cell
+                }
+            })),
+        )
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows: Vec<Row> =
+            self.rows()
+                .into_iter()
+                .map(|((provider, proxy), node)| {
+                    let style = if node.errors.is_empty() {
+                        Style::default()
+                    } else {
+                        Style::default().fg(Color::Red)
+                    };
+                    Row::new(std::iter::once(format!("{provider}/{proxy}")).chain(
+                        self.columns.iter().map(|column| {
+                            node.results.get(column).map(summary).unwrap_or_default()
+                        }),
+                    ))
+                    .style(style)
+                })
+                .collect();
+        let widths = std::iter::once(Constraint::Min(24))
+            .chain(self.columns.iter().map(|_| Constraint::Min(12)))
+            .collect::<Vec<_>>();
+        let title = if self.filter.is_empty() && !self.editing_filter {
+            "results".to_owned()
+        } else {
+            format!("results (filter: {})", self.filter)
+        };
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, layout[0], &mut self.table);
+
+        let detail = match self.selected_node() {
+            Some((key, _)) => {
+                let node = &self.nodes[&key];
+                let mut lines = vec![];
+                for ((test_provider, test), value) in &node.results {
+                    lines.push(format!("{test_provider}/{test}:"));
+                    lines.extend(
+                        serde_json::to_string_pretty(value)
+                            .unwrap_or_default()
+                            .lines()
+                            .map(|l| format!("  {l}")),
+                    );
+                }
+                if !node.errors.is_empty() {
+                    lines.push("errors:".to_owned());
+                    lines.extend(node.errors.iter().map(|e| format!("  {e}")));
+                }
+                lines.join("\n")
+            }
+            None => String::new(),
+        };
+        frame.render_widget(
+            Paragraph::new(detail)
+                .wrap(Wrap { trim: false })
+                .block(Block::default().borders(Borders::ALL).title("detail")),
+            layout[1],
+        );
+        // The borders take two of the lines.
+        let visible = layout[2].height.saturating_sub(2) as usize;
+        let logs: Vec<_> = self
+            .logs
+            .iter()
+            .skip(self.logs.len().saturating_sub(visible))
+            .map(String::as_str)
+            .collect();
+        frame.render_widget(
+            Paragraph::new(logs.join("\n"))
+                .block(Block::default().borders(Borders::ALL).title("log")),
+            layout[2],
+        );
+        frame.render_widget(Paragraph::new(self.status.as_str()), layout[3]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn test_finished(proxy: &str, test: &str, result: Option<Value>) -> Event {
+        Event::TestFinished {
+            provider: "sub".to_owned(),
+            proxy: proxy.to_owned(),
+            test_provider: "tests".to_owned(),
+            test: test.to_owned(),
+            attempts: 1,
+            error: result.is_none().then(|| "timed out".to_owned()),
+            result,
+        }
+    }
+
+    fn app() -> (App, UnboundedReceiver<Command>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (App::new(sender, CancellationToken::new()), receiver)
+    }
+
+    fn press(app: &mut App, code: KeyCode) -> bool {
+        app.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn proxies(app: &App) -> Vec<&str> {
+        app.rows()
+            .into_iter()
+            .map(|((_, proxy), _)| proxy.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn collects_results_and_errors_per_proxy() {
+        let (mut app, _) = app();
+        app.apply(Event::ProxySetup {
+            provider: "sub".to_owned(),
+            proxy: "b".to_owned(),
+            attempts: 1,
+            error: Some("unsupported cipher".to_owned()),
+        });
+        app.apply(test_finished("a", "latency", Some(json!(100))));
+        app.apply(test_finished("a", "download", None));
+
+        assert_eq!(proxies(&app), ["a", "b"]);
+        let a = &app.nodes[&("sub".to_owned(), "a".to_owned())];
+        assert_eq!(a.results.len(), 1);
+        assert_eq!(a.errors, ["tests/download: timed out"]);
+        let b = &app.nodes[&("sub".to_owned(), "b".to_owned())];
+        assert_eq!(b.errors, ["setup: unsupported cipher"]);
+        assert_eq!(app.columns.len(), 2);
+
+        // A rerun replaces the failure.
+        app.apply(test_finished("a", "download", Some(json!(5))));
+        assert_eq!(
+            app.nodes[&("sub".to_owned(), "a".to_owned())].results.len(),
+            2
+        );
+    }
+
+    #[test]
+    fn filters_and_sorts_the_rows() {
+        let (mut app, _) = app();
+        app.apply(test_finished("fast", "latency", Some(json!(10))));
+        app.apply(test_finished("slow", "latency", Some(json!(300))));
+        app.apply(test_finished("medium", "latency", Some(json!(50))));
+        app.apply(test_finished("broken", "latency", None));
+
+        press(&mut app, KeyCode::Char('s'));
+        assert_eq!(proxies(&app), ["fast", "medium", "slow", "broken"]);
+        press(&mut app, KeyCode::Char('s'));
+        assert_eq!(proxies(&app), ["broken", "fast", "medium", "slow"]);
+
+        press(&mut app, KeyCode::Char('/'));
+        for c in "sl".chars() {
+            press(&mut app, KeyCode::Char(c));
+        }
+        assert!(
+            press(&mut app, KeyCode::Char('q')),
+            "q is part of the filter"
+        );
+        assert_eq!(app.filter, "slq");
+        press(&mut app, KeyCode::Backspace);
+        press(&mut app, KeyCode::Enter);
+        assert_eq!(proxies(&app), ["slow"]);
+        assert!(!press(&mut app, KeyCode::Char('q')));
+    }
+
+    #[test]
+    fn keeps_the_sorted_and_selected_column_when_columns_arrive() {
+        let (mut app, _) = app();
+        app.apply(test_finished("a", "latency", Some(json!(20))));
+        app.apply(test_finished("b", "latency", Some(json!(10))));
+        press(&mut app, KeyCode::Char('s'));
+        assert_eq!(proxies(&app), ["b", "a"]);
+
+        // Sorts before `latency`, which moves it to the second column.
+        app.apply(test_finished("a", "download", Some(json!(1))));
+        app.apply(test_finished("b", "download", Some(json!(2))));
+        assert_eq!(proxies(&app), ["b", "a"]);
+        let latency = ("tests".to_owned(), "latency".to_owned());
+        assert_eq!(app.selected_column(), Some(&latency));
+
+        press(&mut app, KeyCode::Left);
+        press(&mut app, KeyCode::Left);
+        assert_eq!(app.selected_column().unwrap().1, "download");
+        press(&mut app, KeyCode::Char('s'));
+        assert_eq!(proxies(&app), ["a", "b"]);
+        press(&mut app, KeyCode::Right);
+        press(&mut app, KeyCode::Right);
+        assert_eq!(app.selected_column(), Some(&latency));
+    }
+
+    #[test]
+    fn sends_rerun_commands_for_the_selection() {
+        let (mut app, mut commands) = app();
+        app.apply(test_finished("a", "latency", Some(json!(20))));
+        app.apply(test_finished("b", "latency", Some(json!(10))));
+
+        press(&mut app, KeyCode::Down);
+        press(&mut app, KeyCode::Char('r'));
+        assert!(matches!(
+            commands.try_recv(),
+            Ok(Command::RerunProxy { proxy, .. }) if proxy == "b"
+        ));
+        press(&mut app, KeyCode::Up);
+        press(&mut app, KeyCode::Char('t'));
+        assert!(matches!(
+            commands.try_recv(),
+            Ok(Command::RerunTest { proxy, test, .. }) if proxy == "a" && test == "latency"
+        ));
+    }
+
+    #[test]
+    fn ctrl_c_cancels_the_run() {
+        let (mut app, _) = app();
+        let cancel = app.cancel.clone();
+        assert!(press(&mut app, KeyCode::Char('c')));
+        assert!(!cancel.is_cancelled());
+        assert!(!app.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)));
+        assert!(cancel.is_cancelled());
+    }
+
+    #[test]
+    fn forwards_whole_log_lines() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut writer = LogWriter::new(sender);
+        write!(writer, "[WARN] first\n[ERROR] sec").unwrap();
+        writeln!(writer, "ond").unwrap();
+        assert_eq!(receiver.try_recv().unwrap(), "[WARN] first");
+        assert_eq!(receiver.try_recv().unwrap(), "[ERROR] second");
+        assert!(receiver.try_recv().is_err());
+    }
+}
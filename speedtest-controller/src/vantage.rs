@@ -0,0 +1,286 @@
+//! Testing the same proxies from several locations.
+//!
+//! A vantage point is a set of proxy backends and test providers running at one location,
+//! usually `ws://` plugins on a remote agent. Every vantage point sets up and tests every proxy on
+//! its own, while parsers and transformers are shared:
+//!
+//! ```toml
+//! [vantage_points.tokyo]
+//! plugins = ["tokyo-gost", "tokyo-tests"]
+//!
+//! [vantage_points.frankfurt]
+//! plugins = ["frankfurt-agent"]
+//! ```
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::score::RankedProxy;
+use crate::speedtest::{ProxyProviderMap, RunResults, SpeedTest, TestProviderMap};
+use crate::transform::{ProxyRouter, SetupConfig, SetupPlan};
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct VantagePoint {
+    /// The plugins at this location, by their name in `plugins`
+    pub plugins: Vec<String>,
+}
+
+/// Vantage point -> results of the run from there
+pub type VantageResults = HashMap<String, RunResults>;
+
+/// The proxies planned for one location, with the plugins setting up and testing them there.
+pub struct Location {
+    pub plan: SetupPlan,
+    pub test_providers: TestProviderMap,
+    pub router: ProxyRouter,
+    /// Test providers able to test through a TUN device
+    pub tun_test_providers: HashSet<String>,
+    /// Proxy backends on this host able to set up proxies inside a given network namespace
+    pub netns_backends: HashSet<String>,
+}
+
+impl Location {
+    /// Plans `proxy_providers` on the backends of `speedtest`, e.g. a view from `SpeedTest::at`.
+    pub async fn new(
+        speedtest: &SpeedTest,
+        proxy_providers: &ProxyProviderMap,
+        setup: &SetupConfig,
+    ) -> Self {
+        let router = speedtest.get_proxy_router().await;
+        let mut netns_backends = HashSet::new();
+        for (name, plugin) in speedtest.plugins_with(|c| c.proxy_backend && c.supports_netns) {
+            // Namespaces are paths on this host, which only a backend launched here can enter.
+            if plugin.process_id().await.is_some() {
+                netns_backends.insert(name);
+            } else {
+                log::debug!("Backend {name} is not launched by the controller, not isolating");
+            }
+        }
+        Location {
+            plan: router.plan(proxy_providers, setup),
+            test_providers: speedtest.get_test_provider().await,
+            router,
+            tun_test_providers: speedtest
+                .plugins_with(|c| c.test_provider && c.supports_tun)
+                .into_keys()
+                .collect(),
+            netns_backends,
+        }
+    }
+
+    /// Every (provider, proxy) planned.
+    pub fn proxies(&self) -> Vec<(String, String)> {
+        self.plan
+            .iter()
+            .flat_map(|(provider, proxies)| {
+                proxies.iter().map(|p| (provider.clone(), p.name.clone()))
+            })
+            .collect()
+    }
+
+    /// Every (test provider, test) expected to run on each proxy.
+    pub fn tests(&self) -> Vec<(String, String)> {
+        self.test_providers
+            .iter()
+            .flat_map(|(test_provider, (_, tests))| {
+                tests
+                    .iter()
+                    .map(|t| (test_provider.clone(), t.name.clone()))
+            })
+            .collect()
+    }
+}
+
+/// Aggregates of the ranking from one vantage point.
+#[derive(Debug, Serialize, Default, PartialEq)]
+pub struct LocationSummary {
+    pub proxies: usize,
+    pub passed: usize,
+    /// Mean score of the proxies that passed
+    pub mean_score: Option<f64>,
+    /// Metric name -> mean over the proxies that passed
+    pub metrics: HashMap<String, f64>,
+}
+
+impl LocationSummary {
+    pub fn new(ranking: &[RankedProxy]) -> Self {
+        let passed: Vec<_> = ranking.iter().filter(|r| r.passed).collect();
+        let mean = |values: Vec<f64>| {
+            (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+        };
+        let names: HashSet<_> = passed.iter().flat_map(|r| r.metrics.keys()).collect();
+        LocationSummary {
+            proxies: ranking.len(),
+            passed: passed.len(),
+            mean_score: mean(passed.iter().map(|r| r.score).collect()),
+            metrics: names
+                .into_iter()
+                .filter_map(|name| {
+                    let values = passed.iter().filter_map(|r| r.metrics.get(name)).copied();
+                    Some((name.clone(), mean(values.collect())?))
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::future::join_all;
+    use serde_json::{json, Value};
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::checkpoint::Checkpoint;
+    use crate::mock::{self, MockServer};
+    use crate::plugin::json_rpc::JSONRPCPlugin;
+    use crate::progress::{Event, Reporter};
+    use crate::run::{run_with_reporter, RunContext};
+    use crate::speedtest::PluginMap;
+
+    /// Keeps the vantage points of the events, which are not reported on their own.
+    #[derive(Default)]
+    struct VantagePointsReporter(Mutex<HashSet<String>>);
+
+    impl Reporter for VantagePointsReporter {
+        fn report(&self, _: &Event) {
+            panic!("an event without vantage point was reported");
+        }
+
+        fn report_at(&self, vantage_point: &str, _: &Event) {
+            self.0.lock().unwrap().insert(vantage_point.to_owned());
+        }
+    }
+
+    /// A remote agent on loopback, setting up `mock` proxies and measuring `latency`.
+    async fn agent(latency: u64) -> MockServer {
+        let script = json!({
+            "metadata": {
+                "name": "agent",
+                "protocol_version": crate::plugin::PROTOCOL_VERSION,
+                "capabilities": {"proxy_backend": true, "test_provider": true},
+            },
+            "proxies": [{"name": "a", "scheme": "mock", "content": "a"}],
+            "proxy_schemes": ["mock"],
+            "tests": [{"name": "latency", "response": {"result": latency}}],
+        });
+        mock::serve(serde_json::from_value(script).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tests_the_same_proxies_from_every_vantage_point() {
+        let subscription = mock::serve(
+            serde_json::from_value(json!({
+                "metadata": {
+                    "name": "subscription",
+                    "protocol_version": crate::plugin::PROTOCOL_VERSION,
+                    "capabilities": {"parser": true},
+                },
+                "proxies": [{"name": "a", "scheme": "mock", "content": "a"}],
+            }))
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+        let (east, west) = (agent(10).await, agent(200).await);
+        let mut plugins = PluginMap::new();
+        for (name, server) in [("sub", &subscription), ("east", &east), ("west", &west)] {
+            let plugin = JSONRPCPlugin::new(&server.addr.to_string(), Value::Null)
+                .await
+                .unwrap();
+            plugins.insert(name.to_owned(), Arc::new(plugin));
+        }
+        let speedtest = SpeedTest::from_plugins(plugins).await;
+        let proxy_providers = speedtest.get_proxy_provider("mock://").await;
+
+        let points = ["east", "west"].map(|name| {
+            let point = VantagePoint {
+                plugins: vec![name.to_owned()],
+            };
+            (name, point)
+        });
+        let reporter = Arc::new(VantagePointsReporter::default());
+        let results = join_all(points.iter().map(|(name, point)| {
+            let reporter = reporter.clone();
+            let speedtest = &speedtest;
+            let proxy_providers = &proxy_providers;
+            async move {
+                let location =
+                    Location::new(&speedtest.at(point), proxy_providers, &Default::default()).await;
+                let run = Arc::new(RunContext {
+                    reporter,
+                    cancel: CancellationToken::new(),
+                    resumed: Checkpoint::default(),
+                    retry: Default::default(),
+                    attempts: Default::default(),
+                    router: location.router,
+                    tun_test_providers: location.tun_test_providers,
+                    isolation: Default::default(),
+                    netns_backends: location.netns_backends,
+                    proxy_locks: Default::default(),
+                    checkpoint: None,
+                    vantage_point: Some(name.to_string()),
+                });
+                let results = run_with_reporter(location.plan, location.test_providers, run).await;
+                (name.to_string(), results)
+            }
+        }))
+        .await;
+        let results: VantageResults = results.into_iter().collect();
+
+        assert_eq!(results["east"]["sub"]["a"]["east"]["latency"], 10);
+        assert_eq!(results["west"]["sub"]["a"]["west"]["latency"], 200);
+        assert!(!results["east"]["sub"]["a"].contains_key("west"));
+        assert!(east.calls().contains(&"setup_proxy:a".to_owned()));
+        assert!(west.calls().contains(&"setup_proxy:a".to_owned()));
+        let reported = reporter.0.lock().unwrap();
+        assert_eq!(
+            *reported,
+            HashSet::from(["east".to_owned(), "west".to_owned()])
+        );
+    }
+
+    fn ranked(proxy: &str, passed: bool, score: f64, latency: Option<f64>) -> RankedProxy {
+        RankedProxy {
+            provider: "sub".to_owned(),
+            proxy: proxy.to_owned(),
+            score,
+            passed,
+            metrics: latency
+                .map(|latency| HashMap::from([("latency".to_owned(), latency)]))
+                .unwrap_or_default(),
+            failures: 0,
+            violations: vec![],
+        }
+    }
+
+    #[test]
+    fn summarises_the_proxies_that_passed() {
+        let ranking = [
+            ranked("a", true, 1.0, Some(100.0)),
+            ranked("b", true, 0.5, Some(300.0)),
+            ranked("c", false, 0.0, Some(900.0)),
+            ranked("d", false, -1.0, None),
+        ];
+        assert_eq!(
+            LocationSummary::new(&ranking),
+            LocationSummary {
+                proxies: 4,
+                passed: 2,
+                mean_score: Some(0.75),
+                metrics: HashMap::from([("latency".to_owned(), 200.0)]),
+            }
+        );
+        assert_eq!(
+            LocationSummary::new(&ranking[2..]),
+            LocationSummary {
+                proxies: 2,
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -1,17 +1,19 @@
-use jsonrpsee::{server::Server, types::ErrorObject, RpcModule};
+use jsonrpsee::{types::ErrorObject, RpcModule};
 use regex::Regex;
 use serde::Deserialize;
-use speedtest_controller::plugin::{ConnectionDescriptor, PluginMetaData, ProtocolDescriptor};
-use speedtest_controller::process::create_process_and_wait_for_pattern;
-use std::sync::{Arc, Mutex};
-use tokio::{
-    process::{Child, Command},
-    signal::ctrl_c,
+use speedtest_controller::plugin::{
+    Capabilities, ConnectionDescriptor, PluginMetaData, ProtocolDescriptor, PROTOCOL_VERSION,
+};
+use speedtest_controller::process::{
+    create_process_and_wait_for_pattern, PluginProcess, TERMINATE_GRACE,
 };
+use speedtest_controller::server::serve;
+use std::sync::{Arc, Mutex};
+use tokio::{process::Command, signal::ctrl_c};
 
-#[derive(Debug, Default)]
+#[derive(Default)]
 struct HelloPlugin {
-    process: Option<Child>,
+    process: Option<PluginProcess>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -21,21 +23,50 @@ struct HelloPluginConfig {
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let server = Server::builder().build("127.0.0.1:0").await?;
     let hello_plugin: Arc<Mutex<HelloPlugin>> = Default::default();
     let mut module = RpcModule::new(());
     module.register_method("metadata", |_, _| PluginMetaData {
         name: "hello".to_owned(),
+        protocol_version: PROTOCOL_VERSION,
+        version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+        capabilities: Capabilities {
+            parser: true,
+            proxy_backend: true,
+            ..Default::default()
+        },
     })?;
     module.register_method("parse_protocol", |_, _| -> Result<_, ErrorObject> {
         Ok(vec![ProtocolDescriptor {
             name: "hello-dummy".to_owned(),
+            scheme: None,
+            protocol: None,
             content: serde_json::Value::Null,
         }])
     })?;
     module.register_method("init", |params, _| {
         println!("init with {:?}", params);
+        if let Ok(config) = params.one::<HelloPluginConfig>() {
+            println!("{}", config.display_string);
+        }
     })?;
+    {
+        let hello_plugin = Arc::clone(&hello_plugin);
+        module.register_method("teardown_proxy", move |_, _| {
+            // Dropping the process kills it.
+            hello_plugin.lock().unwrap().process.take();
+        })?;
+    }
+    {
+        let hello_plugin = Arc::clone(&hello_plugin);
+        module.register_async_method("shutdown", move |_, _| {
+            let process = hello_plugin.lock().unwrap().process.take();
+            async move {
+                if let Some(mut process) = process {
+                    process.terminate(TERMINATE_GRACE).await;
+                }
+            }
+        })?;
+    }
     {
         module.register_async_method("setup_proxy", move |params, _| {
             let hello_plugin = Arc::clone(&hello_plugin);
@@ -49,20 +80,23 @@ async fn main() -> anyhow::Result<()> {
                     create_process_and_wait_for_pattern(command, re, |[port]| {
                         format!("socks5://127.0.0.1:{}", port)
                     })
-                    .await;
+                    .await
+                    .map_err(|e| {
+                        ErrorObject::owned(1, format!("Unable to launch gost. {e}"), None::<()>)
+                    })?;
                 let mut guard = hello_plugin.lock().unwrap();
-                std::mem::swap(&mut guard.process, &mut Some(child));
+                guard.process.replace(child);
                 Result::<_, ErrorObject>::Ok(ConnectionDescriptor {
                     http: None,
                     socks5: Some(connection_string),
                     tun: false,
+                    ..Default::default()
                 })
             }
         })?;
     }
-    let addr = server.local_addr()?;
+    let (addr, handle) = serve(module).await?;
     println!("Listen on {}", addr);
-    let handle = server.start(module);
     ctrl_c().await?;
     handle.stop().unwrap();
     Ok(())
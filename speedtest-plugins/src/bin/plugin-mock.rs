@@ -0,0 +1,15 @@
+use speedtest_controller::mock::{self, MockScript};
+use tokio::signal::ctrl_c;
+
+/// Serves the script at `$PLUGIN_MOCK_SCRIPT`, or an empty one if unset.
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let script: MockScript = match std::env::var_os("PLUGIN_MOCK_SCRIPT") {
+        Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+        None => MockScript::default(),
+    };
+    let server = mock::serve(script).await?;
+    println!("Listen on {}", server.addr);
+    ctrl_c().await?;
+    Ok(())
+}